@@ -0,0 +1,67 @@
+use common::client_config::ClientConfig;
+
+use crate::{rest, schedule::format_time};
+
+fn parse_audit_args<'a>(args: &[&'a str]) -> Result<Vec<(&'a str, &'a str)>, String> {
+    let mut filters = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let name = match *arg {
+            "--device" => "device",
+            "--type" => "type",
+            "--limit" => "limit",
+            arg => return Err(format!("invalid argument {}", arg)),
+        };
+        let value = args.next().ok_or(format!("{} needs a value", arg))?;
+        if name == "limit" && value.parse::<usize>().is_err() {
+            return Err("--limit needs a number".to_string());
+        }
+        filters.push((name, *value));
+    }
+
+    Ok(filters)
+}
+
+/// Prints the most recent entries of the server's audit log
+pub fn list(config: &ClientConfig, args: &[&str]) {
+    let filters = match parse_audit_args(args) {
+        Ok(filters) => filters,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let entries = match rest::fetch_audit(config, &filters) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to fetch the audit log: {}", e);
+            return;
+        }
+    };
+
+    if entries.is_empty() {
+        println!("No matching audit log entries");
+        return;
+    }
+
+    for entry in entries {
+        println!("{} {}", format_time(entry.time), entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_args() {
+        assert_eq!(
+            parse_audit_args(&["--device", "laptop", "--limit", "10"]),
+            Ok(vec![("device", "laptop"), ("limit", "10")])
+        );
+        assert!(parse_audit_args(&["--limit", "many"]).is_err());
+        assert!(parse_audit_args(&["--type"]).is_err());
+    }
+}
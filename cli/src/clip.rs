@@ -0,0 +1,85 @@
+use common::client_config::ClientConfig;
+use common::ipc::{EntryOrigin, IpcRequest, IpcResponse};
+use dialoguer::FuzzySelect;
+
+use crate::{rest, schedule::format_time};
+
+pub fn set(config: &ClientConfig, text: &str, ttl: Option<&str>) {
+    let ttl = match ttl.map(|ttl| ttl.trim_end_matches('s').parse::<u64>()) {
+        Some(Ok(ttl)) => Some(ttl),
+        Some(Err(_)) => {
+            eprintln!("Invalid TTL, expected a number of seconds");
+            return;
+        }
+        None => None,
+    };
+
+    match rest::set_clipboard(config, text, ttl) {
+        Ok(()) => match ttl {
+            Some(ttl) => println!("Set clipboard on all devices for {}s", ttl),
+            None => println!("Set clipboard on all devices"),
+        },
+        Err(e) => eprintln!("Failed to set clipboard: {}", e),
+    }
+}
+
+pub fn clear(config: &ClientConfig) {
+    match rest::clear_clipboard(config) {
+        Ok(()) => println!("Cleared clipboard on the server and all devices"),
+        Err(e) => eprintln!("Failed to clear clipboard: {}", e),
+    }
+}
+
+/// Lets the user pick an entry of the local daemon's clipboard history and restores it
+pub fn pick() {
+    let entries = match common::ipc::send(&IpcRequest::History) {
+        Ok(IpcResponse::History(entries)) => entries,
+        Ok(response) => {
+            eprintln!("Unexpected response from the daemon: {:?}", response);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return;
+        }
+    };
+
+    if entries.is_empty() {
+        println!("The clipboard history is empty");
+        return;
+    }
+
+    let items = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{} {:<6} {}",
+                format_time(entry.copied_at),
+                match entry.origin {
+                    EntryOrigin::Local => "local",
+                    EntryOrigin::Remote => "remote",
+                },
+                entry.preview
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let Some(selection) = FuzzySelect::new()
+        .with_prompt("Which entry do you want to restore?")
+        .items(&items)
+        .default(0)
+        .interact_opt()
+        .unwrap()
+    else {
+        return;
+    };
+
+    match common::ipc::send(&IpcRequest::Restore {
+        id: entries[selection].id,
+    }) {
+        Ok(IpcResponse::Ok) => println!("Restored clipboard entry"),
+        Ok(IpcResponse::Error(e)) => eprintln!("Failed to restore entry: {}", e),
+        Ok(response) => eprintln!("Unexpected response from the daemon: {:?}", response),
+        Err(e) => eprintln!("{:#}", e),
+    }
+}
@@ -0,0 +1,142 @@
+use common::client_config::ClientConfig;
+use common::types::ConnectedClientInfo;
+use dialoguer::Confirm;
+use std::io::IsTerminal;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{rest, schedule::format_time};
+
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+// Formats a number of seconds like 45s, 12m or 3h
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+fn print_device(device: &ConnectedClientInfo) {
+    if device.online {
+        println!("{} (id {})", device.name, device.id);
+    } else {
+        println!("{} (offline)", device.name);
+        let mut details = Vec::new();
+        if let Some(last_seen) = device.last_seen {
+            details.push(format!("last seen {}", format_system_time(last_seen)));
+        }
+        if let Some(ip) = device.last_ip {
+            details.push(format!("from {}", ip));
+        }
+        if let Some(reason) = &device.disconnect_reason {
+            details.push(format!("({})", reason));
+        }
+        if !details.is_empty() {
+            println!("  {}", details.join(" "));
+        }
+        if device.wakeable {
+            println!("  can be woken up with Wake on LAN");
+        }
+    }
+    println!(
+        "  connected for {} in total",
+        format_duration(device.uptime_secs)
+    );
+
+    let Some(metadata) = &device.metadata else {
+        if let Some(os) = &device.os {
+            println!("  {}", os);
+        }
+        return;
+    };
+
+    println!(
+        "  {}@{}, {} ({}), daemon {}",
+        metadata.user,
+        metadata.hostname,
+        metadata.os_version,
+        metadata.arch,
+        metadata.daemon_version
+    );
+    if !metadata.ips.is_empty() {
+        let ips: Vec<_> = metadata.ips.iter().map(|ip| ip.to_string()).collect();
+        println!("  IPs:  {}", ips.join(", "));
+    }
+    if !metadata.macs.is_empty() {
+        println!("  MACs: {}", metadata.macs.join(", "));
+    }
+
+    let mut presence = Vec::new();
+    if let Some(idle) = metadata.idle_secs {
+        presence.push(format!("idle for {}", format_duration(idle)));
+    }
+    if let Some(last_activity) = metadata.last_activity {
+        presence.push(format!("last active {}", format_time(last_activity)));
+    }
+    if let Some(battery) = &metadata.battery {
+        presence.push(format!("battery {}", battery));
+    }
+    if !presence.is_empty() {
+        println!("  {}", presence.join(", "));
+    }
+}
+
+pub(crate) fn format_system_time(time: SystemTime) -> String {
+    format_time(
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    )
+}
+
+/// Lists the connected and offline devices with what they reported about themselves
+pub fn list(config: &ClientConfig) {
+    let devices = match rest::fetch_devices(config, true) {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!("Failed to fetch devices: {}", e);
+            return;
+        }
+    };
+
+    if devices.is_empty() {
+        println!("No devices have connected yet");
+        return;
+    }
+
+    // Offline devices are greyed out on terminals
+    let dim = std::io::stdout().is_terminal();
+    for device in devices.iter().filter(|d| d.online) {
+        print_device(device);
+    }
+    for device in devices.iter().filter(|d| !d.online) {
+        if dim {
+            print!("{}", DIM);
+        }
+        print_device(device);
+        if dim {
+            print!("{}", RESET);
+        }
+    }
+}
+
+/// Offers to wake up an offline device, if it is the Wake on LAN target of the server
+pub fn offer_wake_on_lan(config: &ClientConfig, device: &ConnectedClientInfo) {
+    if !device.wakeable || !std::io::stdin().is_terminal() {
+        return;
+    }
+
+    let wake = Confirm::new()
+        .with_prompt(format!("Send a Wake on LAN packet to {}?", device.name))
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+    if wake {
+        match rest::send_wol(config) {
+            Ok(()) => println!("Sent WOL packet"),
+            Err(e) => eprintln!("Failed to send WOL packet: {}", e),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use common::ipc::{IpcRequest, IpcResponse};
+
+use crate::schedule::format_time;
+
+// How many recent events `mysti status` shows
+const SHOWN_EVENTS: usize = 10;
+
+pub(crate) fn request(request: IpcRequest) -> Option<IpcResponse> {
+    match common::ipc::send(&request) {
+        Ok(IpcResponse::Error(e)) => {
+            eprintln!("The daemon returned an error: {}", e);
+            None
+        }
+        Ok(response) => Some(response),
+        Err(e) => {
+            eprintln!("{:#}", e);
+            None
+        }
+    }
+}
+
+/// Shows the state of the daemon running on this device
+pub fn status() {
+    let Some(IpcResponse::Status(status)) = request(IpcRequest::Status) else {
+        return;
+    };
+
+    println!("Device:     {}", status.device_name);
+    println!("Server:     {} ({})", status.server_host, status.connection);
+    println!(
+        "Clipboard:  {}",
+        match status.paused {
+            Some(state) => state.to_string(),
+            None => "syncing".to_string(),
+        }
+    );
+    println!("Actions:    {}", status.actions.join(", "));
+    println!("History:    {} entries", status.history_entries);
+
+    let Some(IpcResponse::Events(events)) = request(IpcRequest::RecentEvents) else {
+        return;
+    };
+    if !events.is_empty() {
+        println!("\nRecent events:");
+        for event in events.iter().rev().take(SHOWN_EVENTS) {
+            println!("  {} {}", format_time(event.time), event.description);
+        }
+    }
+}
+
+pub fn reload() {
+    if request(IpcRequest::ReloadConfig).is_some() {
+        println!("Reloaded the daemon configuration");
+    }
+}
@@ -1,22 +1,85 @@
-use common::{action::Action, client_config::ClientConfig, name::client_name};
-use dialoguer::FuzzySelect;
+use common::{
+    action::{Action, ActionInfo, ActionParam, ParamType},
+    client_config::ClientConfig,
+    name::client_name,
+    types::ConnectedClientInfo,
+};
+use dialoguer::{Confirm, FuzzySelect, Input, Select};
 
 use crate::rest::post_action;
 
+mod audit;
+mod clip;
+mod devices;
+mod local;
+mod pause;
 mod rest;
+mod schedule;
 
-fn send_action_interactive(config: &ClientConfig) {
+const RESULT_POLL_ATTEMPTS: usize = 6;
+// Confirmation dialogs time out after 30 seconds by default
+const CONFIRM_POLL_ATTEMPTS: usize = 240;
+const RESULT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+fn prompt_param(param: &ActionParam, idx: usize, count: usize) -> String {
+    let mut prompt = format!("{} ({}/{})", param.name, idx, count);
+    if let Some(description) = &param.description {
+        prompt = format!("{} - {}", prompt, description);
+    }
+
+    match param.kind {
+        ParamType::Enum => {
+            let default = param
+                .default
+                .as_ref()
+                .and_then(|d| param.choices.iter().position(|c| c == d))
+                .unwrap_or(0);
+
+            let selection = Select::new()
+                .with_prompt(prompt)
+                .items(&param.choices)
+                .default(default)
+                .interact()
+                .unwrap();
+
+            param.choices[selection].clone()
+        }
+        ParamType::Bool => Confirm::new()
+            .with_prompt(prompt)
+            .default(param.default.as_deref() == Some("true"))
+            .interact()
+            .unwrap()
+            .to_string(),
+        _ => {
+            let mut input = Input::<String>::new().with_prompt(prompt);
+            if let Some(default) = &param.default {
+                input = input.default(default.clone());
+            }
+
+            input
+                .validate_with(|value: &String| param.validate(value).map_err(|e| e.to_string()))
+                .interact_text()
+                .unwrap()
+        }
+    }
+}
+
+/// Lets the user pick a client, one of its actions and the arguments
+fn select_action(
+    config: &ClientConfig,
+    prompt: &str,
+) -> Option<(ConnectedClientInfo, ActionInfo, Action)> {
     let clients = match rest::fetch_connected_clients(config) {
         Ok(clients) => clients,
         Err(e) => {
             eprintln!("Failed to fetch connected clients:\n{}\nMake sure you are connected to the internet", e);
-            return;
+            return None;
         }
     };
 
     if clients.is_empty() {
         println!("No clients are currently connected");
-        return;
+        return None;
     }
 
     let current_client_name = client_name();
@@ -31,7 +94,7 @@ fn send_action_interactive(config: &ClientConfig) {
         0
     } else {
         FuzzySelect::new()
-            .with_prompt("Select a client to run an action")
+            .with_prompt(prompt)
             .items(&clients)
             .default(default_idx)
             .interact()
@@ -47,59 +110,229 @@ fn send_action_interactive(config: &ClientConfig) {
             &client
                 .supported_actions
                 .iter()
-                .map(|(name, _)| name)
+                .map(|action| match &action.description {
+                    Some(description) => format!("{} - {}", action.name, description),
+                    None => action.name.clone(),
+                })
                 .collect::<Vec<_>>(),
         )
         .default(0)
         .interact()
         .unwrap();
 
-    let (selected_action, required_args) = &client.supported_actions[action];
+    let selected_action = &client.supported_actions[action];
 
-    let args = (1..=*required_args)
-        .into_iter()
-        .map(|arg| {
-            dialoguer::Input::<String>::new()
-                .with_prompt(format!(
-                    "Enter value for argument {}/{}",
-                    arg, required_args
-                ))
-                .interact()
-                .unwrap()
-        })
+    let args = selected_action
+        .params
+        .iter()
+        .enumerate()
+        .map(|(idx, param)| prompt_param(param, idx + 1, selected_action.params.len()))
         .collect::<Vec<_>>();
 
     let action = Action {
-        action: selected_action.clone(),
-        args: args,
+        action: selected_action.name.clone(),
+        args,
+        id: None,
+    };
+
+    Some((client.clone(), selected_action.clone(), action))
+}
+
+fn send_action_interactive(config: &ClientConfig) {
+    let Some((client, selected_action, action)) =
+        select_action(config, "Select a client to run an action")
+    else {
+        return;
     };
 
     println!("Running action {} on client {}", &action, client.name);
 
-    post_action(&config, client.id, &action).expect("Failed to post action");
+    let created = post_action(config, client.id, &action).expect("Failed to post action");
 
     println!("Sent action.");
+
+    let attempts = if selected_action.confirm {
+        println!("Waiting for the user of {} to confirm...", client.name);
+        CONFIRM_POLL_ATTEMPTS
+    } else {
+        RESULT_POLL_ATTEMPTS
+    };
+
+    wait_for_result(config, created.id, attempts);
+}
+
+// Shows what the client reported, e.g. whether the action was started or refused
+fn wait_for_result(config: &ClientConfig, action_id: u64, attempts: usize) {
+    for _ in 0..attempts {
+        std::thread::sleep(RESULT_POLL_INTERVAL);
+
+        match rest::fetch_action_results(config, action_id) {
+            Ok(results) if !results.is_empty() => {
+                for result in results {
+                    println!("{}", result);
+                }
+                return;
+            }
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("Failed to fetch action result: {}", e);
+                return;
+            }
+        }
+    }
+
+    println!("The client did not report a result yet.");
+}
+
+/// Finds the connected device with the name, or whose name uniquely contains the target
+fn find_client(config: &ClientConfig, target: &str) -> Option<ConnectedClientInfo> {
+    let devices = match rest::fetch_devices(config, true) {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!("Failed to fetch connected clients: {}", e);
+            return None;
+        }
+    };
+    let (clients, offline): (Vec<_>, Vec<_>) = devices.into_iter().partition(|d| d.online);
+
+    // Match exact names first, then unique parts of names like the hostname
+    let target_lower = target.to_lowercase();
+    let matching = match clients.iter().find(|c| c.name == target) {
+        Some(client) => vec![client],
+        None => clients
+            .iter()
+            .filter(|c| c.name.to_lowercase().contains(&target_lower))
+            .collect(),
+    };
+
+    match matching.as_slice() {
+        [client] => Some((*client).clone()),
+        [] => {
+            eprintln!("No connected device matches {}", target);
+
+            let target_lower = target.to_lowercase();
+            let device = offline
+                .iter()
+                .find(|d| d.name.to_lowercase().contains(&target_lower))?;
+            if let Some(last_seen) = device.last_seen {
+                eprintln!(
+                    "{} was last seen {}",
+                    device.name,
+                    devices::format_system_time(last_seen)
+                );
+            }
+            devices::offer_wake_on_lan(config, device);
+            None
+        }
+        _ => {
+            eprintln!("{} matches multiple devices:", target);
+            for client in matching {
+                eprintln!("  {}", client.name);
+            }
+            None
+        }
+    }
+}
+
+/// Runs an action on a device or, if the target starts with @, on all devices in a group
+fn run_action(config: &ClientConfig, target: &str, action: &str, args: &[&str]) {
+    let action = Action {
+        action: action.to_string(),
+        args: args.iter().map(|a| a.to_string()).collect(),
+        id: None,
+    };
+
+    if let Some(group) = target.strip_prefix('@') {
+        println!("Running action {} on group {}", action, group);
+
+        match rest::post_group_action(config, group, &action) {
+            Ok(result) => {
+                for device in result.devices {
+                    println!("{}", device);
+                }
+            }
+            Err(e) => eprintln!("Failed to run action on group {}: {}", group, e),
+        }
+        return;
+    }
+
+    let Some(client) = find_client(config, target) else {
+        return;
+    };
+
+    println!("Running action {} on client {}", action, client.name);
+
+    match post_action(config, client.id, &action) {
+        Ok(created) => {
+            let confirm = client
+                .supported_actions
+                .iter()
+                .any(|a| a.name == action.action && a.confirm);
+            wait_for_result(
+                config,
+                created.id,
+                if confirm {
+                    CONFIRM_POLL_ATTEMPTS
+                } else {
+                    RESULT_POLL_ATTEMPTS
+                },
+            );
+        }
+        Err(e) => eprintln!("Failed to post action: {}", e),
+    }
 }
 
 fn main() {
     let config = common::client_config::find_parse_config().expect("Failed to parse config");
 
-    let args = std::env::args().collect::<Vec<_>>();
-    match args.len() {
-        1 => send_action_interactive(&config),
-        2 if match config.wol_shortcut {
-            Some(ref shortcut) => args[1].eq(shortcut),
-            None => false,
-        } =>
-        {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => send_action_interactive(&config),
+        ["schedule"] | ["schedule", "add"] => schedule::add_interactive(&config),
+        ["schedule", "list"] => schedule::list(&config),
+        ["schedule", "cancel", id] => schedule::cancel(&config, id),
+        ["run", target, action, args @ ..] => run_action(&config, target, action, args),
+        ["clip", "set", text] => clip::set(&config, text, None),
+        ["clip", "set", text, "--ttl", ttl] => clip::set(&config, text, Some(ttl)),
+        ["clip", "clear"] => clip::clear(&config),
+        ["clip", "pick"] => clip::pick(),
+        ["devices"] => devices::list(&config),
+        ["audit", args @ ..] => audit::list(&config, args),
+        ["status"] => local::status(),
+        ["pause", args @ ..] => pause::pause(&config, args),
+        ["resume"] => pause::resume(&config, None),
+        ["resume", "--device", device] => pause::resume(&config, Some(device)),
+        ["reload"] => local::reload(),
+        [shortcut] if config.wol_shortcut.as_deref() == Some(*shortcut) => {
             rest::send_wol(&config).expect("Failed to send WOL packet");
             println!("Sent WOL packet");
         }
         _ => {
-            // This is where the fun begins
-            unimplemented!(
-                "Arguments are not yet supported. Please use the interactive mode for now."
-            );
+            eprintln!("Usage:");
+            eprintln!("  mysti                        run an action interactively");
+            if let Some(shortcut) = &config.wol_shortcut {
+                eprintln!("  mysti {:<22} send a Wake on LAN packet", shortcut);
+            }
+            eprintln!("  mysti run <device> <action> [args...]");
+            eprintln!("  mysti run @<group> <action> [args...]");
+            eprintln!("  mysti schedule [add]         schedule an action interactively");
+            eprintln!("  mysti schedule list          list scheduled actions");
+            eprintln!("  mysti schedule cancel <id>   cancel a scheduled action");
+            eprintln!("  mysti clip set <text> [--ttl <seconds>]");
+            eprintln!("  mysti clip clear             clear the clipboard everywhere");
+            eprintln!("  mysti clip pick              restore an entry of the clipboard history");
+            eprintln!("  mysti devices                list connected and offline devices");
+            eprintln!("  mysti audit [--device <device>] [--type <type>] [--limit <n>]");
+            eprintln!("  mysti status                 show the state of the local daemon");
+            eprintln!("  mysti pause [outgoing|incoming|both] [<duration>] [--device <device>]");
+            eprintln!("  mysti resume [--device <device>]");
+            eprintln!("  mysti reload                 reload the daemon configuration");
+            std::process::exit(2);
         }
     }
 }
@@ -0,0 +1,122 @@
+use common::client_config::ClientConfig;
+use common::ipc::IpcRequest;
+use common::sync::{PauseDirection, PauseRequest};
+
+use crate::{find_client, local, rest};
+
+/// Parses durations like 90, 90s, 30m or 2h into seconds
+fn parse_duration(duration: &str) -> Option<u64> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => duration.split_at(i),
+        None => (duration, "s"),
+    };
+
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok().map(|n| n * factor)
+}
+
+fn parse_pause_args<'a>(args: &[&'a str]) -> Result<(PauseRequest, Option<&'a str>), String> {
+    let mut request = PauseRequest::default();
+    let mut device = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if *arg == "--device" {
+            device = Some(*args.next().ok_or("--device needs a device name")?);
+        } else if let Ok(direction) = arg.parse::<PauseDirection>() {
+            request.direction = direction;
+        } else if let Some(duration) = parse_duration(arg) {
+            request.duration = Some(duration);
+        } else {
+            return Err(format!(
+                "invalid argument {}, expected a direction or a duration like 30m",
+                arg
+            ));
+        }
+    }
+
+    Ok((request, device))
+}
+
+/// Pauses clipboard sync of the local daemon or, with --device, of a connected device
+pub fn pause(config: &ClientConfig, args: &[&str]) {
+    let (request, device) = match parse_pause_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut description = format!("Paused {} clipboard sync", request.direction);
+    if let Some(duration) = request.duration {
+        description = format!("{} for {}s", description, duration);
+    }
+
+    match device {
+        Some(device) => {
+            let Some(client) = find_client(config, device) else {
+                return;
+            };
+            match rest::pause_device(config, client.id, &request) {
+                Ok(()) => println!("{} on {}", description, client.name),
+                Err(e) => eprintln!("Failed to pause {}: {}", client.name, e),
+            }
+        }
+        None => {
+            if local::request(IpcRequest::Pause(request)).is_some() {
+                println!("{}", description);
+            }
+        }
+    }
+}
+
+/// Resumes clipboard sync of the local daemon or of a connected device
+pub fn resume(config: &ClientConfig, device: Option<&str>) {
+    match device {
+        Some(device) => {
+            let Some(client) = find_client(config, device) else {
+                return;
+            };
+            match rest::resume_device(config, client.id) {
+                Ok(()) => println!("Resumed clipboard sync on {}", client.name),
+                Err(e) => eprintln!("Failed to resume {}: {}", client.name, e),
+            }
+        }
+        None => {
+            if local::request(IpcRequest::Resume).is_some() {
+                println!("Resumed clipboard sync");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_args() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("30m"), Some(1800));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("2d"), None);
+
+        let (request, device) =
+            parse_pause_args(&["outgoing", "10m", "--device", "laptop"]).unwrap();
+        assert_eq!(request.direction, PauseDirection::Outgoing);
+        assert_eq!(request.duration, Some(600));
+        assert_eq!(device, Some("laptop"));
+
+        assert_eq!(
+            parse_pause_args(&[]).unwrap(),
+            (PauseRequest::default(), None)
+        );
+        assert!(parse_pause_args(&["sideways"]).is_err());
+    }
+}
@@ -1,15 +1,31 @@
 use common::{
-    action::Action, client_config::ClientConfig, types::ConnectedClientInfo,
+    action::Action,
+    client_config::ClientConfig,
+    sync::PauseRequest,
+    types::{
+        ActionCreated, AuditEntry, ConnectedClientInfo, DeviceActionResult, GroupDispatchResult,
+        ScheduleRequest, ScheduledAction,
+    },
     url::generate_request_url,
 };
 
 use serde::de::DeserializeOwned;
 
-fn fetch_and_decode_json<T>(url: url::Url) -> anyhow::Result<T>
+fn fetch_and_decode_json<T>(cfg: &ClientConfig, url: url::Url) -> anyhow::Result<T>
 where
     T: DeserializeOwned,
 {
-    let response = reqwest::blocking::get(url)?;
+    fetch_and_decode_json_with(&cfg.token, url)
+}
+
+fn fetch_and_decode_json_with<T>(token: &str, url: url::Url) -> anyhow::Result<T>
+where
+    T: DeserializeOwned,
+{
+    let response = reqwest::blocking::Client::new()
+        .get(url)
+        .bearer_auth(token)
+        .send()?;
 
     if response.status().is_success() {
         // Try to deserialize the JSON response
@@ -24,14 +40,42 @@ where
 }
 
 pub fn fetch_connected_clients(cfg: &ClientConfig) -> anyhow::Result<Vec<ConnectedClientInfo>> {
-    fetch_and_decode_json(generate_request_url(
-        cfg,
-        "/devices",
-        common::url::Scheme::HTTP,
-    )?)
+    fetch_devices(cfg, false)
+}
+
+/// Lists the connected devices and, if requested, the offline devices the server knows
+pub fn fetch_devices(
+    cfg: &ClientConfig,
+    include_offline: bool,
+) -> anyhow::Result<Vec<ConnectedClientInfo>> {
+    let mut url = generate_request_url(cfg, "/devices", common::url::Scheme::HTTP)?;
+    if include_offline {
+        url.query_pairs_mut().append_pair("include_offline", "true");
+    }
+
+    fetch_and_decode_json(cfg, url)
+}
+
+/// Fetches the server's audit log, filtered by the given query parameters
+pub fn fetch_audit(
+    cfg: &ClientConfig,
+    filters: &[(&str, &str)],
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let token = cfg.audit_token.as_deref().ok_or(anyhow::anyhow!(
+        "audit_token is not set, it needs the audit_token of the server"
+    ))?;
+
+    let mut url = generate_request_url(cfg, "/audit", common::url::Scheme::HTTP)?;
+    url.query_pairs_mut().extend_pairs(filters);
+
+    fetch_and_decode_json_with(token, url)
 }
 
-pub fn post_action(cfg: &ClientConfig, client_id: usize, action: &Action) -> anyhow::Result<()> {
+pub fn post_action(
+    cfg: &ClientConfig,
+    client_id: usize,
+    action: &Action,
+) -> anyhow::Result<ActionCreated> {
     let url = generate_request_url(
         cfg,
         &format!("/actions/create/{}", client_id),
@@ -39,10 +83,14 @@ pub fn post_action(cfg: &ClientConfig, client_id: usize, action: &Action) -> any
     )?;
 
     let client = reqwest::blocking::Client::new();
-    let response = client.post(url).json(action).send()?;
+    let response = client
+        .post(url)
+        .bearer_auth(&cfg.token)
+        .json(action)
+        .send()?;
 
     if response.status().is_success() {
-        Ok(())
+        Ok(response.json()?)
     } else {
         Err(anyhow::anyhow!(
             "Request failed with status code: {}",
@@ -51,11 +99,195 @@ pub fn post_action(cfg: &ClientConfig, client_id: usize, action: &Action) -> any
     }
 }
 
+pub fn fetch_action_results(
+    cfg: &ClientConfig,
+    action_id: u64,
+) -> anyhow::Result<Vec<DeviceActionResult>> {
+    fetch_and_decode_json(
+        cfg,
+        generate_request_url(
+            cfg,
+            &format!("/actions/{}", action_id),
+            common::url::Scheme::HTTP,
+        )?,
+    )
+}
+
 pub fn send_wol(cfg: &ClientConfig) -> anyhow::Result<()> {
     let url = generate_request_url(cfg, "/wol", common::url::Scheme::HTTP)?;
 
     let client = reqwest::blocking::Client::new();
-    let response = client.post(url).send()?;
+    let response = client.post(url).bearer_auth(&cfg.token).send()?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Request failed with status code: {}",
+            response.status()
+        ))
+    }
+}
+
+pub fn create_schedule(
+    cfg: &ClientConfig,
+    request: &ScheduleRequest,
+) -> anyhow::Result<ScheduledAction> {
+    let url = generate_request_url(cfg, "/schedules", common::url::Scheme::HTTP)?;
+
+    let client = reqwest::blocking::Client::new();
+    let response = client
+        .post(url)
+        .bearer_auth(&cfg.token)
+        .json(request)
+        .send()?;
+
+    if response.status().is_success() {
+        Ok(response.json()?)
+    } else {
+        Err(anyhow::anyhow!(
+            "Request failed with status code {}: {}",
+            response.status(),
+            response.text().unwrap_or_default()
+        ))
+    }
+}
+
+pub fn fetch_schedules(cfg: &ClientConfig) -> anyhow::Result<Vec<ScheduledAction>> {
+    fetch_and_decode_json(
+        cfg,
+        generate_request_url(cfg, "/schedules", common::url::Scheme::HTTP)?,
+    )
+}
+
+pub fn cancel_schedule(cfg: &ClientConfig, id: u64) -> anyhow::Result<()> {
+    let url = generate_request_url(
+        cfg,
+        &format!("/schedules/{}", id),
+        common::url::Scheme::HTTP,
+    )?;
+
+    let client = reqwest::blocking::Client::new();
+    let response = client.delete(url).bearer_auth(&cfg.token).send()?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Request failed with status code: {}",
+            response.status()
+        ))
+    }
+}
+
+pub fn post_group_action(
+    cfg: &ClientConfig,
+    group: &str,
+    action: &Action,
+) -> anyhow::Result<GroupDispatchResult> {
+    let mut url = generate_request_url(
+        cfg,
+        &format!("/groups/{}/actions", group),
+        common::url::Scheme::HTTP,
+    )?;
+    url.query_pairs_mut().append_pair("wait", "10");
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(std::time::Duration::from_secs(30))
+        .build()?;
+    let response = client
+        .post(url)
+        .bearer_auth(&cfg.token)
+        .json(action)
+        .send()?;
+
+    if response.status().is_success() {
+        Ok(response.json()?)
+    } else {
+        Err(anyhow::anyhow!(
+            "Request failed with status code {}: {}",
+            response.status(),
+            response.text().unwrap_or_default()
+        ))
+    }
+}
+
+pub fn set_clipboard(cfg: &ClientConfig, text: &str, ttl: Option<u64>) -> anyhow::Result<()> {
+    let mut url = generate_request_url(cfg, "/devices/clipboard", common::url::Scheme::HTTP)?;
+    if let Some(ttl) = ttl {
+        url.query_pairs_mut().append_pair("ttl", &ttl.to_string());
+    }
+
+    let client = reqwest::blocking::Client::new();
+    let response = client
+        .post(url)
+        .bearer_auth(&cfg.token)
+        .body(text.to_string())
+        .send()?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Request failed with status code: {}",
+            response.status()
+        ))
+    }
+}
+
+pub fn clear_clipboard(cfg: &ClientConfig) -> anyhow::Result<()> {
+    let url = generate_request_url(cfg, "/devices/clipboard", common::url::Scheme::HTTP)?;
+
+    let client = reqwest::blocking::Client::new();
+    let response = client.delete(url).bearer_auth(&cfg.token).send()?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Request failed with status code: {}",
+            response.status()
+        ))
+    }
+}
+
+pub fn pause_device(
+    cfg: &ClientConfig,
+    client_id: usize,
+    request: &PauseRequest,
+) -> anyhow::Result<()> {
+    let url = generate_request_url(
+        cfg,
+        &format!("/devices/{}/pause", client_id),
+        common::url::Scheme::HTTP,
+    )?;
+
+    let client = reqwest::blocking::Client::new();
+    let response = client
+        .post(url)
+        .bearer_auth(&cfg.token)
+        .json(request)
+        .send()?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Request failed with status code: {}",
+            response.status()
+        ))
+    }
+}
+
+pub fn resume_device(cfg: &ClientConfig, client_id: usize) -> anyhow::Result<()> {
+    let url = generate_request_url(
+        cfg,
+        &format!("/devices/{}/resume", client_id),
+        common::url::Scheme::HTTP,
+    )?;
+
+    let client = reqwest::blocking::Client::new();
+    let response = client.post(url).bearer_auth(&cfg.token).send()?;
 
     if response.status().is_success() {
         Ok(())
@@ -0,0 +1,136 @@
+use chrono::{Local, NaiveTime, TimeZone};
+use common::{client_config::ClientConfig, types::ScheduleRequest};
+use dialoguer::Input;
+
+use crate::{rest, select_action};
+
+/// When a scheduled action should run, as entered by the user
+enum When {
+    Cron(String),
+    At(u64),
+    Delay(u64),
+}
+
+/// Parses "30m", "in 2h", "23:00" or a cron expression like "0 23 * * Mon-Fri"
+fn parse_when(input: &str) -> Result<When, String> {
+    let input = input.trim();
+    let delay = input.strip_prefix("in ").unwrap_or(input).trim();
+
+    let unit_seconds = match delay.chars().last() {
+        Some('s') => Some(1),
+        Some('m') => Some(60),
+        Some('h') => Some(60 * 60),
+        Some('d') => Some(24 * 60 * 60),
+        _ => None,
+    };
+    if let Some(unit_seconds) = unit_seconds {
+        if let Ok(amount) = delay[..delay.len() - 1].trim().parse::<u64>() {
+            return Ok(When::Delay(amount * unit_seconds));
+        }
+    }
+
+    if let Ok(time) = NaiveTime::parse_from_str(input, "%H:%M") {
+        let now = Local::now();
+        let mut date = now.date_naive();
+        if time <= now.time() {
+            date = date.succ_opt().ok_or("Invalid date")?;
+        }
+
+        let at = Local
+            .from_local_datetime(&date.and_time(time))
+            .earliest()
+            .ok_or("This time does not exist in the local time zone")?;
+        return Ok(When::At(at.timestamp() as u64));
+    }
+
+    if input.split_whitespace().count() >= 5 {
+        return Ok(When::Cron(input.to_string()));
+    }
+
+    Err("Enter a delay like 30m, a time like 23:00 or a cron expression".to_string())
+}
+
+pub(crate) fn format_time(timestamp: u64) -> String {
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+pub fn add_interactive(config: &ClientConfig) {
+    let Some((client, _, action)) = select_action(config, "Select a client to schedule an action")
+    else {
+        return;
+    };
+
+    let when = Input::<String>::new()
+        .with_prompt("When? (e.g. 30m, 23:00 or a cron expression like \"0 23 * * Mon-Fri\")")
+        .validate_with(|input: &String| parse_when(input).map(|_| ()))
+        .interact_text()
+        .unwrap();
+
+    let mut request = ScheduleRequest {
+        action,
+        target: Some(client.name.clone()),
+        cron: None,
+        at: None,
+        delay_secs: None,
+    };
+    match parse_when(&when).unwrap() {
+        When::Cron(cron) => request.cron = Some(cron),
+        When::At(at) => request.at = Some(at),
+        When::Delay(delay) => request.delay_secs = Some(delay),
+    }
+
+    match rest::create_schedule(config, &request) {
+        Ok(schedule) => println!(
+            "Scheduled {} on {} as #{}, next run at {}",
+            schedule.action,
+            client.name,
+            schedule.id,
+            format_time(schedule.next_run)
+        ),
+        Err(e) => eprintln!("Failed to schedule action: {}", e),
+    }
+}
+
+pub fn list(config: &ClientConfig) {
+    let schedules = match rest::fetch_schedules(config) {
+        Ok(schedules) => schedules,
+        Err(e) => {
+            eprintln!("Failed to fetch schedules: {}", e);
+            return;
+        }
+    };
+
+    if schedules.is_empty() {
+        println!("No actions are scheduled");
+        return;
+    }
+
+    for schedule in schedules {
+        println!(
+            "#{} {} on {}, next run at {}{}",
+            schedule.id,
+            schedule.action,
+            schedule.target.as_deref().unwrap_or("all devices"),
+            format_time(schedule.next_run),
+            match &schedule.cron {
+                Some(cron) => format!(" (repeats: {})", cron),
+                None => String::new(),
+            }
+        );
+    }
+}
+
+pub fn cancel(config: &ClientConfig, id: &str) {
+    let Ok(id) = id.trim_start_matches('#').parse::<u64>() else {
+        eprintln!("Invalid schedule id {}", id);
+        return;
+    };
+
+    match rest::cancel_schedule(config, id) {
+        Ok(()) => println!("Cancelled #{}", id),
+        Err(e) => eprintln!("Failed to cancel #{}: {}", id, e),
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Display, process::Command};
+use std::{collections::HashMap, fmt::Display, process::Command};
 
 #[cfg(windows)]
 use std::os::windows::process::CommandExt;
@@ -6,11 +6,72 @@ use std::os::windows::process::CommandExt;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::substitution;
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Action {
     pub action: String,
     #[serde(default = "Vec::new")]
     pub args: Vec<String>,
+
+    // Assigned by the server so results can be matched to the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionStatus {
+    // The command was started and is not waited for
+    Started,
+    // The command ran to completion, see the exit code
+    Finished,
+    // The command could not be started or exited unsuccessfully
+    Failed,
+    // The command was killed, e.g. because of a timeout
+    Killed,
+    // The command was not started, e.g. because of the concurrency limit
+    Refused,
+}
+
+/// Reported by a client after it received an action
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ActionResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub action: String,
+    pub status: ActionStatus,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    // Reason for the status, or the end of stderr for failed commands
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ActionResult {
+    pub fn new(action: &Action, status: ActionStatus, message: Option<String>) -> Self {
+        Self {
+            id: action.id,
+            action: action.action.clone(),
+            status,
+            exit_code: None,
+            message,
+        }
+    }
+}
+
+impl Display for ActionResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.status)?;
+        if let Some(exit_code) = self.exit_code {
+            write!(f, " (exit code {})", exit_code)?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
 }
 
 impl Display for Action {
@@ -23,54 +84,313 @@ impl Display for Action {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    #[default]
+    String,
+    Int,
+    Bool,
+    Enum,
+    Path,
+}
+
+impl Display for ParamType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ParamType::String => "string",
+            ParamType::Int => "int",
+            ParamType::Bool => "bool",
+            ParamType::Enum => "enum",
+            ParamType::Path => "path",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A named parameter of an action, defined with `[[action.param]]` in the client config.
+/// The n-th parameter is substituted for `$n`/`%n` in the command string.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ActionParam {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    #[serde(default, rename = "type")]
+    pub kind: ParamType,
+
+    // Used when the argument is not given; a parameter with a default is optional
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+
+    // Allowed values for enum parameters
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<String>,
+
+    // Regex the whole value must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validation: Option<String>,
+}
+
+impl ActionParam {
+    /// Creates an untyped parameter, used for actions that only reference `$n` without a schema
+    pub fn positional(index: usize) -> Self {
+        Self {
+            name: format!("argument {}", index),
+            description: None,
+            kind: ParamType::String,
+            default: None,
+            choices: Vec::new(),
+            validation: None,
+        }
+    }
+
+    pub fn is_optional(&self) -> bool {
+        self.default.is_some()
+    }
+
+    /// Makes sure the parameter definition itself makes sense
+    fn check_definition(&self) -> Result<()> {
+        if self.kind == ParamType::Enum && self.choices.is_empty() {
+            return Err(anyhow::anyhow!(
+                "enum parameter {} has no choices",
+                self.name
+            ));
+        }
+        if let Some(validation) = &self.validation {
+            regex::Regex::new(validation).context(format!(
+                "invalid validation regex for parameter {}",
+                self.name
+            ))?;
+        }
+        if let Some(default) = &self.default {
+            self.validate(default)
+                .context(format!("invalid default for parameter {}", self.name))?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self, value: &str) -> Result<()> {
+        match self.kind {
+            ParamType::String => (),
+            ParamType::Int => {
+                value.parse::<i64>().map_err(|_| {
+                    anyhow::anyhow!("{} must be an integer, got {:?}", self.name, value)
+                })?;
+            }
+            ParamType::Bool => {
+                if value != "true" && value != "false" {
+                    return Err(anyhow::anyhow!(
+                        "{} must be true or false, got {:?}",
+                        self.name,
+                        value
+                    ));
+                }
+            }
+            ParamType::Enum => {
+                if !self.choices.iter().any(|c| c == value) {
+                    return Err(anyhow::anyhow!(
+                        "{} must be one of {:?}, got {:?}",
+                        self.name,
+                        self.choices,
+                        value
+                    ));
+                }
+            }
+            ParamType::Path => {
+                if value.is_empty() {
+                    return Err(anyhow::anyhow!("{} must not be an empty path", self.name));
+                }
+            }
+        }
+
+        if let Some(validation) = &self.validation {
+            // Anchor the regex so it has to match the whole value
+            let regex = regex::Regex::new(&format!("^(?:{})$", validation))?;
+            if !regex.is_match(value) {
+                return Err(anyhow::anyhow!(
+                    "{} does not match {}, got {:?}",
+                    self.name,
+                    validation,
+                    value
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What a client advertises to the server about each of its actions
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ActionInfo {
+    pub name: String,
+    // Shown next to the name when picking an action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub params: Vec<ActionParam>,
+    // Whether the user of the device has to accept the action first
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+impl ActionInfo {
+    pub fn required_args(&self) -> usize {
+        self.params.iter().filter(|p| !p.is_optional()).count()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunMode {
+    // Start the command and report that it started
+    #[default]
+    Detached,
+    // Wait for the command and report its exit code and output
+    Attached,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
 pub struct ActionDefinition {
     pub name: String,
+    pub description: Option<String>,
 
     pub linux: Option<String>,
     pub macos: Option<String>,
     pub windows: Option<String>,
+
+    #[serde(default = "Vec::new", rename = "param")]
+    pub params: Vec<ActionParam>,
+
+    // Kill the command after this many seconds
+    pub timeout: Option<u64>,
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // Written to the standard input of the command
+    pub stdin: Option<String>,
+    #[serde(default)]
+    pub mode: RunMode,
+    // How many instances of this action may run at once, e.g. 1 to never run it twice
+    pub max_concurrent: Option<usize>,
+    // Unix user to run the command as, the daemon needs the permission to switch to it
+    pub run_as: Option<String>,
+    // Ask the local user before running the action
+    #[serde(default)]
+    pub confirm: bool,
 }
 
 impl ActionDefinition {
-    pub fn find_by_name(
-        name: &String,
-        actions: &Vec<ActionDefinition>,
-    ) -> Option<ActionDefinition> {
-        actions.iter().find(|a| &a.name == name).cloned()
+    pub fn find_by_name(name: &str, actions: &[ActionDefinition]) -> Option<ActionDefinition> {
+        actions.iter().find(|a| a.name == name).cloned()
     }
 
-    pub fn required_args(&self) -> usize {
-        // Find the number of arguments in the command_string, as in $1, %2, etc
-        let mut max_arg = 0;
-        if let Some(command_string) = self.command_string().ok() {
-            let mut arg = String::new();
-            let mut expect_digit = false;
-
-            for c in command_string.chars() {
-                if c == '%' || c == '$' {
-                    expect_digit = true;
-                } else if expect_digit && c.is_digit(10) {
-                    arg.push(c);
-                } else if expect_digit {
-                    expect_digit = false;
-                    let arg_num = arg.parse::<usize>().unwrap_or(0);
-                    if arg_num > max_arg {
-                        max_arg = arg_num;
-                    }
-                    arg.clear();
-                }
-            }
+    /// The parameters of this action. If no schema is defined, they are derived from the
+    /// `$n`/`%n` references in the command string.
+    pub fn parameters(&self) -> Vec<ActionParam> {
+        if !self.params.is_empty() {
+            return self.params.clone();
+        }
+
+        (1..=self.referenced_args())
+            .map(ActionParam::positional)
+            .collect()
+    }
 
-            let arg_num = arg.parse::<usize>().unwrap_or(0);
-            if arg_num > max_arg {
-                max_arg = arg_num;
+    pub fn info(&self) -> ActionInfo {
+        ActionInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            params: self.parameters(),
+            confirm: self.confirm,
+        }
+    }
+
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.max_concurrent == Some(0) {
+            return Err(anyhow::anyhow!(
+                "action {}: max_concurrent must be at least 1",
+                self.name
+            ));
+        }
+
+        for command_string in [&self.linux, &self.macos, &self.windows]
+            .into_iter()
+            .flatten()
+        {
+            substitution::check_command(command_string).context(format!("action {}", self.name))?;
+        }
+
+        for param in &self.params {
+            param
+                .check_definition()
+                .context(format!("action {}", self.name))?;
+        }
+
+        let mut seen_optional = false;
+        for param in &self.params {
+            if param.is_optional() {
+                seen_optional = true;
+            } else if seen_optional {
+                return Err(anyhow::anyhow!(
+                    "action {}: required parameter {} follows an optional one",
+                    self.name,
+                    param.name
+                ));
             }
+        }
 
-            arg.clear();
+        Ok(())
+    }
+
+    /// Validates the given arguments against the parameters and fills in defaults
+    pub fn resolve_args(&self, args: &[String]) -> Result<Vec<String>> {
+        let params = self.parameters();
+
+        if args.len() > params.len() && !self.params.is_empty() {
+            return Err(anyhow::anyhow!(
+                "command {} takes at most {} arguments, but {} were provided",
+                self.name,
+                params.len(),
+                args.len()
+            ));
         }
 
-        max_arg
+        let mut resolved = Vec::with_capacity(params.len());
+        for (i, param) in params.iter().enumerate() {
+            match (args.get(i), &param.default) {
+                (Some(arg), _) => {
+                    param.validate(arg)?;
+                    resolved.push(arg.clone());
+                }
+                (None, Some(default)) => resolved.push(default.clone()),
+                (None, None) => {
+                    return Err(anyhow::anyhow!(
+                        "command {} requires {} arguments, but only {} were provided",
+                        self.name,
+                        self.required_args(),
+                        args.len()
+                    ))
+                }
+            }
+        }
+
+        // Without a schema, surplus arguments are passed along like before
+        resolved.extend(args.iter().skip(params.len()).cloned());
+
+        Ok(resolved)
+    }
+
+    pub fn required_args(&self) -> usize {
+        self.info().required_args()
+    }
+
+    fn referenced_args(&self) -> usize {
+        // Find the number of arguments in the command_string, as in $1, %2, etc
+        self.command_string()
+            .map(|command_string| substitution::max_placeholder(command_string))
+            .unwrap_or(0)
     }
 
     pub fn is_available(&self) -> bool {
@@ -96,63 +416,79 @@ impl ActionDefinition {
         ))
     }
 
-    fn to_command(&self, args: &Vec<String>) -> Result<Command> {
+    /// Builds the command with the given arguments, working directory and environment
+    pub fn to_command(&self, args: &[String]) -> Result<Command> {
         let command_string = self.command_string()?;
 
-        let split_results: Vec<String> = shell_words::split(command_string)?;
-        if split_results.len() == 0 {
-            return Err(anyhow::anyhow!("command {} is empty", self.name));
-        }
+        let args = self.resolve_args(args)?;
 
-        if args.len() < self.required_args() {
-            return Err(anyhow::anyhow!(
-                "command {} requires {} arguments, but only {} were provided",
-                self.name,
-                self.required_args(),
-                args.len()
-            ));
+        // Arguments are passed as separate words, or quoted if the command runs a shell script
+        let argv = substitution::command_args(command_string, &args)
+            .context(format!("cannot build command {}", self.name))?;
+
+        let mut command = Command::new(&argv[0]);
+        command.args(&argv[1..]);
+        command.envs(&self.env);
+        if let Some(working_dir) = &self.working_dir {
+            command.current_dir(working_dir);
         }
 
-        // Replace %1, etc or $1, $2 in the command string with the arguments
-        // Note that we might have escaped % or $ characters in the command string
-        // and that we might have multiple digits in the argument number
-        #[cfg(not(windows))]
-        let mut command = Command::new(split_results[0].clone());
+        // CREATE_NO_WINDOW
         #[cfg(windows)]
-        let mut command = {
-            let mut command = Command::new("cmd");
-            command
-                .arg("/C")
-                .arg("start")
-                .arg("")
-                .arg("/B")
-                .arg(split_results[0].clone())
-                // CREATE_NO_WINDOW
-                .creation_flags(0x08000000);
-            command
-        };
+        command.creation_flags(0x08000000);
 
-        for i in 1..split_results.len() {
-            let mut arg = split_results[i].clone();
-            for j in (0..args.len()).rev() {
-                arg = arg.replace(&format!("%{}", j + 1), &args[j]);
-                arg = arg.replace(&format!("${}", j + 1), &args[j]);
-            }
-            command.arg(arg);
+        if let Some(user) = &self.run_as {
+            run_as(&mut command, user).context(format!("cannot run {} as {}", self.name, user))?;
         }
 
         Ok(command)
     }
+}
 
-    pub fn run(&self, args: &Vec<String>) -> Result<()> {
-        let mut command = self.to_command(args)?;
+#[cfg(unix)]
+fn run_as(command: &mut Command, user: &str) -> Result<()> {
+    use std::os::unix::process::CommandExt;
 
-        let _ = command
-            .spawn()
-            .context(format!("failed to run command {}", self.name))?;
+    let name = std::ffi::CString::new(user)?;
+    // getpwnam_r instead of getpwnam, which returns a shared static struct and runs on any thread
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    let (uid, gid) = loop {
+        let mut passwd = std::mem::MaybeUninit::<libc::passwd>::uninit();
+        let mut result = std::ptr::null_mut();
+        // SAFETY: all pointers are valid for the call, passwd is only read if result points to it
+        let error = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                passwd.as_mut_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
 
-        Ok(())
-    }
+        match error {
+            // The strings of the entry don't fit into the buffer
+            libc::ERANGE if buffer.len() < 1024 * 1024 => buffer.resize(buffer.len() * 2, 0),
+            0 if !result.is_null() => {
+                // SAFETY: getpwnam_r initialized passwd, result points to it
+                let passwd = unsafe { passwd.assume_init() };
+                break (passwd.pw_uid, passwd.pw_gid);
+            }
+            0 => return Err(anyhow::anyhow!("user {} does not exist", user)),
+            error => {
+                return Err(std::io::Error::from_raw_os_error(error))
+                    .context(format!("cannot look up user {}", user))
+            }
+        }
+    };
+
+    command.uid(uid).gid(gid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run_as(_command: &mut Command, _user: &str) -> Result<()> {
+    Err(anyhow::anyhow!("run_as is only supported on Unix"))
 }
 
 #[cfg(test)]
@@ -167,18 +503,85 @@ mod tests {
             linux: command_str.clone(),
             macos: command_str.clone(),
             windows: command_str.clone(),
+            ..Default::default()
         };
 
         assert_eq!(action.required_args(), 22);
 
         let cmd_list = action.to_command(&vec!["a".to_string(); 22]).unwrap();
 
-        let cmd_list: Vec<&str> = cmd_list
-            .get_args()
-            .into_iter()
-            .map(|s| s.to_str().unwrap())
-            .collect();
+        let cmd_list: Vec<&str> = cmd_list.get_args().map(|s| s.to_str().unwrap()).collect();
 
         assert_eq!(cmd_list, vec!["a", "a", "am", "aß"]);
     }
+
+    #[test]
+    fn action_params() {
+        let config = crate::client_config::parse(
+            r#"
+    server_host = "ws://localhost"
+    token = "token"
+
+    [[action]]
+    name = "Volume"
+    linux = "amixer set Master $1% $2"
+
+    [[action.param]]
+    name = "level"
+    type = "int"
+    validation = "[0-9]{1,3}"
+
+    [[action.param]]
+    name = "mode"
+    type = "enum"
+    choices = ["mute", "unmute"]
+    default = "unmute"
+    "#,
+        )
+        .unwrap();
+
+        let action = &config.actions[0];
+        assert_eq!(action.required_args(), 1);
+        assert_eq!(action.info().params[1].kind, ParamType::Enum);
+
+        assert_eq!(
+            action.resolve_args(&["50".to_string()]).unwrap(),
+            vec!["50", "unmute"]
+        );
+        assert!(action.resolve_args(&[]).is_err());
+        assert!(action.resolve_args(&["loud".to_string()]).is_err());
+        assert!(action.resolve_args(&["1000".to_string()]).is_err());
+        assert!(action
+            .resolve_args(&["50".to_string(), "louder".to_string()])
+            .is_err());
+    }
+
+    #[test]
+    fn action_params_invalid_definition() {
+        let config = r#"
+    server_host = "ws://localhost"
+    token = "token"
+
+    [[action]]
+    name = "Test"
+    linux = "echo $1 $2"
+
+    [[action.param]]
+    name = "first"
+    default = "a"
+
+    [[action.param]]
+    name = "second"
+    "#;
+
+        assert!(crate::client_config::parse(config).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn run_as_users() {
+        let mut command = Command::new("true");
+        assert!(run_as(&mut command, "root").is_ok());
+        assert!(run_as(&mut command, "mysti-no-such-user").is_err());
+    }
 }
@@ -2,16 +2,216 @@ use anyhow::{Context, Result};
 use serde::Deserialize;
 
 use crate::action::ActionDefinition;
+use crate::sync::SyncRule;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ClientConfig {
     pub server_host: String,
     pub token: String,
+    // The server's audit_token, only needed to read the audit log
+    pub audit_token: Option<String>,
 
     pub wol_shortcut: Option<String>,
 
+    // Reported to the server, so this device is part of tag-based groups
+    #[serde(default)]
+    pub tags: Vec<String>,
+
     #[serde(default = "Vec::new", rename = "action")]
     pub actions: Vec<ActionDefinition>,
+
+    #[serde(default)]
+    pub confirmation: ConfirmationConfig,
+
+    // Overrides the clipboard sync rules the server has for this device
+    #[serde(default)]
+    pub sync: SyncRule,
+
+    #[serde(default)]
+    pub sensitive: SensitiveConfig,
+
+    #[serde(default)]
+    pub history: HistoryConfig,
+
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+
+    // How many messages are kept for the server while disconnected
+    #[serde(default = "default_outbox_size")]
+    pub outbox_size: usize,
+}
+
+fn default_outbox_size() -> usize {
+    100
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfirmationPolicy {
+    Accept,
+    #[default]
+    Deny,
+}
+
+/// How actions with `confirm = true` are confirmed
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfirmationConfig {
+    // Seconds until the dialog closes and the on_timeout policy is applied
+    #[serde(default = "default_confirmation_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub on_timeout: ConfirmationPolicy,
+    // Used when no dialog can be shown, e.g. on headless systems
+    #[serde(default)]
+    pub headless: ConfirmationPolicy,
+}
+
+fn default_confirmation_timeout() -> u64 {
+    30
+}
+
+impl Default for ConfirmationConfig {
+    fn default() -> Self {
+        Self {
+            timeout: default_confirmation_timeout(),
+            on_timeout: ConfirmationPolicy::default(),
+            headless: ConfirmationPolicy::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SensitivePolicy {
+    // Never sync the content
+    #[default]
+    Skip,
+    // Never sync the content and clear the local clipboard after expire_after seconds
+    Expire,
+}
+
+/// How clipboard content that looks like a secret is detected and handled
+#[derive(Deserialize, Debug, Clone)]
+pub struct SensitiveConfig {
+    // Treat content that password managers mark as concealed or transient as sensitive
+    #[serde(default = "default_true")]
+    pub respect_hints: bool,
+
+    // Text matching any of these regexes is sensitive
+    #[serde(default)]
+    pub patterns: Vec<String>,
+
+    // Single words with at least this many bits of entropy per character are sensitive
+    pub min_entropy: Option<f64>,
+    #[serde(default = "default_min_entropy_length")]
+    pub min_entropy_length: usize,
+
+    #[serde(default)]
+    pub policy: SensitivePolicy,
+    #[serde(default = "default_expire_after")]
+    pub expire_after: u64,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_min_entropy_length() -> usize {
+    12
+}
+
+fn default_expire_after() -> u64 {
+    30
+}
+
+impl Default for SensitiveConfig {
+    fn default() -> Self {
+        Self {
+            respect_hints: true,
+            patterns: Vec::new(),
+            min_entropy: None,
+            min_entropy_length: default_min_entropy_length(),
+            policy: SensitivePolicy::default(),
+            expire_after: default_expire_after(),
+        }
+    }
+}
+
+/// The local clipboard history of the daemon
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryConfig {
+    // Set to 0 to disable the history
+    #[serde(default = "default_history_entries")]
+    pub max_entries: usize,
+    #[serde(default = "default_history_bytes")]
+    pub max_bytes: usize,
+
+    // Keep the history across restarts in this file
+    pub file: Option<String>,
+    // Encrypt the history file with a key derived from this passphrase
+    pub passphrase: Option<String>,
+}
+
+fn default_history_entries() -> usize {
+    50
+}
+
+fn default_history_bytes() -> usize {
+    20 * 1024 * 1024
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: default_history_entries(),
+            max_bytes: default_history_bytes(),
+            file: None,
+            passphrase: None,
+        }
+    }
+}
+
+/// How the daemon retries connecting to the server
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReconnectConfig {
+    // Seconds before the first retry, multiplied after every failed attempt up to max_delay
+    #[serde(default = "default_initial_delay")]
+    pub initial_delay: f64,
+    #[serde(default = "default_max_delay")]
+    pub max_delay: f64,
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    // Delays are randomly shortened or extended by up to this fraction,
+    // so devices don't all reconnect at the same moment after a server restart
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+}
+
+fn default_initial_delay() -> f64 {
+    1.0
+}
+
+fn default_max_delay() -> f64 {
+    120.0
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_jitter() -> f64 {
+    0.2
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: default_initial_delay(),
+            max_delay: default_max_delay(),
+            multiplier: default_multiplier(),
+            jitter: default_jitter(),
+        }
+    }
 }
 
 pub fn parse_file(name: &str) -> Result<ClientConfig> {
@@ -29,6 +229,34 @@ pub fn parse(content: &str) -> Result<ClientConfig> {
         if !names.insert(&action.name) {
             return Err(anyhow::anyhow!("Duplicate action name {}", action.name));
         }
+
+        action.check()?;
+    }
+
+    if res.history.passphrase.is_some() && res.history.file.is_none() {
+        return Err(anyhow::anyhow!(
+            "history.passphrase is only used with history.file"
+        ));
+    }
+
+    if res.outbox_size == 0 {
+        return Err(anyhow::anyhow!("outbox_size must be positive"));
+    }
+
+    let reconnect = &res.reconnect;
+    if !(reconnect.initial_delay > 0.0 && reconnect.max_delay >= reconnect.initial_delay) {
+        return Err(anyhow::anyhow!(
+            "reconnect.initial_delay must be positive and at most reconnect.max_delay"
+        ));
+    }
+    if reconnect.multiplier < 1.0 || !(0.0..=1.0).contains(&reconnect.jitter) {
+        return Err(anyhow::anyhow!(
+            "reconnect.multiplier must be at least 1 and reconnect.jitter between 0 and 1"
+        ));
+    }
+
+    for pattern in &res.sensitive.patterns {
+        regex::Regex::new(pattern).context(format!("invalid sensitive pattern {}", pattern))?;
     }
 
     Ok(res)
@@ -37,6 +265,11 @@ pub fn parse(content: &str) -> Result<ClientConfig> {
 /// Look for the configuration file in common directories
 /// and stop when finding the first
 pub fn find_parse_config() -> Result<ClientConfig> {
+    find_parse_config_file().map(|(_, config)| config)
+}
+
+/// Like `find_parse_config`, but also returns the path of the file that was used
+pub fn find_parse_config_file() -> Result<(String, ClientConfig)> {
     // Search in different order depending on the OS
     // Linux/Mac: XDG_CONFIG_HOME, $HOME/.config, working directory
     // Windows: %USERPROFILE%\.config, working directory
@@ -70,11 +303,11 @@ pub fn find_parse_config() -> Result<ClientConfig> {
     for path in &paths {
         log::debug!("Trying to parse config file {}", path);
 
-        match parse_file(&path) {
-            Ok(config) => return Ok(config),
+        match parse_file(path) {
+            Ok(config) => return Ok((path.clone(), config)),
             Err(e) => {
                 // Only log if the file exists
-                if std::path::Path::new(&path).exists() {
+                if std::path::Path::new(path).exists() {
                     log::warn!("Failed to parse config file {}: {}", path, e);
                 }
             }
@@ -0,0 +1,274 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use crate::sync::{ContentKind, PauseRequest, PauseState};
+
+/// A request to the local daemon. Each connection sends one JSON line and gets one JSON line back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IpcRequest {
+    Status,
+    // Stops syncing the clipboard until resumed or the duration is over
+    Pause(PauseRequest),
+    Resume,
+    // Sets the clipboard, which is then synced like content copied locally
+    PushClipboard { text: String },
+    RecentEvents,
+    // Reads the configuration file again
+    ReloadConfig,
+    History,
+    // Puts a history entry back into the clipboard
+    Restore { id: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum IpcResponse {
+    Ok,
+    Status(DaemonStatus),
+    Events(Vec<RecentEvent>),
+    History(Vec<HistoryEntry>),
+    Error(String),
+}
+
+/// Why connecting to the server failed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionFailure {
+    // The server host could not be resolved
+    Dns,
+    // The server could not be reached, e.g. connection refused or timed out
+    Network,
+    Tls,
+    // The server answered 401 or 403
+    Unauthorized,
+    // The server answered 404, which it also does for a wrong token
+    NotFound,
+    // The server answered, but not with a WebSocket connection
+    Protocol,
+}
+
+impl ConnectionFailure {
+    /// Whether retrying is unlikely to help until the configuration changes
+    pub fn is_config_error(&self) -> bool {
+        matches!(
+            self,
+            ConnectionFailure::Unauthorized | ConnectionFailure::NotFound
+        )
+    }
+}
+
+impl std::fmt::Display for ConnectionFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionFailure::Dns => write!(f, "the server host could not be resolved"),
+            ConnectionFailure::Network => write!(f, "the server could not be reached"),
+            ConnectionFailure::Tls => write!(f, "the TLS handshake failed"),
+            ConnectionFailure::Unauthorized => {
+                write!(f, "the server rejected the token")
+            }
+            ConnectionFailure::NotFound => write!(
+                f,
+                "the server rejected the connection, check token and server_host"
+            ),
+            ConnectionFailure::Protocol => write!(f, "the server did not accept a WebSocket"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting {
+        attempts: u64,
+        last_error: Option<String>,
+        #[serde(default)]
+        failure: Option<ConnectionFailure>,
+        // Unix timestamp of the next attempt
+        #[serde(default)]
+        retry_at: Option<u64>,
+    },
+    Connected {
+        // Unix timestamp
+        since: u64,
+    },
+}
+
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Connecting { attempts: 0, .. } => write!(f, "connecting"),
+            ConnectionState::Connecting {
+                attempts,
+                last_error,
+                failure,
+                retry_at,
+            } => {
+                write!(f, "connecting, {} failed attempts", attempts)?;
+                if let Some(failure) = failure {
+                    write!(f, ": {}", failure)?;
+                }
+                if let Some(retry_at) = retry_at {
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    write!(f, ", next attempt in {}s", retry_at.saturating_sub(now))?;
+                }
+                if let Some(error) = last_error {
+                    write!(f, " (last error: {})", error)?;
+                }
+                Ok(())
+            }
+            ConnectionState::Connected { .. } => write!(f, "connected"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DaemonStatus {
+    pub device_name: String,
+    pub server_host: String,
+    pub connection: ConnectionState,
+    pub paused: Option<PauseState>,
+    pub actions: Vec<String>,
+    pub history_entries: usize,
+}
+
+/// Something the daemon did recently, e.g. sending the clipboard or running an action
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecentEvent {
+    // Unix timestamp
+    pub time: u64,
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryOrigin {
+    // Copied on this device
+    Local,
+    // Received from another device
+    Remote,
+}
+
+/// A clipboard history entry without its content
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub origin: EntryOrigin,
+    pub kind: ContentKind,
+    // Unix timestamp
+    pub copied_at: u64,
+    pub size: usize,
+    // The start of the text, or a description for images
+    pub preview: String,
+}
+
+/// Where the daemon listens for local requests
+pub fn socket_path() -> Result<PathBuf> {
+    #[cfg(target_os = "windows")]
+    {
+        let user = std::env::var("USERNAME").unwrap_or_default();
+        Ok(PathBuf::from(format!(r"\\.\pipe\mysti-{}", user)))
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            // Anyone can create files in the temporary directory, so use our own directory in it
+            None => private_dir(
+                &std::env::temp_dir().join(format!("mysti-{}", unsafe { libc::getuid() })),
+            )?,
+        };
+        Ok(dir.join("mysti.sock"))
+    }
+}
+
+/// Creates the directory if needed and makes sure only the current user can access it,
+/// as another user might have created it first
+#[cfg(not(target_os = "windows"))]
+fn private_dir(dir: &std::path::Path) -> Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(e).context(format!("failed to create {}", dir.display())),
+    }
+
+    // Not following symlinks, which could point anywhere
+    let metadata =
+        std::fs::symlink_metadata(dir).context(format!("failed to check {}", dir.display()))?;
+    if !metadata.is_dir()
+        || metadata.uid() != unsafe { libc::getuid() }
+        || metadata.mode() & 0o077 != 0
+    {
+        return Err(anyhow::anyhow!(
+            "{} must be a directory that only the current user can access",
+            dir.display()
+        ));
+    }
+
+    Ok(dir.to_path_buf())
+}
+
+/// Sends a request to the daemon listening at the path
+pub fn send_to(path: &std::path::Path, request: &IpcRequest) -> Result<IpcResponse> {
+    #[cfg(target_os = "windows")]
+    let stream = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path);
+    #[cfg(not(target_os = "windows"))]
+    let stream = std::os::unix::net::UnixStream::connect(path);
+
+    let mut stream = stream.context(format!(
+        "Failed to connect to the daemon at {}, is it running?",
+        path.display()
+    ))?;
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+
+    serde_json::from_str(&response).context("Invalid response from the daemon")
+}
+
+/// Sends a request to the local daemon
+pub fn send(request: &IpcRequest) -> Result<IpcResponse> {
+    send_to(&socket_path()?, request)
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_socket_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("mysti-ipc-{}", std::process::id()));
+        assert_eq!(private_dir(&dir).unwrap(), dir);
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        // Existing directories are fine as long as they stay private
+        assert!(private_dir(&dir).is_ok());
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(private_dir(&dir).is_err());
+        std::fs::remove_dir(&dir).unwrap();
+
+        // E.g. a symlink to a directory of another user
+        let link = dir.with_extension("link");
+        std::os::unix::fs::symlink(std::env::temp_dir(), &link).unwrap();
+        assert!(private_dir(&link).is_err());
+        std::fs::remove_file(&link).unwrap();
+    }
+}
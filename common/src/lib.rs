@@ -3,14 +3,77 @@ use serde::{Deserialize, Serialize};
 
 pub mod action;
 pub mod client_config;
+pub mod ipc;
 pub mod name;
+pub mod substitution;
+pub mod sync;
 pub mod types;
 pub mod url;
+pub mod watch;
+
+/// The longest TTL of an expiring clipboard, one day
+pub const MAX_CLIPBOARD_TTL: u64 = 24 * 60 * 60;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ActionMessage {
     Clipboard(ClipboardContent),
+    // Clipboard content that is cleared after ttl seconds, unless it was replaced by then
+    ExpiringClipboard { content: ClipboardContent, ttl: u64 },
+    // Clears the clipboard on the server and all devices
+    ClearClipboard,
+    // Sent to a daemon to pause or resume its clipboard sync
+    PauseSync(sync::PauseRequest),
+    ResumeSync,
+    // Sent by a daemon whenever its sync is paused or resumed
+    PauseState(Option<sync::PauseState>),
+    // Sent by a daemon after connecting and when its configuration changes
+    Capabilities(Capabilities),
+    // Sent by a daemon after connecting and then periodically
+    Metadata(Box<types::DeviceMetadata>),
     Action(action::Action),
+    ActionResult(action::ActionResult),
+}
+
+impl ActionMessage {
+    /// The clipboard content carried by this message, if any
+    pub fn clipboard_content(&self) -> Option<&ClipboardContent> {
+        match self {
+            ActionMessage::Clipboard(content) => Some(content),
+            ActionMessage::ExpiringClipboard { content, .. } => Some(content),
+            _ => None,
+        }
+    }
+
+    /// Name of the message type, e.g. for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ActionMessage::Clipboard(_) => "clipboard",
+            ActionMessage::ExpiringClipboard { .. } => "expiring_clipboard",
+            ActionMessage::ClearClipboard => "clear_clipboard",
+            ActionMessage::PauseSync(_) => "pause_sync",
+            ActionMessage::ResumeSync => "resume_sync",
+            ActionMessage::PauseState(_) => "pause_state",
+            ActionMessage::Capabilities(_) => "capabilities",
+            ActionMessage::Metadata(_) => "metadata",
+            ActionMessage::Action(_) => "action",
+            ActionMessage::ActionResult(_) => "action_result",
+        }
+    }
+}
+
+/// What a daemon offers and how it takes part in clipboard sync. Daemons send it after
+/// connecting and whenever it changes; the WebSocket URL query contains the basics as well.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub supported_actions: Vec<action::ActionInfo>,
+    pub tags: Vec<String>,
+    pub sync: sync::SyncRule,
+    // Operating system, e.g. "Fedora Linux 39 (Workstation Edition)"
+    #[serde(default)]
+    pub os: Option<String>,
+    // Version of the daemon
+    #[serde(default)]
+    pub version: Option<String>,
 }
 
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -29,6 +92,57 @@ impl Debug for ClipboardContent {
 }
 
 const BINARY_IMAGE_MESSAGE_TYPE: u8 = 3;
+// Followed by the TTL as 8 byte big endian number, then the image
+const BINARY_EXPIRING_IMAGE_MESSAGE_TYPE: u8 = 4;
+
+// Images are sent as binary messages, as JSON would be much larger
+fn encode_binary(message: &ActionMessage) -> Option<Vec<u8>> {
+    match message {
+        ActionMessage::Clipboard(ClipboardContent::Image(content)) => {
+            let mut bytes = vec![BINARY_IMAGE_MESSAGE_TYPE];
+            bytes.extend(content);
+            Some(bytes)
+        }
+        ActionMessage::ExpiringClipboard {
+            content: ClipboardContent::Image(content),
+            ttl,
+        } => {
+            let mut bytes = vec![BINARY_EXPIRING_IMAGE_MESSAGE_TYPE];
+            bytes.extend(ttl.to_be_bytes());
+            bytes.extend(content);
+            Some(bytes)
+        }
+        _ => None,
+    }
+}
+
+fn decode_binary(bytes: &[u8]) -> anyhow::Result<ActionMessage> {
+    // The first byte of the binary message is the type of the message.
+    let Some((&message_type, bytes)) = bytes.split_first() else {
+        return Err(anyhow::anyhow!("Invalid binary message - message is empty"));
+    };
+
+    match message_type {
+        BINARY_IMAGE_MESSAGE_TYPE => Ok(ActionMessage::Clipboard(ClipboardContent::Image(
+            bytes.to_vec(),
+        ))),
+        BINARY_EXPIRING_IMAGE_MESSAGE_TYPE => {
+            if bytes.len() < 8 {
+                return Err(anyhow::anyhow!("Invalid binary message - missing TTL"));
+            }
+            let (ttl, image) = bytes.split_at(8);
+
+            Ok(ActionMessage::ExpiringClipboard {
+                content: ClipboardContent::Image(image.to_vec()),
+                ttl: u64::from_be_bytes(ttl.try_into()?),
+            })
+        }
+        _ => Err(anyhow::anyhow!(
+            "Invalid binary message - invalid message type {}",
+            message_type
+        )),
+    }
+}
 
 use warp::ws::Message as WebSocketMessage;
 
@@ -43,24 +157,7 @@ impl TryFrom<&WebSocketMessage> for ActionMessage {
                 Err(_) => Err(anyhow::anyhow!("Error converting text message to string")),
             }
         } else if message.is_binary() {
-            let bytes = message.as_bytes();
-            // The first byte of the binary message is the type of the message.
-            if bytes.len() <= 0 {
-                return Err(anyhow::anyhow!("Invalid binary message - message is empty"));
-            }
-
-            match bytes[0] {
-                BINARY_IMAGE_MESSAGE_TYPE => {
-                    // Image message
-                    Ok(ActionMessage::Clipboard(ClipboardContent::Image(
-                        bytes[1..].to_vec(),
-                    )))
-                }
-                _ => Err(anyhow::anyhow!(
-                    "Invalid binary message - invalid message type {}",
-                    bytes[0]
-                )),
-            }
+            decode_binary(message.as_bytes())
         } else {
             Err(anyhow::anyhow!("Invalid message type"))
         }
@@ -72,14 +169,10 @@ impl TryFrom<ActionMessage> for WebSocketMessage {
     type Error = anyhow::Error;
 
     fn try_from(message: ActionMessage) -> Result<Self, Self::Error> {
-        match message {
-            // Special messages get a custom handler, otherwise just serialize the message as JSON.
-            ActionMessage::Clipboard(ClipboardContent::Image(content)) => {
-                let mut bytes = vec![BINARY_IMAGE_MESSAGE_TYPE];
-                bytes.extend(content);
-                Ok(WebSocketMessage::binary(bytes))
-            }
-            _ => Ok(WebSocketMessage::text(serde_json::to_string(&message)?)),
+        // Special messages get a custom handler, otherwise just serialize the message as JSON.
+        match encode_binary(&message) {
+            Some(bytes) => Ok(WebSocketMessage::binary(bytes)),
+            None => Ok(WebSocketMessage::text(serde_json::to_string(&message)?)),
         }
     }
 }
@@ -92,25 +185,7 @@ impl TryFrom<Message> for ActionMessage {
     fn try_from(message: Message) -> Result<Self, Self::Error> {
         match message {
             Message::Text(msg) => Ok(serde_json::from_str(&msg)?),
-            Message::Binary(bytes) => {
-                // The first byte of the binary message is the type of the message.
-                if bytes.len() <= 0 {
-                    return Err(anyhow::anyhow!("Invalid binary message - message is empty"));
-                }
-
-                match bytes[0] {
-                    BINARY_IMAGE_MESSAGE_TYPE => {
-                        // Image message
-                        Ok(ActionMessage::Clipboard(ClipboardContent::Image(
-                            bytes[1..].to_vec(),
-                        )))
-                    }
-                    _ => Err(anyhow::anyhow!(
-                        "Invalid binary message - invalid message type {}",
-                        bytes[0]
-                    )),
-                }
-            }
+            Message::Binary(bytes) => decode_binary(&bytes),
             _ => Err(anyhow::anyhow!("Invalid message type")),
         }
     }
@@ -120,14 +195,38 @@ impl TryFrom<ActionMessage> for Message {
     type Error = anyhow::Error;
 
     fn try_from(message: ActionMessage) -> Result<Self, Self::Error> {
-        match message {
-            // Special messages get a custom handler, otherwise just serialize the message as JSON.
-            ActionMessage::Clipboard(ClipboardContent::Image(content)) => {
-                let mut bytes = vec![BINARY_IMAGE_MESSAGE_TYPE];
-                bytes.extend(content);
-                Ok(Message::Binary(bytes))
-            }
-            _ => Ok(Message::Text(serde_json::to_string(&message)?)),
+        // Special messages get a custom handler, otherwise just serialize the message as JSON.
+        match encode_binary(&message) {
+            Some(bytes) => Ok(Message::Binary(bytes)),
+            None => Ok(Message::Text(serde_json::to_string(&message)?)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_messages() {
+        let messages = [
+            ActionMessage::Clipboard(ClipboardContent::Image(vec![1, 2, 3])),
+            ActionMessage::ExpiringClipboard {
+                content: ClipboardContent::Image(vec![1, 2, 3]),
+                ttl: 30,
+            },
+            ActionMessage::ExpiringClipboard {
+                content: ClipboardContent::Text("text".to_string()),
+                ttl: 30,
+            },
+            ActionMessage::ClearClipboard,
+        ];
+
+        for message in messages {
+            let encoded = Message::try_from(message.clone()).unwrap();
+            assert_eq!(ActionMessage::try_from(encoded).unwrap(), message);
+        }
+
+        assert!(decode_binary(&[BINARY_EXPIRING_IMAGE_MESSAGE_TYPE, 0, 0]).is_err());
+    }
+}
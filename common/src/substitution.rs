@@ -0,0 +1,821 @@
+//! Substitution of `$n`/`%n` placeholders in command strings.
+//!
+//! Arguments never get interpreted by a shell unless the command itself starts one. In that case
+//! they are quoted for the context they appear in (POSIX shells), or rejected if they cannot be
+//! passed safely (`cmd.exe`, PowerShell). Placeholders in positions we can't prove safe, e.g.
+//! behind `sudo` or in a `python -c` script, are rejected when the command is checked.
+use anyhow::Result;
+
+enum Segment {
+    Literal(String),
+    // 1-based index of the argument
+    Placeholder(usize),
+}
+
+/// Splits a string into literal parts and `$n`/`%n` placeholders
+fn parse(input: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if (c == '$' || c == '%') && chars.peek().is_some_and(|n| n.is_ascii_digit()) {
+            let mut digits = String::new();
+            while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                digits.push(*d);
+                chars.next();
+            }
+
+            match digits.parse::<usize>() {
+                Ok(index) if index > 0 => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(index));
+                }
+                _ => {
+                    literal.push(c);
+                    literal.push_str(&digits);
+                }
+            }
+        } else {
+            literal.push(c);
+        }
+    }
+
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    segments
+}
+
+/// Returns the highest placeholder index used in the input, e.g. 3 for `echo $1 %3`
+pub fn max_placeholder(input: &str) -> usize {
+    parse(input)
+        .iter()
+        .filter_map(|s| match s {
+            Segment::Placeholder(i) => Some(*i),
+            Segment::Literal(_) => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn arg(args: &[String], index: usize) -> Result<&str> {
+    args.get(index - 1).map(|a| a.as_str()).ok_or_else(|| {
+        anyhow::anyhow!(
+            "placeholder {} is used, but only {} arguments were provided",
+            index,
+            args.len()
+        )
+    })
+}
+
+/// How a word of the command line is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WordContext {
+    // Passed as a single argument to the program, substitution is safe as is
+    Plain,
+    // A script run by a POSIX shell (`sh -c <script>`)
+    PosixScript,
+    // Interpreted by cmd.exe or PowerShell, which have no reliable quoting
+    UnquotableScript,
+    // The program itself
+    Program,
+    // Interpreted in a way we can't analyse, so no placeholders are allowed
+    Forbidden,
+}
+
+const POSIX_SHELLS: &[&str] = &["sh", "bash", "dash", "zsh", "ksh", "ash"];
+
+// Programs that run another program given in their arguments
+const WRAPPERS: &[&str] = &[
+    "sudo",
+    "doas",
+    "env",
+    "timeout",
+    "nohup",
+    "nice",
+    "ionice",
+    "setsid",
+    "stdbuf",
+    "chrt",
+    "taskset",
+    "time",
+    "exec",
+    "command",
+    "xargs",
+    "chroot",
+    "unshare",
+    "nsenter",
+    "systemd-run",
+    "flatpak-spawn",
+    "firejail",
+    "caffeinate",
+];
+
+// Programs that pass their arguments on to a shell we don't know
+const REMOTE_SHELLS: &[&str] = &["ssh", "su", "runuser", "watch"];
+
+// Programs that take code as an argument
+const INTERPRETERS: &[&str] = &[
+    "python",
+    "perl",
+    "ruby",
+    "node",
+    "nodejs",
+    "deno",
+    "php",
+    "lua",
+    "tclsh",
+    "osascript",
+    "awk",
+    "gawk",
+    "mawk",
+    "nawk",
+];
+
+/// Whether the program name is one of the names, ignoring versions like python3.12
+fn is_one_of(name: &str, names: &[&str]) -> bool {
+    names.iter().any(|n| {
+        name.strip_prefix(n)
+            .is_some_and(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.'))
+    })
+}
+
+fn is_windows_shell(name: &str) -> bool {
+    // Backslashes in unquoted Windows paths get lost when splitting, so only look at the end
+    ["cmd", "powershell", "pwsh"]
+        .iter()
+        .any(|shell| name.ends_with(shell))
+}
+
+fn runs_code(name: &str) -> bool {
+    is_one_of(name, POSIX_SHELLS)
+        || is_one_of(name, WRAPPERS)
+        || is_one_of(name, REMOTE_SHELLS)
+        || is_one_of(name, INTERPRETERS)
+        || is_windows_shell(name)
+        || matches!(name, "fish" | "csh" | "tcsh" | "nu")
+}
+
+fn program_name(program: &str) -> String {
+    let name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let name = name.to_lowercase();
+    name.strip_suffix(".exe").unwrap_or(&name).to_string()
+}
+
+fn word_contexts(words: &[String]) -> Vec<WordContext> {
+    let mut contexts = vec![WordContext::Plain; words.len()];
+    let Some(program) = words.first() else {
+        return contexts;
+    };
+    contexts[0] = WordContext::Program;
+
+    let name = program_name(program);
+    let forbid_from = |contexts: &mut Vec<WordContext>, start: usize| {
+        for context in contexts.iter_mut().skip(start) {
+            *context = WordContext::Forbidden;
+        }
+    };
+    // The first argument that isn't an option, e.g. the script file of `bash script.sh`
+    let first_operand = || {
+        words
+            .iter()
+            .skip(1)
+            .position(|w| !w.starts_with('-'))
+            .map(|i| i + 1)
+    };
+
+    if is_one_of(&name, WRAPPERS) {
+        // We can't parse the wrapper's options, so only a shell or interpreter it runs is
+        // analysed; anything else could end up as the program
+        match words
+            .iter()
+            .skip(1)
+            .position(|w| runs_code(&program_name(w)))
+        {
+            Some(i) => {
+                forbid_from(&mut contexts, 1);
+                let nested = word_contexts(&words[i + 1..]);
+                contexts.truncate(i + 1);
+                contexts.extend(nested.into_iter().map(|context| match context {
+                    WordContext::Program => WordContext::Forbidden,
+                    context => context,
+                }));
+            }
+            None => forbid_from(&mut contexts, 1),
+        }
+    } else if is_one_of(&name, POSIX_SHELLS) {
+        // The word after -c (or e.g. -ec) is the script, words after it are positional parameters
+        if let Some(flag) = words
+            .iter()
+            .skip(1)
+            .take_while(|w| w.starts_with('-'))
+            .position(|w| !w.starts_with("--") && w.ends_with('c'))
+        {
+            if let Some(context) = contexts.get_mut(flag + 2) {
+                *context = WordContext::PosixScript;
+            }
+        } else if let Some(script) = first_operand() {
+            contexts[script] = WordContext::Forbidden;
+        }
+    } else if is_windows_shell(&name) && name.ends_with("cmd") {
+        // Everything after /C or /K is parsed again by cmd
+        if let Some(flag) = words
+            .iter()
+            .position(|w| w.eq_ignore_ascii_case("/c") || w.eq_ignore_ascii_case("/k"))
+        {
+            for context in contexts.iter_mut().skip(flag + 1) {
+                *context = WordContext::UnquotableScript;
+            }
+        }
+    } else if is_windows_shell(&name) {
+        if let Some(flag) = words.iter().position(|w| {
+            w.eq_ignore_ascii_case("-c")
+                || w.eq_ignore_ascii_case("-command")
+                || w.eq_ignore_ascii_case("-encodedcommand")
+        }) {
+            for context in contexts.iter_mut().skip(flag + 1) {
+                *context = WordContext::UnquotableScript;
+            }
+        }
+    } else if matches!(name.as_str(), "fish" | "csh" | "tcsh" | "nu") {
+        // Other shells have their own quoting rules we don't know
+        for context in contexts.iter_mut().skip(1) {
+            *context = WordContext::UnquotableScript;
+        }
+    } else if is_one_of(&name, REMOTE_SHELLS) {
+        forbid_from(&mut contexts, 1);
+    } else if is_one_of(&name, INTERPRETERS) {
+        // Code given with e.g. -c, -e or -m comes before the arguments of the script, which
+        // are safe. awk takes its program without a flag.
+        let code_flag = words.iter().skip(1).position(|w| {
+            w.starts_with("--") && ["--eval", "--print", "--command"].contains(&w.as_str())
+                || !w.starts_with("--")
+                    && w.starts_with('-')
+                    && w.contains(['c', 'e', 'E', 'r', 'p', 'm'])
+        });
+        let code = match code_flag {
+            Some(flag) => Some(flag + 2),
+            None if name.contains("awk") => None,
+            None => first_operand(),
+        };
+        match code {
+            Some(code) => {
+                for context in contexts.iter_mut().take(code + 1).skip(1) {
+                    *context = WordContext::Forbidden;
+                }
+            }
+            None => forbid_from(&mut contexts, 1),
+        }
+    }
+
+    contexts
+}
+
+/// Makes sure a command string can be substituted safely, independent of the arguments
+pub fn check_command(command_string: &str) -> Result<()> {
+    let words = shell_words::split(command_string)?;
+    if words.is_empty() {
+        return Err(anyhow::anyhow!("command is empty"));
+    }
+
+    if max_placeholder(&words[0]) > 0 {
+        return Err(anyhow::anyhow!(
+            "the program {:?} must not contain placeholders",
+            words[0]
+        ));
+    }
+
+    for (word, context) in words.iter().zip(word_contexts(&words)) {
+        match context {
+            WordContext::Forbidden if max_placeholder(word) > 0 => {
+                return Err(anyhow::anyhow!(
+                    "placeholders in {:?} can't be passed safely through {}, use sh -c 'script' with positional parameters instead",
+                    word,
+                    program_name(&words[0])
+                ));
+            }
+            WordContext::PosixScript => check_posix_script(word)?,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits the command string into words and substitutes the arguments, returning the argv
+/// of the command to run
+pub fn command_args(command_string: &str, args: &[String]) -> Result<Vec<String>> {
+    check_command(command_string)?;
+
+    let words = shell_words::split(command_string)?;
+    let contexts = word_contexts(&words);
+
+    words
+        .iter()
+        .zip(contexts)
+        .map(|(word, context)| match context {
+            // check_command made sure forbidden words don't contain placeholders
+            WordContext::Program | WordContext::Forbidden => Ok(word.clone()),
+            WordContext::Plain => substitute_plain(word, args),
+            WordContext::PosixScript => posix_script(word, args),
+            WordContext::UnquotableScript => substitute_unquotable(word, args),
+        })
+        .collect()
+}
+
+fn substitute_plain(word: &str, args: &[String]) -> Result<String> {
+    let mut result = String::new();
+    for segment in parse(word) {
+        match segment {
+            Segment::Literal(literal) => result.push_str(&literal),
+            Segment::Placeholder(index) => result.push_str(arg(args, index)?),
+        }
+    }
+    Ok(result)
+}
+
+fn substitute_unquotable(word: &str, args: &[String]) -> Result<String> {
+    let mut result = String::new();
+    for segment in parse(word) {
+        match segment {
+            Segment::Literal(literal) => result.push_str(&literal),
+            Segment::Placeholder(index) => {
+                let value = arg(args, index)?;
+                // Only allow characters that no shell attaches a meaning to
+                if let Some(c) = value.chars().find(|c| {
+                    !(c.is_alphanumeric()
+                        || matches!(
+                            c,
+                            ' ' | '.' | ',' | '_' | '-' | '+' | ':' | '/' | '\\' | '=' | '@'
+                        ))
+                }) {
+                    return Err(anyhow::anyhow!(
+                        "argument {} contains {:?}, which cannot be passed safely to this shell",
+                        index,
+                        c
+                    ));
+                }
+                result.push_str(value);
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum QuoteState {
+    Unquoted,
+    Single,
+    Double,
+    // After an unquoted # at the start of a word, up to the end of the line
+    Comment,
+}
+
+// Whether the shell starts a new word after this unquoted character, where # begins a comment
+fn ends_word(c: char) -> bool {
+    c.is_whitespace() || ";&|()<>".contains(c)
+}
+
+// Commands that evaluate their arguments or input as code again
+const EVALUATING_COMMANDS: &[&str] = &["eval", "source", "trap", "alias", "xargs"];
+
+// Words that can come before the command name
+const RESERVED_WORDS: &[&str] = &[
+    "!", "{", "}", "if", "then", "else", "elif", "fi", "do", "done", "while", "until",
+];
+
+// Stands in for a placeholder in the words of a script
+const PLACEHOLDER_MARK: char = '\0';
+
+/// Splits a POSIX shell script into simple commands and their words, with quotes removed.
+/// Fails on constructs that would interpret a substituted value a second time.
+fn script_commands(script: &str) -> Result<Vec<Vec<String>>> {
+    let unsafe_construct = |construct: &str| {
+        Err(anyhow::anyhow!(
+            "placeholders can't be used in a script with {}, pass them as positional parameters instead",
+            construct
+        ))
+    };
+
+    let mut commands = Vec::new();
+    let mut command = Vec::new();
+    let mut word: Option<String> = None;
+    let mut state = QuoteState::Unquoted;
+    let mut escaped = false;
+    let mut previous = None;
+    let mut word_start = true;
+
+    let chars = parse(script).into_iter().flat_map(|segment| match segment {
+        Segment::Literal(literal) => literal.chars().collect::<Vec<_>>(),
+        Segment::Placeholder(_) => vec![PLACEHOLDER_MARK],
+    });
+
+    for c in chars.chain(std::iter::once('\n')) {
+        if escaped {
+            escaped = false;
+            word.get_or_insert_with(String::new).push(c);
+            previous = Some(c);
+            word_start = false;
+            continue;
+        }
+
+        if state == QuoteState::Comment {
+            if c == PLACEHOLDER_MARK {
+                return Err(anyhow::anyhow!("placeholders can't be used in comments"));
+            }
+            if c != '\n' {
+                continue;
+            }
+            // The newline ends the command as usual
+            state = QuoteState::Unquoted;
+        }
+
+        if state != QuoteState::Single && (c == '`' || (c == '(' && previous == Some('$'))) {
+            return unsafe_construct("command substitution or arithmetic");
+        }
+
+        match (state, c) {
+            (QuoteState::Unquoted, '\\') | (QuoteState::Double, '\\') => escaped = true,
+            (QuoteState::Unquoted, '\'') => {
+                state = QuoteState::Single;
+                word.get_or_insert_with(String::new);
+            }
+            (QuoteState::Unquoted, '"') => {
+                state = QuoteState::Double;
+                word.get_or_insert_with(String::new);
+            }
+            (QuoteState::Single, '\'') | (QuoteState::Double, '"') => state = QuoteState::Unquoted,
+            (QuoteState::Unquoted, '<') if previous == Some('<') => {
+                return unsafe_construct("a here-document");
+            }
+            (QuoteState::Unquoted, '#') if word_start => state = QuoteState::Comment,
+            (QuoteState::Unquoted, c) if c.is_whitespace() || ";&|()".contains(c) => {
+                command.extend(word.take());
+                if c != ' ' && c != '\t' && !command.is_empty() {
+                    commands.push(std::mem::take(&mut command));
+                }
+            }
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+        previous = Some(c);
+        word_start = state == QuoteState::Unquoted && ends_word(c);
+    }
+
+    Ok(commands)
+}
+
+/// Makes sure placeholders in a POSIX shell script are only used where quoting them is enough.
+/// Command substitution, arithmetic, here-docs, `eval` and nested scripts would interpret the
+/// quoted value a second time, e.g. after it was assigned to a variable.
+pub fn check_posix_script(script: &str) -> Result<()> {
+    if max_placeholder(script) == 0 {
+        return Ok(());
+    }
+
+    for command in script_commands(script)? {
+        let words = command
+            .iter()
+            .skip_while(|w| {
+                RESERVED_WORDS.contains(&w.as_str())
+                    || w.split_once('=').is_some_and(|(name, _)| {
+                        !name.is_empty()
+                            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let Some(program) = words.first() else {
+            continue;
+        };
+
+        let name = program_name(program);
+        if EVALUATING_COMMANDS.contains(&name.as_str()) {
+            return Err(anyhow::anyhow!(
+                "placeholders can't be used in a script that runs {}",
+                name
+            ));
+        }
+
+        for (word, context) in words.iter().zip(word_contexts(&words)) {
+            let nested_script = matches!(
+                context,
+                WordContext::PosixScript | WordContext::UnquotableScript
+            );
+            if nested_script || (context != WordContext::Plain && word.contains(PLACEHOLDER_MARK)) {
+                return Err(anyhow::anyhow!(
+                    "placeholders can't be passed safely to {} in a script, pass them as positional parameters instead",
+                    name
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Substitutes the arguments into a POSIX shell script, quoting each one for the context
+/// (unquoted, inside '...' or inside "...") its placeholder appears in
+pub fn posix_script(script: &str, args: &[String]) -> Result<String> {
+    check_posix_script(script)?;
+
+    let mut result = String::new();
+    let mut state = QuoteState::Unquoted;
+    let mut word_start = true;
+
+    for segment in parse(script) {
+        match segment {
+            Segment::Literal(literal) => {
+                let mut escaped = false;
+                for c in literal.chars() {
+                    result.push(c);
+
+                    if escaped {
+                        escaped = false;
+                        word_start = false;
+                        continue;
+                    }
+
+                    state = match (state, c) {
+                        (QuoteState::Comment, '\n') => QuoteState::Unquoted,
+                        (QuoteState::Comment, _) => state,
+                        (QuoteState::Unquoted, '#') if word_start => QuoteState::Comment,
+                        (QuoteState::Unquoted, '\\') | (QuoteState::Double, '\\') => {
+                            escaped = true;
+                            state
+                        }
+                        (QuoteState::Unquoted, '\'') => QuoteState::Single,
+                        (QuoteState::Unquoted, '"') => QuoteState::Double,
+                        (QuoteState::Single, '\'') => QuoteState::Unquoted,
+                        (QuoteState::Double, '"') => QuoteState::Unquoted,
+                        _ => state,
+                    };
+                    word_start = state == QuoteState::Unquoted && ends_word(c);
+                }
+
+                if escaped {
+                    return Err(anyhow::anyhow!(
+                        "a placeholder must not directly follow a backslash"
+                    ));
+                }
+            }
+            Segment::Placeholder(index) => {
+                let value = arg(args, index)?;
+                if value.contains('\0') {
+                    return Err(anyhow::anyhow!("argument {} contains a NUL byte", index));
+                }
+
+                word_start = false;
+                match state {
+                    // A newline in the value would end the comment
+                    QuoteState::Comment => {
+                        return Err(anyhow::anyhow!("placeholders can't be used in comments"))
+                    }
+                    QuoteState::Unquoted => result.push_str(&shell_words::quote(value)),
+                    // Close the quote, add an escaped quote and open it again
+                    QuoteState::Single => result.push_str(&value.replace('\'', r"'\''")),
+                    QuoteState::Double => {
+                        for c in value.chars() {
+                            if matches!(c, '\\' | '"' | '$' | '`') {
+                                result.push('\\');
+                            }
+                            result.push(c);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTILE_INPUTS: &[&str] = &[
+        "; touch pwned",
+        "$(touch pwned)",
+        "`touch pwned`",
+        "' ; touch pwned ; '",
+        "\" ; touch pwned ; \"",
+        "\\\"; touch pwned; \\\"",
+        "a'b\"c\\d$e`f",
+        "line\ntouch pwned",
+        "&& touch pwned || touch pwned | cat",
+        "> pwned",
+        "$HOME ${HOME} $((1+1))",
+        "'\\''",
+        "%1 $2",
+        "",
+        "   spaced   ",
+    ];
+
+    fn args(value: &str) -> Vec<String> {
+        vec![value.to_string(), "second".to_string()]
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(max_placeholder("echo %1 %3 $22m %8ß"), 22);
+        assert_eq!(max_placeholder("echo $0 $ % 100%"), 0);
+        assert_eq!(max_placeholder("echo $1$2"), 2);
+    }
+
+    #[test]
+    fn plain_arguments_stay_single_words() {
+        for input in HOSTILE_INPUTS {
+            let argv = command_args("echo $1 x%1y", &args(input)).unwrap();
+            assert_eq!(
+                argv,
+                vec![
+                    "echo".to_string(),
+                    input.to_string(),
+                    format!("x{}y", input)
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn multi_digit_placeholders() {
+        let args: Vec<String> = (1..=12).map(|i| i.to_string()).collect();
+        let argv = command_args("echo $1 $12 $1a", &args).unwrap();
+        assert_eq!(argv, vec!["echo", "1", "12", "1a"]);
+    }
+
+    #[test]
+    fn program_placeholder_rejected() {
+        assert!(check_command("$1 --help").is_err());
+        assert!(command_args("/usr/bin/%1", &args("sh")).is_err());
+        assert!(check_command("").is_err());
+    }
+
+    #[test]
+    fn missing_argument() {
+        assert!(command_args("echo $2", &["a".to_string()]).is_err());
+        assert!(posix_script("echo $2", &["a".to_string()]).is_err());
+    }
+
+    #[test]
+    fn unquotable_shells_reject_metacharacters() {
+        for command in [
+            "cmd /C echo %1",
+            "cmd.exe /c \"echo %1\"",
+            "powershell -Command Write-Output $1",
+            "C:\\Windows\\System32\\WindowsPowerShell\\v1.0\\powershell.exe -c \"echo $1\"",
+            "fish -c \"echo $1\"",
+        ] {
+            for input in HOSTILE_INPUTS
+                .iter()
+                .filter(|i| i.contains(|c: char| !c.is_alphanumeric() && c != ' '))
+            {
+                assert!(
+                    command_args(command, &args(input)).is_err(),
+                    "{:?} with {:?} was accepted",
+                    command,
+                    input
+                );
+            }
+
+            assert!(command_args(command, &args("safe value-1.txt")).is_ok());
+        }
+    }
+
+    #[test]
+    fn posix_script_detection() {
+        let argv = command_args("sh -ec \"echo $1\" name $1", &args("$(x)")).unwrap();
+        assert_eq!(argv, vec!["sh", "-ec", "echo '$(x)'", "name", "$(x)"]);
+
+        let argv = command_args("/bin/bash --norc -c 'echo \"$1\"'", &args("`x`")).unwrap();
+        assert_eq!(argv, vec!["/bin/bash", "--norc", "-c", "echo \"\\`x\\`\""]);
+    }
+
+    #[test]
+    fn nested_interpretation_rejected() {
+        for command in [
+            // Command substitution and arithmetic evaluate the quoted value again
+            "sh -c \"echo \\\"$(printf %s '$1')\\\"\"",
+            "sh -c 'echo `echo $1`'",
+            "bash -c 'echo $(( $1 + 1 ))'",
+            "sh -c 'cat <<EOF\n$1\nEOF'",
+            "sh -c 'eval echo $1'",
+            "sh -c 'x=$1; bash -c \"$x\"'",
+            "sh -c 'echo $1 | xargs rm'",
+            // Wrappers and interpreters
+            "sudo systemctl restart $1",
+            "sudo -u $1 sh -c 'echo hi'",
+            "env $1",
+            "timeout 5 $1",
+            "ssh host 'echo $1'",
+            "su -c 'echo $1' user",
+            "python -c \"print('$1')\"",
+            "python3.12 -m $1",
+            "perl -e 'print \"$1\"'",
+            "awk '{ print \"$1\" }'",
+            "node --eval 'console.log(\"$1\")'",
+            "sh $1",
+            "sudo env bash -c 'echo $(cat $1)'",
+        ] {
+            assert!(
+                check_command(command).is_err(),
+                "{:?} was accepted",
+                command
+            );
+            assert!(command_args(command, &args("x")).is_err());
+        }
+
+        // Server clipboard actions only use posix_script
+        assert!(posix_script("echo \"$(printf %s $1)\"", &args("x")).is_err());
+        assert!(posix_script("printf %s '$(x)' $1", &args("x")).is_ok());
+        assert!(posix_script("x=$1; sh -c \"$x\"", &args("x")).is_err());
+        assert!(posix_script("if true; then $1; fi", &args("x")).is_err());
+        assert!(posix_script("cd /tmp && python script.py \"$1\"", &args("x")).is_ok());
+        assert!(posix_script("X=1 python -c \"print('$1')\"", &args("x")).is_err());
+        assert!(posix_script("python -c 'import sys; print(sys.argv[1])' $1", &args("x")).is_ok());
+        // A newline in the value would end the comment
+        assert!(posix_script("echo hi # $1", &args("x")).is_err());
+        assert_eq!(
+            posix_script("echo a#$1 '#'$1", &args("x y")).unwrap(),
+            "echo a#'x y' '#''x y'"
+        );
+    }
+
+    #[test]
+    fn wrapped_shells() {
+        for (command, script) in [
+            ("sudo sh -c 'echo $1'", 3),
+            ("env LANG=C bash -c 'echo $1'", 4),
+            ("timeout 5 sh -c 'echo $1'", 4),
+            ("nohup sh -c 'echo $1'", 3),
+            ("sudo env bash -c 'echo $1'", 4),
+        ] {
+            let argv = command_args(command, &args("$(touch pwned)")).unwrap();
+            assert_eq!(argv[script], "echo '$(touch pwned)'", "{:?}", command);
+        }
+
+        // Arguments of scripts are passed as they are
+        let argv = command_args("python3 script.py $1", &args("'; x")).unwrap();
+        assert_eq!(argv, vec!["python3", "script.py", "'; x"]);
+        let argv = command_args("sh -c 'echo \"$0\"' $1", &args("$(x)")).unwrap();
+        assert_eq!(argv, vec!["sh", "-c", "echo \"$0\"", "$(x)"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn posix_script_hostile_inputs() {
+        let dir = std::env::temp_dir().join(format!("mysti-substitution-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for template in [
+            "printf %s $1",
+            "printf %s '$1'",
+            "printf %s \"$1\"",
+            "printf %s x$1'y'\"$1\"",
+            "printf %s \"a 'b' $1\"",
+            "printf %s 'a \"b\" $1'",
+            "# don't panic\nprintf %s $1",
+            "printf %s $1 # it's fine\nprintf %s $1",
+        ] {
+            for input in HOSTILE_INPUTS {
+                let script = posix_script(template, &args(input)).unwrap();
+
+                let output = std::process::Command::new("sh")
+                    .current_dir(&dir)
+                    .arg("-c")
+                    .arg(&script)
+                    .output()
+                    .unwrap();
+
+                let expected = match template {
+                    "printf %s x$1'y'\"$1\"" => format!("x{}y{}", input, input),
+                    "printf %s \"a 'b' $1\"" => format!("a 'b' {}", input),
+                    "printf %s 'a \"b\" $1'" => format!("a \"b\" {}", input),
+                    "printf %s $1 # it's fine\nprintf %s $1" => format!("{}{}", input, input),
+                    _ => input.to_string(),
+                };
+
+                assert_eq!(
+                    String::from_utf8_lossy(&output.stdout),
+                    expected,
+                    "template {:?} with {:?} produced script {:?}",
+                    template,
+                    input,
+                    script
+                );
+                assert!(
+                    !dir.join("pwned").exists(),
+                    "template {:?} with {:?} executed code",
+                    template,
+                    input
+                );
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ClipboardContent;
+
+// Devices without sync groups share their clipboard within this group
+pub const DEFAULT_SYNC_GROUP: &str = "default";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    Text,
+    Image,
+}
+
+impl ContentKind {
+    pub fn of(content: &ClipboardContent) -> Self {
+        match content {
+            ClipboardContent::Text(_) => ContentKind::Text,
+            ClipboardContent::Image(_) => ContentKind::Image,
+        }
+    }
+}
+
+/// How a device takes part in clipboard sync. Fields that are not set fall back to
+/// the next matching rule, and finally to syncing everything with everyone.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncRule {
+    // Whether clipboard changes of this device are sent to others
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send: Option<bool>,
+    // Whether this device receives clipboard changes of others
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receive: Option<bool>,
+
+    // Devices only share their clipboard if they have a sync group in common
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+
+    // Which kinds of content are synced in both directions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<ContentKind>>,
+    // Images larger than this many bytes are not synced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_image_size: Option<usize>,
+}
+
+impl SyncRule {
+    pub fn is_empty(&self) -> bool {
+        *self == SyncRule::default()
+    }
+
+    /// Fills the fields that are not set in this rule from the fallback
+    pub fn or(self, fallback: &SyncRule) -> SyncRule {
+        SyncRule {
+            send: self.send.or(fallback.send),
+            receive: self.receive.or(fallback.receive),
+            groups: self.groups.or_else(|| fallback.groups.clone()),
+            content: self.content.or_else(|| fallback.content.clone()),
+            max_image_size: self.max_image_size.or(fallback.max_image_size),
+        }
+    }
+
+    pub fn sends(&self) -> bool {
+        self.send.unwrap_or(true)
+    }
+
+    pub fn receives(&self) -> bool {
+        self.receive.unwrap_or(true)
+    }
+
+    pub fn sync_groups(&self) -> Vec<String> {
+        match &self.groups {
+            Some(groups) => groups.clone(),
+            None => vec![DEFAULT_SYNC_GROUP.to_string()],
+        }
+    }
+
+    pub fn shares_with(&self, groups: &[String]) -> bool {
+        self.sync_groups().iter().any(|g| groups.contains(g))
+    }
+
+    /// Whether the content filters of this rule let the content pass
+    pub fn allows(&self, content: &ClipboardContent) -> bool {
+        if let Some(kinds) = &self.content {
+            if !kinds.contains(&ContentKind::of(content)) {
+                return false;
+            }
+        }
+
+        match (content, self.max_image_size) {
+            (ClipboardContent::Image(bytes), Some(max)) => bytes.len() <= max,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PauseDirection {
+    // Don't send clipboard changes of this device
+    Outgoing,
+    // Don't apply clipboard changes of other devices
+    Incoming,
+    #[default]
+    Both,
+}
+
+impl PauseDirection {
+    pub fn pauses_outgoing(&self) -> bool {
+        matches!(self, PauseDirection::Outgoing | PauseDirection::Both)
+    }
+
+    pub fn pauses_incoming(&self) -> bool {
+        matches!(self, PauseDirection::Incoming | PauseDirection::Both)
+    }
+}
+
+impl std::fmt::Display for PauseDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PauseDirection::Outgoing => write!(f, "outgoing"),
+            PauseDirection::Incoming => write!(f, "incoming"),
+            PauseDirection::Both => write!(f, "both"),
+        }
+    }
+}
+
+impl std::str::FromStr for PauseDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "outgoing" | "out" => Ok(PauseDirection::Outgoing),
+            "incoming" | "in" => Ok(PauseDirection::Incoming),
+            "both" => Ok(PauseDirection::Both),
+            _ => Err(format!(
+                "invalid direction {}, expected outgoing, incoming or both",
+                s
+            )),
+        }
+    }
+}
+
+/// Asks a daemon to pause clipboard sync
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PauseRequest {
+    #[serde(default)]
+    pub direction: PauseDirection,
+    // Seconds until sync resumes automatically, otherwise it stays paused until resumed
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+/// How clipboard sync of a daemon is paused
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PauseState {
+    pub direction: PauseDirection,
+    // Unix timestamp when sync resumes automatically
+    pub until: Option<u64>,
+}
+
+impl std::fmt::Display for PauseState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.direction {
+            PauseDirection::Both => write!(f, "paused")?,
+            direction => write!(f, "paused ({})", direction)?,
+        }
+
+        if let Some(until) = self.until {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            write!(f, " for {}s", until.saturating_sub(now))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let server = SyncRule {
+            send: Some(false),
+            groups: Some(vec!["office".to_string()]),
+            max_image_size: Some(4),
+            ..Default::default()
+        };
+        let device = SyncRule {
+            send: Some(true),
+            content: Some(vec![ContentKind::Image]),
+            ..Default::default()
+        };
+
+        let rule = device.or(&server);
+        assert!(rule.sends());
+        assert!(rule.receives());
+        assert!(rule.shares_with(&["office".to_string()]));
+        assert!(!rule.shares_with(&[DEFAULT_SYNC_GROUP.to_string()]));
+
+        assert!(!rule.allows(&ClipboardContent::Text("text".to_string())));
+        assert!(rule.allows(&ClipboardContent::Image(vec![0; 4])));
+        assert!(!rule.allows(&ClipboardContent::Image(vec![0; 5])));
+
+        assert!(SyncRule::default().shares_with(&SyncRule::default().sync_groups()));
+    }
+}
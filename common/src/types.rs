@@ -1,6 +1,9 @@
+use crate::action::{Action, ActionInfo, ActionResult, ActionStatus};
+use crate::sync::PauseState;
 use std::{
     fmt::{Display, Formatter},
-    time::SystemTime,
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -8,7 +11,94 @@ pub struct ConnectedClientInfo {
     pub name: String,
     pub id: usize,
     pub connected_at: std::time::SystemTime,
-    pub supported_actions: Vec<(String, usize)>,
+    pub supported_actions: Vec<ActionInfo>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Set while the device doesn't sync its clipboard
+    #[serde(default)]
+    pub paused: Option<PauseState>,
+    // Reported by the daemon after connecting
+    #[serde(default)]
+    pub os: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    // Refreshed by the daemon every few minutes
+    #[serde(default)]
+    pub metadata: Option<DeviceMetadata>,
+
+    // Offline devices are only listed with ?include_offline=true. Their id is the one of
+    // their last connection and their other fields are as they were back then.
+    #[serde(default = "default_online")]
+    pub online: bool,
+    // When an offline device disconnected
+    #[serde(default)]
+    pub last_seen: Option<SystemTime>,
+    #[serde(default)]
+    pub last_ip: Option<IpAddr>,
+    #[serde(default)]
+    pub disconnect_reason: Option<String>,
+    // Time connected in total, including the current connection
+    #[serde(default)]
+    pub uptime_secs: u64,
+    // Whether the Wake on LAN target of the server is one of its MAC addresses
+    #[serde(default)]
+    pub wakeable: bool,
+}
+
+fn default_online() -> bool {
+    true
+}
+
+/// Structured information about a device and its user's presence
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DeviceMetadata {
+    pub user: String,
+    pub hostname: String,
+    // As in std::env::consts::OS, e.g. "linux"
+    pub os: String,
+    // E.g. "Fedora Linux 39 (Workstation Edition)"
+    pub os_version: String,
+    pub arch: String,
+    pub daemon_version: String,
+    #[serde(default)]
+    pub ips: Vec<IpAddr>,
+    // Of the network interfaces, e.g. to use one as Wake on LAN target
+    #[serde(default)]
+    pub macs: Vec<String>,
+    // Seconds since the last keyboard or mouse input, if the platform reports it
+    #[serde(default)]
+    pub idle_secs: Option<u64>,
+    #[serde(default)]
+    pub battery: Option<Battery>,
+    // Unix timestamp of the last user input or clipboard change
+    #[serde(default)]
+    pub last_activity: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Battery {
+    pub percent: u8,
+    pub charging: bool,
+}
+
+impl Display for Battery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}%", self.percent)?;
+        if self.charging {
+            write!(f, " (charging)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A change of the connected devices, streamed by `GET /devices/events`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DeviceEvent {
+    Connected(ConnectedClientInfo),
+    // E.g. new actions or a paused clipboard
+    Updated(ConnectedClientInfo),
+    Disconnected { id: usize },
 }
 
 impl Display for ConnectedClientInfo {
@@ -21,6 +111,275 @@ impl Display for ConnectedClientInfo {
                 .duration_since(self.connected_at)
                 .unwrap()
                 .as_secs()
-        )
+        )?;
+        if let Some(paused) = &self.paused {
+            write!(f, ", clipboard {}", paused)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters of the server's rate limits since it started, returned by `GET /rate-limits`
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RateLimitStats {
+    // Requests answered with 429 Too Many Requests
+    pub rejected_requests: u64,
+    pub auth_failures: u64,
+    pub lockouts: u64,
+    // IP addresses that are locked out right now
+    pub locked_out: usize,
+    // Messages of devices that sent too many
+    pub dropped_clipboard: u64,
+    pub dropped_actions: u64,
+}
+
+/// How the origin of an audited event authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Credential {
+    // The token in the Authorization header
+    Token,
+    // The deprecated ?token= query parameter
+    QueryToken,
+    ClientCertificate,
+    // A connected daemon
+    Device,
+    // A scheduled action
+    Scheduler,
+}
+
+/// Who caused an audited event
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AuditActor {
+    pub credential: Credential,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+}
+
+/// What happened; clipboard contents are never logged
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Action {
+        action: String,
+        args: Vec<String>,
+        #[serde(default)]
+        id: Option<u64>,
+    },
+    ActionResult {
+        action: String,
+        #[serde(default)]
+        id: Option<u64>,
+        status: ActionStatus,
+        #[serde(default)]
+        exit_code: Option<i32>,
+    },
+    WakeOnLan {
+        mac: String,
+    },
+    Clipboard {
+        // "text" or "image"
+        kind: String,
+        bytes: usize,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    ClearClipboard,
+    PauseSync,
+    ResumeSync,
+    Schedule {
+        action: String,
+        args: Vec<String>,
+    },
+    CancelSchedule {
+        id: u64,
+    },
+}
+
+impl AuditEvent {
+    /// The type as written to the log, e.g. "wake_on_lan"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuditEvent::Action { .. } => "action",
+            AuditEvent::ActionResult { .. } => "action_result",
+            AuditEvent::WakeOnLan { .. } => "wake_on_lan",
+            AuditEvent::Clipboard { .. } => "clipboard",
+            AuditEvent::ClearClipboard => "clear_clipboard",
+            AuditEvent::PauseSync => "pause_sync",
+            AuditEvent::ResumeSync => "resume_sync",
+            AuditEvent::Schedule { .. } => "schedule",
+            AuditEvent::CancelSchedule { .. } => "cancel_schedule",
+        }
+    }
+}
+
+/// An entry of the server's audit log, as returned by `GET /audit`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AuditEntry {
+    // Unix timestamp
+    pub time: u64,
+    pub actor: AuditActor,
+    pub event: AuditEvent,
+    // The device, @group or schedule it was aimed at; none means all devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub success: bool,
+    // E.g. how many devices received it, or why it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(actor: AuditActor, event: AuditEvent) -> Self {
+        AuditEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            actor,
+            event,
+            target: None,
+            success: true,
+            outcome: None,
+        }
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn outcome(mut self, success: bool, outcome: impl Into<String>) -> Self {
+        self.success = success;
+        self.outcome = Some(outcome.into());
+        self
     }
 }
+
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.actor.credential)?;
+        if let Some(device) = &self.actor.device {
+            write!(f, " {}", device)?;
+        }
+        if let Some(ip) = &self.actor.ip {
+            write!(f, " from {}", ip)?;
+        }
+
+        match &self.event {
+            AuditEvent::Action { action, args, .. } | AuditEvent::Schedule { action, args } => {
+                write!(f, ": {} {}", self.event.kind(), action)?;
+                if !args.is_empty() {
+                    write!(f, " {}", args.join(" "))?;
+                }
+            }
+            AuditEvent::ActionResult {
+                action,
+                status,
+                exit_code,
+                ..
+            } => {
+                write!(f, ": result of {}: {:?}", action, status)?;
+                if let Some(exit_code) = exit_code {
+                    write!(f, " with exit code {}", exit_code)?;
+                }
+            }
+            AuditEvent::WakeOnLan { mac } => write!(f, ": wake on lan {}", mac)?,
+            AuditEvent::Clipboard { kind, bytes, ttl } => {
+                write!(f, ": clipboard, {} bytes of {}", bytes, kind)?;
+                if let Some(ttl) = ttl {
+                    write!(f, " for {}s", ttl)?;
+                }
+            }
+            AuditEvent::CancelSchedule { id } => write!(f, ": cancel schedule #{}", id)?,
+            event => write!(f, ": {}", event.kind())?,
+        }
+
+        if let Some(target) = &self.target {
+            write!(f, " -> {}", target)?;
+        }
+        match (&self.outcome, self.success) {
+            (Some(outcome), true) => write!(f, " ({})", outcome),
+            (Some(outcome), false) => write!(f, " (failed: {})", outcome),
+            (None, true) => Ok(()),
+            (None, false) => write!(f, " (failed)"),
+        }
+    }
+}
+
+/// Response of the server after creating an action
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ActionCreated {
+    pub id: u64,
+}
+
+/// An action result reported by a client, as returned by `GET /actions/{id}`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DeviceActionResult {
+    pub device_id: usize,
+    pub device_name: String,
+    pub received_at: std::time::SystemTime,
+    pub result: ActionResult,
+}
+
+impl Display for DeviceActionResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.device_name, self.result)
+    }
+}
+
+/// Request body of `POST /schedules`. Exactly one of `cron`, `at` and `delay_secs` must be set.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ScheduleRequest {
+    pub action: Action,
+    // Name of the device to run the action on, all devices if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+
+    // Cron expression in local server time, e.g. "0 23 * * Mon-Fri"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    // Unix timestamp in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ScheduledAction {
+    pub id: u64,
+    pub action: Action,
+    pub target: Option<String>,
+    // Set for repeating schedules
+    pub cron: Option<String>,
+    // Unix timestamp in seconds
+    pub next_run: u64,
+}
+
+/// The outcome of an action on one member of a group
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GroupMemberResult {
+    pub device_id: usize,
+    pub device_name: String,
+    // Not set if the device didn't report back in time
+    pub result: Option<ActionResult>,
+}
+
+impl Display for GroupMemberResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.result {
+            Some(result) => write!(f, "{}: {}", self.device_name, result),
+            None => write!(f, "{}: no result", self.device_name),
+        }
+    }
+}
+
+/// Response of `POST /groups/{name}/actions`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GroupDispatchResult {
+    pub id: u64,
+    pub devices: Vec<GroupMemberResult>,
+}
@@ -38,6 +38,8 @@ impl Scheme {
     }
 }
 
+/// The URL of an endpoint on the server. The token is not part of it, it is sent
+/// in an `Authorization` header, see [`authorization_header`].
 pub fn generate_request_url(cfg: &ClientConfig, path: &str, scheme: Scheme) -> Result<Url> {
     let mut server_url = Url::parse(&cfg.server_host).context("Failed to parse server URL")?;
 
@@ -51,9 +53,10 @@ pub fn generate_request_url(cfg: &ClientConfig, path: &str, scheme: Scheme) -> R
         )
         .map_err(|_| anyhow::anyhow!("Failed to set scheme"))?;
 
-    server_url
-        .query_pairs_mut()
-        .append_pair("token", &cfg.token);
-
     Ok(server_url)
 }
+
+/// Value of the `Authorization` header for requests to the server
+pub fn authorization_header(cfg: &ClientConfig) -> String {
+    format!("Bearer {}", cfg.token)
+}
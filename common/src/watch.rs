@@ -0,0 +1,61 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Notices changes of a file, e.g. a configuration file, by comparing its modification time
+pub struct FileWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl FileWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let modified = modified(&path);
+        Self { path, modified }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file was modified since the last call. A missing file doesn't count as
+    /// a change, as editors often remove the file for a moment while saving it.
+    pub fn changed(&mut self) -> bool {
+        let Some(modified) = modified(&self.path) else {
+            return false;
+        };
+
+        if self.modified == Some(modified) {
+            return false;
+        }
+        self.modified = Some(modified);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_changes() {
+        let path = std::env::temp_dir().join(format!("mysti-watch-{}", std::process::id()));
+        std::fs::write(&path, "one").unwrap();
+
+        let mut watcher = FileWatcher::new(&path);
+        assert!(!watcher.changed());
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10))
+            .unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!watcher.changed());
+    }
+}
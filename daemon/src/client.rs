@@ -1,53 +1,397 @@
-use crate::clipboard::{self, Watcher};
+use crate::clipboard::{self, ClipboardChange, Watcher};
+use crate::connection::Connection;
+use crate::history::History;
+use crate::ipc::IpcCall;
+use crate::metadata;
+use crate::outbox::Outbox;
+use crate::reconnect::{self, Endpoint};
+use crate::runner::ActionRunner;
+use crate::sensitive::SensitiveDetector;
+use ::url::Url;
 use anyhow::Result;
-use common::action::ActionDefinition;
-use common::name::client_name;
+use common::action::{ActionDefinition, ActionResult, ActionStatus};
+use common::client_config::{ReconnectConfig, SensitivePolicy};
+use common::ipc::{
+    ConnectionState, DaemonStatus, EntryOrigin, IpcRequest, IpcResponse, RecentEvent,
+};
+use common::name::{client_name, os_name};
+use common::sync::{PauseDirection, PauseRequest, PauseState};
+use common::types::DeviceMetadata;
 use common::url;
-use common::{client_config::ClientConfig, ActionMessage, ClipboardContent};
-use futures_util::SinkExt;
-use futures_util::StreamExt;
+use common::watch::FileWatcher;
+use common::{client_config::ClientConfig, ActionMessage, Capabilities, ClipboardContent};
 use image::ImageOutputFormat;
-use std::convert::TryInto;
-use std::ops::Add;
-use std::pin::pin;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{thread, time::Duration};
 use tokio::select;
 use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::sync::Notify;
 use tokio::time::Instant;
-use tokio_tungstenite::connect_async;
 
 enum LocalEvent {
-    ClipboardEvent(ClipboardContent),
+    ClipboardEvent(ClipboardChange),
 }
 
+#[allow(clippy::enum_variant_names)]
 enum Event {
     LocalEvent(LocalEvent),
     RemoteEvent(ActionMessage),
     OutgoingEvent(ActionMessage),
+    IpcEvent(IpcCall),
+    // The configuration file changed or the daemon received SIGHUP
+    ReloadConfig,
+    Metadata(DeviceMetadata),
 }
 
-impl From<ClipboardContent> for LocalEvent {
-    fn from(content: ClipboardContent) -> Self {
-        Self::ClipboardEvent(content)
+impl From<ClipboardChange> for LocalEvent {
+    fn from(change: ClipboardChange) -> Self {
+        Self::ClipboardEvent(change)
     }
 }
 
 pub struct MystiClient {
     config: ClientConfig,
+    // Where the configuration was loaded from, it is reloaded when it changes
+    config_file: Option<String>,
+    // The connection task reconnects whenever this changes
+    server: watch::Sender<Endpoint>,
+    // Read by the connection task before every retry
+    reconnect: watch::Sender<ReconnectConfig>,
     image_format: ImageOutputFormat,
+    runner: ActionRunner,
+    action_results: Option<Receiver<ActionResult>>,
+    sensitive: SensitiveDetector,
+    // When to clear the clipboard, if it still contains the content
+    clipboard_expiry: Option<(Instant, ClipboardContent)>,
+    history: History,
+
+    // While paused, the clipboard is not sent and/or not received, until the optional deadline
+    pause: Option<(PauseState, Option<Instant>)>,
+    // Messages for the server, kept while disconnected
+    outbox: Outbox,
+    connection: watch::Receiver<ConnectionState>,
+    connection_updates: Option<watch::Sender<ConnectionState>>,
+    recent_events: VecDeque<RecentEvent>,
+    // Unix timestamp of the last clipboard change on this device
+    last_activity: Option<u64>,
+}
+
+// How many events are kept for `mysti status`
+const MAX_RECENT_EVENTS: usize = 50;
+
+// How often the configuration file is checked for changes
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+// How long sending queued messages may take when exiting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+// How often device metadata like the idle time is sent to the server
+const METADATA_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn describe(content: &ClipboardContent) -> String {
+    match content {
+        ClipboardContent::Text(text) => format!("text ({} bytes)", text.len()),
+        ClipboardContent::Image(bytes) => format!("image ({} KB)", bytes.len() / 1024),
+    }
+}
+
+fn capabilities(config: &ClientConfig) -> Capabilities {
+    Capabilities {
+        supported_actions: config
+            .actions
+            .iter()
+            .filter(|a| a.is_available())
+            .map(|a| a.info())
+            .collect(),
+        tags: config.tags.clone(),
+        sync: config.sync.clone(),
+        os: Some(os_name()),
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+    }
+}
+
+fn server_url(config: &ClientConfig) -> Result<Url> {
+    let capabilities = capabilities(config);
+
+    let mut server_url = url::generate_request_url(config, "/ws", url::Scheme::WebSocket)?;
+    server_url
+        .query_pairs_mut()
+        .append_pair(
+            "supported_actions",
+            &serde_json::to_string(&capabilities.supported_actions)?,
+        )
+        .append_pair("device_name", &client_name())
+        .append_pair("tags", &capabilities.tags.join(","));
+    if !capabilities.sync.is_empty() {
+        server_url
+            .query_pairs_mut()
+            .append_pair("sync", &serde_json::to_string(&capabilities.sync)?);
+    }
+
+    Ok(server_url)
+}
+
+fn endpoint(config: &ClientConfig) -> Result<Endpoint> {
+    Ok(Endpoint {
+        url: server_url(config)?,
+        authorization: url::authorization_header(config),
+    })
+}
+
+// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("Failed to listen for SIGTERM");
+
+        select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Reports changes of the file or SIGHUP as reload events
+async fn watch_config_file(path: String, events: Sender<Event>) {
+    let mut watcher = FileWatcher::new(path);
+    let mut interval = tokio::time::interval(CONFIG_CHECK_INTERVAL);
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to listen for SIGHUP");
+
+    loop {
+        #[cfg(unix)]
+        select! {
+            _ = interval.tick() => {
+                if !watcher.changed() {
+                    continue;
+                }
+            }
+            _ = hangup.recv() => log::info!("Received SIGHUP"),
+        }
+
+        #[cfg(not(unix))]
+        {
+            interval.tick().await;
+            if !watcher.changed() {
+                continue;
+            }
+        }
+
+        if events.send(Event::ReloadConfig).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Collects device metadata after connecting and then periodically
+async fn collect_metadata(mut connection: watch::Receiver<ConnectionState>, events: Sender<Event>) {
+    let mut interval = tokio::time::interval(METADATA_INTERVAL);
+
+    loop {
+        select! {
+            _ = interval.tick() => (),
+            Ok(()) = connection.changed() => (),
+        }
+
+        // The server would not get it anyways
+        if !matches!(*connection.borrow(), ConnectionState::Connected { .. }) {
+            continue;
+        }
+
+        let metadata = match tokio::task::spawn_blocking(metadata::collect).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                log::warn!("Failed to collect device metadata: {}", e);
+                continue;
+            }
+        };
+        if events.send(Event::Metadata(metadata)).await.is_err() {
+            return;
+        }
+    }
 }
 
 impl MystiClient {
-    pub fn new(config: ClientConfig, image_format: ImageOutputFormat) -> Self {
-        Self {
+    pub fn new(
+        config: ClientConfig,
+        config_file: Option<String>,
+        image_format: ImageOutputFormat,
+    ) -> Result<Self> {
+        let (results, action_results) = channel::<ActionResult>(10);
+        let (connection_updates, connection) = watch::channel(ConnectionState::Connecting {
+            attempts: 0,
+            last_error: None,
+            failure: None,
+            retry_at: None,
+        });
+
+        let (server, _) = watch::channel(endpoint(&config)?);
+        let (reconnect, _) = watch::channel(config.reconnect.clone());
+
+        Ok(Self {
+            config_file,
+            server,
+            reconnect,
+            pause: None,
+            outbox: Outbox::new(config.outbox_size),
+            connection,
+            connection_updates: Some(connection_updates),
+            recent_events: VecDeque::new(),
+            last_activity: None,
+            runner: ActionRunner::new(results, config.confirmation.clone()),
+            action_results: Some(action_results),
+            sensitive: SensitiveDetector::new(config.sensitive.clone())?,
+            clipboard_expiry: None,
+            history: History::new(config.history.clone()).unwrap_or_else(|e| {
+                log::error!("Keeping the clipboard history in memory only: {:#}", e);
+                // The file is left alone, it may become readable again, e.g. with the passphrase
+                History::in_memory(config.history.clone())
+            }),
             config,
             image_format,
+        })
+    }
+
+    fn record(&mut self, description: String) {
+        if self.recent_events.len() >= MAX_RECENT_EVENTS {
+            self.recent_events.pop_front();
+        }
+        self.recent_events.push_back(RecentEvent {
+            time: now(),
+            description,
+        });
+    }
+
+    /// Applies a new configuration, keeping the current one if it is invalid.
+    /// History settings and concealed hints take effect after a restart.
+    fn apply_config(&mut self, config: ClientConfig) -> Result<()> {
+        let sensitive = SensitiveDetector::new(config.sensitive.clone())?;
+        let endpoint = endpoint(&config)?;
+
+        let reconnect =
+            config.server_host != self.config.server_host || config.token != self.config.token;
+        let capabilities_changed = capabilities(&config) != capabilities(&self.config);
+
+        self.sensitive = sensitive;
+        self.runner.set_confirmation(config.confirmation.clone());
+        self.outbox.set_max_size(config.outbox_size);
+        self.reconnect.send_replace(config.reconnect.clone());
+        self.config = config;
+
+        if reconnect {
+            log::info!("Server changed, reconnecting");
+            self.server.send_replace(endpoint);
+        } else {
+            // Used for the next connection, but there is no need to reconnect now
+            self.server.send_if_modified(|current| {
+                *current = endpoint;
+                false
+            });
+
+            if capabilities_changed {
+                self.send_capabilities();
+            }
         }
+
+        self.record("Reloaded configuration".to_string());
+        Ok(())
     }
 
-    async fn on_local_clipboard_change(&self, content: ClipboardContent, channel: Sender<Event>) {
-        let am = ActionMessage::Clipboard(content);
+    fn send_capabilities(&self) {
+        self.outbox
+            .push(ActionMessage::Capabilities(capabilities(&self.config)));
+    }
+
+    fn reload_config(&mut self) -> Result<()> {
+        let result = match &self.config_file {
+            Some(path) => common::client_config::parse_file(path),
+            None => common::client_config::find_parse_config(),
+        }
+        .and_then(|config| self.apply_config(config));
+
+        if let Err(e) = &result {
+            log::error!(
+                "Failed to reload configuration, keeping the previous one: {:#}",
+                e
+            );
+            self.record(format!("Failed to reload configuration: {:#}", e));
+        }
+        result
+    }
+
+    fn expire_clipboard_after(&mut self, content: ClipboardContent, ttl: Duration) {
+        // A TTL too long to represent never expires
+        self.clipboard_expiry = Instant::now()
+            .checked_add(ttl)
+            .map(|expiry| (expiry, content));
+    }
+
+    fn expire_clipboard(&mut self) {
+        let Some((_, content)) = self.clipboard_expiry.take() else {
+            return;
+        };
+
+        match clipboard::clear_if_unchanged(&content) {
+            Ok(()) => log::info!("Clipboard content expired"),
+            Err(e) => log::warn!("Failed to expire clipboard content: {}", e),
+        }
+    }
+
+    async fn on_local_clipboard_change(&mut self, change: ClipboardChange, channel: Sender<Event>) {
+        // Expiring content we just set must not be sent back, that would remove its TTL.
+        // Content that replaces it doesn't expire.
+        match &self.clipboard_expiry {
+            Some((_, content)) if *content == change.content => return,
+            Some(_) => self.clipboard_expiry = None,
+            None => (),
+        }
+
+        self.last_activity = Some(now());
+
+        // An emptied clipboard shouldn't empty the clipboard of all other devices
+        if change.content == ClipboardContent::Text(String::new()) {
+            return;
+        }
+
+        if let Some(reason) = self.sensitive.check(&change.content, change.concealed) {
+            log::info!("Not syncing clipboard content: {}", reason);
+            self.record(format!("Skipped sensitive clipboard content: {}", reason));
+
+            if self.sensitive.config().policy == SensitivePolicy::Expire {
+                let expire_after = Duration::from_secs(self.sensitive.config().expire_after);
+                self.expire_clipboard_after(change.content, expire_after);
+            }
+            return;
+        }
+
+        self.history.add(&change.content, EntryOrigin::Local);
+
+        if self.is_paused(PauseDirection::pauses_outgoing) {
+            log::info!("Not syncing clipboard content, sync is paused");
+            return;
+        }
+        self.record(format!("Sent clipboard {}", describe(&change.content)));
+
+        let am = ActionMessage::Clipboard(change.content);
 
         channel
             .send(Event::OutgoingEvent(am))
@@ -55,20 +399,44 @@ impl MystiClient {
             .expect("Failed to send clipboard content");
     }
 
-    async fn process_local_event(&self, event: LocalEvent, channel: Sender<Event>) {
+    async fn process_local_event(&mut self, event: LocalEvent, channel: Sender<Event>) {
         match event {
-            LocalEvent::ClipboardEvent(content) => {
-                self.on_local_clipboard_change(content, channel).await;
+            LocalEvent::ClipboardEvent(change) => {
+                self.on_local_clipboard_change(change, channel).await;
             }
         }
     }
 
     async fn process_action_message(&mut self, event: &ActionMessage) -> Result<()> {
-        log::info!("Received action message: {:?}", event);
+        log::info!("Received {} message", event.kind());
+
+        if event.clipboard_content().is_some() && self.is_paused(PauseDirection::pauses_incoming) {
+            log::info!("Ignoring clipboard content, sync is paused");
+            return Ok(());
+        }
 
         match &event {
             ActionMessage::Clipboard(content) => {
-                clipboard::set_clipboard(&content)?;
+                self.record(format!("Received clipboard {}", describe(content)));
+                self.clipboard_expiry = None;
+                self.history.add(content, EntryOrigin::Remote);
+                clipboard::set_clipboard(content)?;
+            }
+            ActionMessage::ExpiringClipboard { content, ttl } => {
+                self.record(format!(
+                    "Received clipboard {}, expires after {}s",
+                    describe(content),
+                    ttl
+                ));
+                clipboard::set_clipboard(content)?;
+                self.expire_clipboard_after(content.clone(), Duration::from_secs(*ttl));
+            }
+            ActionMessage::PauseSync(request) => self.pause(request.clone()),
+            ActionMessage::ResumeSync => self.resume(),
+            ActionMessage::ClearClipboard => {
+                self.record("Cleared clipboard".to_string());
+                self.clipboard_expiry = None;
+                clipboard::clear_clipboard()?;
             }
             ActionMessage::Action(action) => {
                 let action_definition =
@@ -76,173 +444,164 @@ impl MystiClient {
 
                 match action_definition {
                     Some(action_definition) => {
-                        action_definition.run(&action.args)?;
+                        self.record(format!("Running action {}", action));
+                        self.runner.run(action_definition, action.clone()).await;
                     }
                     None => {
                         log::warn!("Action {} not found", action.action);
+                        self.record(format!("Refused unknown action {}", action.action));
+                        self.runner
+                            .report(ActionResult::new(
+                                action,
+                                ActionStatus::Refused,
+                                Some("action is not defined on this device".to_string()),
+                            ))
+                            .await;
                     }
                 }
             }
+            ActionMessage::ActionResult(_)
+            | ActionMessage::PauseState(_)
+            | ActionMessage::Capabilities(_)
+            | ActionMessage::Metadata(_) => (),
         }
 
         Ok(())
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        // copy the sender, creating a new one
-        let (clipboard_events, mut clipboard_receiver) = channel::<LocalEvent>(10);
+    fn is_paused(&self, direction: fn(&PauseDirection) -> bool) -> bool {
+        self.pause
+            .as_ref()
+            .is_some_and(|(state, _)| direction(&state.direction))
+    }
 
-        // Run in a separate thread
-        let mut w = Watcher::new(self.image_format.clone(), clipboard_events.clone());
-        thread::spawn(move || {
-            w.run().expect("Failed to run watcher");
-        });
+    fn pause_state(&self) -> Option<PauseState> {
+        self.pause.as_ref().map(|(state, _)| state.clone())
+    }
 
-        // Parse and set the correct URL
-        let mut server_url =
-            url::generate_request_url(&self.config, "/ws", url::Scheme::WebSocket)?;
+    // Tells the server, so it can show the state to other devices
+    fn report_pause_state(&self) {
+        self.outbox
+            .push(ActionMessage::PauseState(self.pause_state()));
+    }
 
-        server_url
-            .query_pairs_mut()
-            .append_pair(
-                "supported_actions",
-                &self
+    fn pause(&mut self, request: PauseRequest) {
+        let state = PauseState {
+            direction: request.direction,
+            until: request.duration.map(|duration| now() + duration),
+        };
+        let resume_at = request
+            .duration
+            .map(|duration| Instant::now() + Duration::from_secs(duration));
+
+        log::info!("Clipboard sync {}", state);
+        self.record(format!("Clipboard sync {}", state));
+        self.pause = Some((state, resume_at));
+        self.report_pause_state();
+    }
+
+    fn resume(&mut self) {
+        if self.pause.take().is_none() {
+            return;
+        }
+
+        log::info!("Resumed clipboard sync");
+        self.record("Resumed clipboard sync".to_string());
+        self.report_pause_state();
+    }
+
+    fn process_ipc_request(&mut self, request: IpcRequest) -> IpcResponse {
+        match request {
+            IpcRequest::Status => IpcResponse::Status(DaemonStatus {
+                device_name: client_name(),
+                server_host: self.config.server_host.clone(),
+                connection: self.connection.borrow().clone(),
+                paused: self.pause_state(),
+                actions: self
                     .config
                     .actions
                     .iter()
                     .filter(|a| a.is_available())
-                    .map(|a| format!("{}:{}", a.name, a.required_args()))
-                    .collect::<Vec<String>>()
-                    .join(","),
-            )
-            .append_pair("device_name", &client_name());
-
-        let (remote_event, mut remote_receiver) = channel::<ActionMessage>(10);
-        let (outgoing_events, mut outgoing_receiver) = channel::<ActionMessage>(10);
+                    .map(|a| a.name.clone())
+                    .collect(),
+                history_entries: self.history.list().len(),
+            }),
+            IpcRequest::Pause(request) => {
+                self.pause(request);
+                IpcResponse::Ok
+            }
+            IpcRequest::Resume => {
+                self.resume();
+                IpcResponse::Ok
+            }
+            IpcRequest::PushClipboard { text } => {
+                let content = ClipboardContent::Text(text);
+                if let Err(e) = clipboard::set_clipboard(&content) {
+                    return IpcResponse::Error(format!("{:#}", e));
+                }
 
-        let moved_server_url = server_url.clone();
-        tokio::spawn(async move {
-            loop {
-                log::info!("Connecting to {}", moved_server_url);
-
-                // Attempt to connect to server and retry if it fails
-                let socket = {
-                    let mut fail_count = 0;
-                    loop {
-                        match connect_async(moved_server_url.clone()).await {
-                            Ok((socket, _)) => break socket,
-                            Err(e) => {
-                                if fail_count % 12 == 0 {
-                                    log::warn!("Failed to connect to server: {}", e);
-                                }
-                                fail_count += 1;
-
-                                tokio::time::sleep(Duration::from_secs(5)).await;
-                            }
-                        };
-                    }
+                // The clipboard watcher picks this up and syncs it like a local copy, so pausing,
+                // the sensitive content detection and the sync rules apply
+                self.record(format!("Pushed clipboard {}", describe(&content)));
+                IpcResponse::Ok
+            }
+            IpcRequest::RecentEvents => {
+                IpcResponse::Events(self.recent_events.iter().cloned().collect())
+            }
+            IpcRequest::ReloadConfig => match self.reload_config() {
+                Ok(()) => IpcResponse::Ok,
+                Err(e) => IpcResponse::Error(format!("{:#}", e)),
+            },
+            IpcRequest::History => IpcResponse::History(self.history.list()),
+            IpcRequest::Restore { id } => {
+                let Some(content) = self.history.get(id) else {
+                    return IpcResponse::Error(format!("no history entry {}", id));
                 };
 
-                println!("Connected to server");
-
-                let (mut socket_sender, socket_receiver) = socket.split();
-                // Peekable is needed to check if there is a pong message without consuming it
-                let mut socket_receiver = pin!(socket_receiver.peekable());
-
-                // We start pinging around 5 seconds after connecting, as
-                // otherwise we might have a race between the initial ping and
-                // the initial data sent from the server.
-                // While we do handle that race correctly in the ping/pong handler,
-                // the delay helps make it less likely.
-                let mut ping_interval = tokio::time::interval_at(
-                    Instant::now().add(Duration::from_secs(5)),
-                    Duration::from_secs(60),
-                );
-
-                loop {
-                    // Read something from the socket OR write something to the socket when we get an outgoing event
-                    select! {
-                        // Simple example with correct sytax: receive from outgoing_receiver and socket_receiver
-                        event = outgoing_receiver.recv() => {
-                            println!("Received outgoing event: {:?}", event);
-                            let Some(event) = event else { break };
-
-                            let message : tokio_tungstenite::tungstenite::Message = match event.try_into() {
-                                Ok(message) => message,
-                                Err(err) => {
-                                    log::warn!("Failed to convert event to message: {}", err);
-                                    continue;
-                                }
-                            };
-
-                            if let Err(e) = socket_sender.send(message).await {
-                                log::warn!("Failed to send message to server: {}", e);
-                                break;
-                            }
-                        }
-                        event = socket_receiver.next() => {
-                            let Some(event) = event else { break };
-                            let Ok(event) = event else {
-                                log::warn!("Failed to receive remote event: {:?}", event);
-                                break;
-                            };
-
-                            match event {
-                                tokio_tungstenite::tungstenite::Message::Close(_) => {
-                                    log::warn!("Server sent close");
-                                    break;
-                                }
-                                tokio_tungstenite::tungstenite::Message::Pong(_) => {
-                                    continue;
-                                }
-                                _ => (),
-                            };
-
-                            println!("Received remote event: {:?}", event);
-
-                            let action_message : ActionMessage = match event.try_into() {
-                                Ok(event) => event,
-                                Err(err) => {
-                                    log::warn!("Failed to convert message to event: {}", err);
-                                    continue;
-                                }
-                            };
-
-                            remote_event.send(action_message).await.expect("Failed to send remote event");
-                        }
-                        _ = ping_interval.tick() => {
-                            if let Err(e) = tokio::time::timeout(Duration::from_secs(5), socket_sender.send(tokio_tungstenite::tungstenite::Message::Ping(vec![1,2,3,4]))).await {
-                                log::warn!("Failed to send ping: {}", e);
-                                break;
-                            }
-
-                            // We cannot be certain that the next message is a pong,
-                            // as the server might send a message in between
-                           let Ok(Some(Ok(event))) = tokio::time::timeout(Duration::from_secs(5), socket_receiver.as_mut().peek()).await else {
-                                log::warn!("Failed to receive pong");
-                                break;
-                            };
-                            if let tokio_tungstenite::tungstenite::Message::Pong(_) = event {
-                                // Now actually consume the pong
-                                match socket_receiver.next().await {
-                                    Some(Ok(tokio_tungstenite::tungstenite::Message::Pong(_))) => (),
-                                    // If we somehow get something else, it must be a bug
-                                    val => panic!("Expected buffered pong message, but got {:?}", val),
-                                };
-                                continue;
-                            } else {
-                                log::warn!("Received non-pong message");
-                                // We continue with that message
-                            }
-                        }
-                    }
+                // The clipboard watcher picks this up and syncs it like a local copy
+                match clipboard::set_clipboard(content) {
+                    Ok(()) => IpcResponse::Ok,
+                    Err(e) => IpcResponse::Error(format!("{:#}", e)),
                 }
-
-                println!("Disconnected from server - reconnecting in 5 seconds");
-                tokio::time::sleep(Duration::from_secs(5)).await;
             }
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        // copy the sender, creating a new one
+        let (clipboard_events, mut clipboard_receiver) = channel::<LocalEvent>(10);
+
+        // Run in a separate thread
+        let mut w = Watcher::new(
+            self.image_format.clone(),
+            clipboard_events.clone(),
+            self.config.sensitive.respect_hints,
+        );
+        thread::spawn(move || {
+            w.run().expect("Failed to run watcher");
         });
 
+        let (remote_event, mut remote_receiver) = channel::<ActionMessage>(10);
+        let (shutdown, mut shutting_down) = watch::channel(false);
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            shutdown.send_replace(true);
+        });
+        let connection = Connection {
+            server: self.server.subscribe(),
+            reconnect: self.reconnect.subscribe(),
+            updates: self
+                .connection_updates
+                .take()
+                .expect("MystiClient::run must only be called once"),
+            outbox: self.outbox.clone(),
+            remote_events: remote_event,
+            network_hints: Arc::new(Notify::new()),
+            shutdown: shutting_down.clone(),
+        };
+        tokio::spawn(reconnect::watch_network(connection.network_hints.clone()));
+        let connection_task = tokio::spawn(connection.run());
+
         let (all_events, mut all_receiver) = channel::<Event>(10);
 
         // Forward local events to the main channel
@@ -275,8 +634,79 @@ impl MystiClient {
             }
         });
 
+        // Forward action results to the server
+        let mut action_results = self
+            .action_results
+            .take()
+            .expect("MystiClient::run must only be called once");
+        let result_all_events = all_events.clone();
+        tokio::spawn(async move {
+            while let Some(result) = action_results.recv().await {
+                result_all_events
+                    .send(Event::OutgoingEvent(ActionMessage::ActionResult(result)))
+                    .await
+                    .expect("Failed to send action result");
+            }
+        });
+
+        // Forward requests of local processes like the CLI
+        let (ipc_calls, mut ipc_receiver) = channel::<IpcCall>(10);
+        tokio::spawn(async move {
+            let result = match common::ipc::socket_path() {
+                Ok(path) => crate::ipc::serve(&path, ipc_calls).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log::warn!("Local requests are not available: {:#}", e);
+            }
+        });
+        if let Some(path) = self.config_file.clone() {
+            tokio::spawn(watch_config_file(path, all_events.clone()));
+        }
+        tokio::spawn(collect_metadata(
+            self.connection.clone(),
+            all_events.clone(),
+        ));
+
+        let ipc_all_events = all_events.clone();
+        tokio::spawn(async move {
+            while let Some(call) = ipc_receiver.recv().await {
+                ipc_all_events
+                    .send(Event::IpcEvent(call))
+                    .await
+                    .expect("Failed to send local request");
+            }
+        });
+
         loop {
-            let event = all_receiver.recv().await.expect("Failed to receive event");
+            let expiry = self
+                .clipboard_expiry
+                .as_ref()
+                .map(|(deadline, _)| *deadline);
+            let resume_at = self.pause.as_ref().and_then(|(_, resume_at)| *resume_at);
+            let event = select! {
+                event = all_receiver.recv() => event.expect("Failed to receive event"),
+                _ = wait_until(expiry) => {
+                    self.expire_clipboard();
+                    continue;
+                }
+                _ = wait_until(resume_at) => {
+                    self.resume();
+                    continue;
+                }
+                Ok(()) = self.connection.changed() => {
+                    // The server forgets the state when the connection is closed
+                    let connected = matches!(*self.connection.borrow(), ConnectionState::Connected { .. });
+                    if connected {
+                        self.send_capabilities();
+                        if self.pause.is_some() {
+                            self.report_pause_state();
+                        }
+                    }
+                    continue;
+                }
+                Ok(()) = shutting_down.changed() => break,
+            };
 
             match event {
                 Event::LocalEvent(event) => {
@@ -287,16 +717,171 @@ impl MystiClient {
                 Event::RemoteEvent(event) => match self.process_action_message(&event).await {
                     Ok(_) => (),
                     Err(err) => {
-                        log::warn!("Error processing action message {:?}: {}", event, err);
+                        log::warn!("Error processing {} message: {}", event.kind(), err);
                     }
                 },
                 Event::OutgoingEvent(event) => {
-                    outgoing_events
-                        .send(event)
-                        .await
-                        .expect("Failed to send outgoing event");
+                    if let ActionMessage::ActionResult(result) = &event {
+                        self.record(format!("Action {}: {}", result.action, result));
+                    }
+                    self.outbox.push(event);
+                }
+                Event::IpcEvent(call) => {
+                    let response = self.process_ipc_request(call.request);
+                    let _ = call.responder.send(response);
+                }
+                Event::ReloadConfig => {
+                    // Errors are logged and recorded
+                    let _ = self.reload_config();
+                }
+                Event::Metadata(mut metadata) => {
+                    let last_input = metadata.idle_secs.map(|idle| now().saturating_sub(idle));
+                    metadata.last_activity = last_input.max(self.last_activity);
+
+                    self.outbox
+                        .push(ActionMessage::Metadata(Box::new(metadata)));
                 }
             }
         }
+
+        log::info!("Shutting down");
+        // Sends what is still queued and closes the connection
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, connection_task)
+            .await
+            .is_err()
+        {
+            log::warn!("Could not close the connection in time");
+        }
+        // Not the content, it may be sensitive
+        let unsent = self.outbox.pending_kinds();
+        if !unsent.is_empty() {
+            log::warn!(
+                "{} message(s) not sent to the server before exiting: {}",
+                unsent.len(),
+                unsent.join(", ")
+            );
+        }
+
+        #[cfg(unix)]
+        if let Ok(path) = common::ipc::socket_path() {
+            let _ = std::fs::remove_file(path);
+        }
+
+        Ok(())
+    }
+}
+
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ipc_pause_and_status() {
+        let config = common::client_config::parse(
+            r#"
+            server_host = "http://localhost:1234"
+            token = "token"
+            "#,
+        )
+        .unwrap();
+        let mut client = MystiClient::new(config, None, ImageOutputFormat::Png).unwrap();
+
+        let path = std::env::temp_dir().join(format!("mysti-client-{}.sock", std::process::id()));
+        let (calls, mut receiver) = channel(1);
+        let server_path = path.clone();
+        tokio::spawn(async move { crate::ipc::serve(&server_path, calls).await });
+
+        tokio::spawn(async move {
+            while let Some(call) = receiver.recv().await {
+                let response = client.process_ipc_request(call.request);
+                let _ = call.responder.send(response);
+            }
+        });
+
+        let request = |request: IpcRequest| {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || {
+                // The server might not be listening yet
+                for _ in 0..50 {
+                    if let Ok(response) = common::ipc::send_to(&path, &request) {
+                        return response;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                panic!("daemon did not answer");
+            })
+        };
+
+        assert_eq!(
+            request(IpcRequest::Pause(PauseRequest {
+                direction: PauseDirection::Outgoing,
+                duration: Some(60),
+            }))
+            .await
+            .unwrap(),
+            IpcResponse::Ok
+        );
+
+        let IpcResponse::Status(status) = request(IpcRequest::Status).await.unwrap() else {
+            panic!("expected status");
+        };
+        let paused = status.paused.unwrap();
+        assert_eq!(paused.direction, PauseDirection::Outgoing);
+        assert!(paused.until.is_some());
+        assert_eq!(status.server_host, "http://localhost:1234");
+        assert!(matches!(
+            status.connection,
+            ConnectionState::Connecting { .. }
+        ));
+
+        let IpcResponse::Events(events) = request(IpcRequest::RecentEvents).await.unwrap() else {
+            panic!("expected events");
+        };
+        assert_eq!(events.len(), 1);
+        assert!(events[0]
+            .description
+            .starts_with("Clipboard sync paused (outgoing)"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_last_good_config() {
+        let path = std::env::temp_dir().join(format!("mysti-reload-{}.toml", std::process::id()));
+        let path_str = path.to_str().unwrap().to_string();
+        let base = r#"
+            server_host = "http://localhost:1234"
+            token = "token"
+        "#;
+
+        std::fs::write(&path, base).unwrap();
+        let config = common::client_config::parse_file(&path_str).unwrap();
+        let mut client = MystiClient::new(config, Some(path_str), ImageOutputFormat::Png).unwrap();
+        let server = client.server.subscribe();
+
+        // New tags are sent in-band, without reconnecting
+        std::fs::write(&path, format!("{}\ntags = [\"lab\"]", base)).unwrap();
+        client.reload_config().unwrap();
+        match client.outbox.next() {
+            Some((_, ActionMessage::Capabilities(capabilities))) => {
+                assert_eq!(capabilities.tags, ["lab"])
+            }
+            other => panic!("expected capabilities, got {:?}", other),
+        }
+        assert!(!server.has_changed().unwrap());
+        assert!(server.borrow().url.as_str().contains("tags=lab"));
+
+        std::fs::write(&path, "server_host = ").unwrap();
+        assert!(client.reload_config().is_err());
+        assert_eq!(client.config.tags, ["lab"]);
+
+        std::fs::remove_file(path).unwrap();
     }
 }
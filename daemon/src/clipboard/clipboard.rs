@@ -13,17 +13,26 @@ use arboard::Clipboard;
 use arboard::ImageData;
 use image::DynamicImage;
 
-pub struct Watcher<T: From<ClipboardContent>> {
-    // A channel of objects that can be ClipboardContent.into() converted
+/// A change of the local clipboard
+pub struct ClipboardChange {
+    pub content: ClipboardContent,
+    // Whether the application marked the content as concealed, e.g. a password
+    pub concealed: bool,
+}
+
+pub struct Watcher<T: From<ClipboardChange>> {
+    // A channel of objects that can be ClipboardChange.into() converted
     channel: Sender<T>,
     output_format: ImageOutputFormat,
+    check_hints: bool,
 }
 
-impl<T: From<ClipboardContent>> Watcher<T> {
-    pub fn new(output_format: ImageOutputFormat, sender: Sender<T>) -> Self {
+impl<T: From<ClipboardChange>> Watcher<T> {
+    pub fn new(output_format: ImageOutputFormat, sender: Sender<T>, check_hints: bool) -> Self {
         Self {
             channel: sender,
             output_format,
+            check_hints,
         }
     }
 
@@ -64,7 +73,7 @@ fn get_clipboard_content(output_format: &ImageOutputFormat) -> Result<ClipboardC
     if let Ok(img) = clipboard.get_image() {
         let mut buf = Vec::new();
         if let Ok(img) = to_dynamic_image(img) {
-            let _ = img.write_to(&mut Cursor::new(&mut buf), output_format.clone())?;
+            img.write_to(&mut Cursor::new(&mut buf), output_format.clone())?;
             return Ok(ClipboardContent::Image(buf));
         }
     }
@@ -72,14 +81,18 @@ fn get_clipboard_content(output_format: &ImageOutputFormat) -> Result<ClipboardC
     Err(anyhow::anyhow!("No clipboard content"))
 }
 
-impl<T: From<ClipboardContent>> ClipboardHandler for &mut Watcher<T> {
+impl<T: From<ClipboardChange>> ClipboardHandler for &mut Watcher<T> {
     fn on_clipboard_change(&mut self) -> CallbackResult {
         log::info!("Clipboard content changed");
         match get_clipboard_content(&self.output_format) {
             Ok(content) => {
+                let concealed = self.check_hints && super::hints::is_concealed();
+
                 // Since we cannot make this function async, use a trick to send the content
                 // to the main thread
-                let _ = self.channel.try_send(content.into());
+                let _ = self
+                    .channel
+                    .try_send(ClipboardChange { content, concealed }.into());
             }
             Err(err) => {
                 log::warn!("Error: {}", err);
@@ -94,6 +107,10 @@ impl<T: From<ClipboardContent>> ClipboardHandler for &mut Watcher<T> {
     }
 }
 
+fn same_image(a: &ImageData, b: &ImageData) -> bool {
+    a.height == b.height && a.width == b.width && a.bytes == b.bytes
+}
+
 pub fn set_clipboard(content: &ClipboardContent) -> anyhow::Result<()> {
     match &content {
         ClipboardContent::Text(text) => {
@@ -117,10 +134,7 @@ pub fn set_clipboard(content: &ClipboardContent) -> anyhow::Result<()> {
 
             // Check the current image and only set if it's different
             if let Ok(current_image) = clipboard.get_image() {
-                if current_image.height == clipboard_image.height
-                    && current_image.width == clipboard_image.width
-                    && current_image.bytes == clipboard_image.bytes
-                {
+                if same_image(&current_image, &clipboard_image) {
                     return Ok(());
                 }
             }
@@ -131,3 +145,29 @@ pub fn set_clipboard(content: &ClipboardContent) -> anyhow::Result<()> {
         }
     }
 }
+
+pub fn clear_clipboard() -> anyhow::Result<()> {
+    Clipboard::new()?
+        .clear()
+        .context("failed to clear clipboard")
+}
+
+/// Clears the clipboard if it still contains the given content
+pub fn clear_if_unchanged(content: &ClipboardContent) -> anyhow::Result<()> {
+    let mut clipboard = Clipboard::new()?;
+
+    let unchanged = match content {
+        ClipboardContent::Text(text) => clipboard.get_text().is_ok_and(|current| current == *text),
+        ClipboardContent::Image(bytes) => {
+            let image = from_dynamic_image(image::load_from_memory(bytes)?)?;
+            clipboard
+                .get_image()
+                .is_ok_and(|current| same_image(&current, &image))
+        }
+    };
+
+    if unchanged {
+        clipboard.clear().context("failed to clear clipboard")?;
+    }
+    Ok(())
+}
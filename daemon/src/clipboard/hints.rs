@@ -0,0 +1,75 @@
+// Clipboard formats password managers add to mark content as secret or short-lived
+#[cfg(not(target_os = "windows"))]
+const CONCEALED_TYPES: &[&str] = &[
+    "x-kde-passwordManagerHint",
+    "org.nspasteboard.ConcealedType",
+    "org.nspasteboard.TransientType",
+];
+
+/// Whether the current clipboard content is marked as concealed or transient
+#[cfg(target_os = "linux")]
+pub fn is_concealed() -> bool {
+    use std::process::{Command, Stdio};
+
+    let command =
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() && crate::util::is_installed("wl-paste") {
+            Command::new("wl-paste").arg("--list-types").output()
+        } else if std::env::var_os("DISPLAY").is_some() && crate::util::is_installed("xclip") {
+            Command::new("xclip")
+                .args(["-selection", "clipboard", "-t", "TARGETS", "-o"])
+                .stdin(Stdio::null())
+                .output()
+        } else {
+            return false;
+        };
+
+    match command {
+        Ok(output) => has_concealed_type(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            log::warn!("Failed to list clipboard types: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn is_concealed() -> bool {
+    let script = "ObjC.import('AppKit'); $.NSPasteboard.generalPasteboard.types.js.map(t => t.js).join('\\n')";
+
+    match std::process::Command::new("osascript")
+        .args(["-l", "JavaScript", "-e", script])
+        .output()
+    {
+        Ok(output) => has_concealed_type(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            log::warn!("Failed to list clipboard types: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn is_concealed() -> bool {
+    use winapi::um::winuser::{IsClipboardFormatAvailable, RegisterClipboardFormatW};
+
+    let name: Vec<u16> = "ExcludeClipboardContentFromMonitorProcessing"
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .collect();
+
+    // Registering an existing format just returns its id
+    unsafe {
+        let format = RegisterClipboardFormatW(name.as_ptr());
+        format != 0 && IsClipboardFormatAvailable(format) != 0
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+pub fn is_concealed() -> bool {
+    false
+}
+
+#[cfg(not(target_os = "windows"))]
+fn has_concealed_type(types: &str) -> bool {
+    types.lines().any(|t| CONCEALED_TYPES.contains(&t.trim()))
+}
@@ -1,2 +1,6 @@
+#[allow(clippy::module_inception)]
 pub mod clipboard;
-pub use crate::clipboard::clipboard::{set_clipboard, Watcher};
+mod hints;
+pub use crate::clipboard::clipboard::{
+    clear_clipboard, clear_if_unchanged, set_clipboard, ClipboardChange, Watcher,
+};
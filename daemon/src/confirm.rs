@@ -0,0 +1,256 @@
+use common::action::{Action, ActionDefinition};
+use common::client_config::{ConfirmationConfig, ConfirmationPolicy};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+// The dialog text is passed in the environment, so it never needs to be quoted for a script
+const TEXT_VARIABLE: &str = "MYSTI_CONFIRM_TEXT";
+
+pub enum Decision {
+    Accepted(String),
+    Denied(String),
+}
+
+impl Decision {
+    fn from_policy(policy: ConfirmationPolicy, reason: &str) -> Self {
+        match policy {
+            ConfirmationPolicy::Accept => {
+                Decision::Accepted(format!("{}, accepted by policy", reason))
+            }
+            ConfirmationPolicy::Deny => Decision::Denied(format!("{}, denied by policy", reason)),
+        }
+    }
+}
+
+enum Answer {
+    Yes,
+    No,
+    Timeout,
+}
+
+/// Asks the local user whether the action may run, falling back to the configured policies
+pub async fn ask(
+    config: &ConfirmationConfig,
+    definition: &ActionDefinition,
+    action: &Action,
+) -> Decision {
+    let text = format!(
+        "Another device wants to run \"{}\" on this computer.\n\nIf you don't answer within {} seconds, it will be {}.",
+        action,
+        config.timeout,
+        match config.on_timeout {
+            ConfirmationPolicy::Accept => "run",
+            ConfirmationPolicy::Deny => "denied",
+        }
+    );
+
+    decide(config, definition, dialog_command(config.timeout), text).await
+}
+
+async fn decide(
+    config: &ConfirmationConfig,
+    definition: &ActionDefinition,
+    command: Option<Command>,
+    text: String,
+) -> Decision {
+    let Some(mut command) = command else {
+        log::info!(
+            "Cannot show a confirmation dialog for {}, applying headless policy",
+            definition.name
+        );
+        return Decision::from_policy(config.headless, "no dialog available");
+    };
+
+    command
+        .env(TEXT_VARIABLE, text)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+
+    // Not every dialog supports a timeout, so we enforce it ourselves as well
+    let output =
+        tokio::time::timeout(Duration::from_secs(config.timeout + 1), command.output()).await;
+
+    let answer = match output {
+        Ok(Ok(output)) => parse_answer(
+            output.status.code(),
+            &String::from_utf8_lossy(&output.stdout),
+        ),
+        Ok(Err(e)) => {
+            log::warn!("Failed to show confirmation dialog: {}", e);
+            return Decision::from_policy(config.headless, "dialog failed");
+        }
+        Err(_) => Answer::Timeout,
+    };
+
+    match answer {
+        Answer::Yes => Decision::Accepted("accepted by the user".to_string()),
+        Answer::No => Decision::Denied("denied by the user".to_string()),
+        Answer::Timeout => Decision::from_policy(config.on_timeout, "no answer"),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn dialog_command(timeout: u64) -> Option<Command> {
+    let has_display =
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some();
+    linux_dialog_command(timeout, has_display, crate::util::is_installed)
+}
+
+// Pressing Enter must never approve an action, so Deny is the default button everywhere
+#[cfg(target_os = "linux")]
+fn linux_dialog_command(
+    timeout: u64,
+    has_display: bool,
+    is_installed: impl Fn(&str) -> bool,
+) -> Option<Command> {
+    if !has_display {
+        return None;
+    }
+
+    let script = if is_installed("zenity") {
+        format!(
+            "zenity --question --no-markup --default-cancel --title=Mysti --ok-label=Run --cancel-label=Deny --timeout={} --text=\"${}\"",
+            timeout, TEXT_VARIABLE
+        )
+    } else if is_installed("kdialog") {
+        format!(
+            "kdialog --title Mysti --yes-label Run --no-label Deny --warningyesno \"${}\"",
+            TEXT_VARIABLE
+        )
+    } else {
+        return None;
+    };
+
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script);
+    Some(command)
+}
+
+#[cfg(target_os = "windows")]
+fn dialog_command(timeout: u64) -> Option<Command> {
+    // 4 = Yes/No buttons, 32 = question icon, 256 = No is the default, 4096 = always on top
+    let script = format!(
+        "exit (New-Object -ComObject WScript.Shell).Popup($env:{}, {}, 'Mysti', 4 + 32 + 256 + 4096)",
+        TEXT_VARIABLE, timeout
+    );
+
+    let mut command = Command::new("powershell");
+    command.arg("-NoProfile").arg("-Command").arg(script);
+    // CREATE_NO_WINDOW
+    command.creation_flags(0x08000000);
+    Some(command)
+}
+
+#[cfg(target_os = "macos")]
+fn dialog_command(timeout: u64) -> Option<Command> {
+    let script = format!(
+        "display dialog (system attribute \"{}\") with title \"Mysti\" buttons {{\"Deny\", \"Run\"}} default button \"Deny\" cancel button \"Deny\" giving up after {}",
+        TEXT_VARIABLE, timeout
+    );
+
+    let mut command = Command::new("osascript");
+    command.arg("-e").arg(script);
+    Some(command)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+fn dialog_command(_timeout: u64) -> Option<Command> {
+    None
+}
+
+fn parse_answer(code: Option<i32>, stdout: &str) -> Answer {
+    if cfg!(target_os = "macos") {
+        // Denying makes osascript fail, otherwise it prints e.g. "button returned:Run, gave up:false"
+        return if stdout.contains("gave up:true") {
+            Answer::Timeout
+        } else if code == Some(0) && stdout.contains("button returned:Run") {
+            Answer::Yes
+        } else {
+            Answer::No
+        };
+    }
+
+    match code {
+        // zenity/kdialog
+        Some(0) => Answer::Yes,
+        Some(5) if cfg!(target_os = "linux") => Answer::Timeout,
+        // WScript.Shell Popup
+        Some(6) => Answer::Yes,
+        Some(-1) => Answer::Timeout,
+        _ => Answer::No,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::client_config::ConfirmationPolicy;
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn answers() {
+        // WScript.Shell Popup: 6 = Yes, 7 = No, -1 = timeout
+        assert!(matches!(parse_answer(Some(6), ""), Answer::Yes));
+        assert!(matches!(parse_answer(Some(7), ""), Answer::No));
+        assert!(matches!(parse_answer(Some(-1), ""), Answer::Timeout));
+        // Killed by a signal
+        assert!(matches!(parse_answer(None, ""), Answer::No));
+
+        if cfg!(target_os = "linux") {
+            // zenity/kdialog: 0 = Run, 1 = Deny or closed, 5 = zenity timeout
+            assert!(matches!(parse_answer(Some(0), ""), Answer::Yes));
+            assert!(matches!(parse_answer(Some(1), ""), Answer::No));
+            assert!(matches!(parse_answer(Some(5), ""), Answer::Timeout));
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn answers() {
+        let answer = |code, stdout| parse_answer(Some(code), stdout);
+        assert!(matches!(
+            answer(0, "button returned:Run, gave up:false"),
+            Answer::Yes
+        ));
+        assert!(matches!(
+            answer(0, "button returned:Deny, gave up:false"),
+            Answer::No
+        ));
+        assert!(matches!(answer(1, ""), Answer::No));
+        assert!(matches!(
+            answer(0, "button returned:, gave up:true"),
+            Answer::Timeout
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn dialog_tools() {
+        assert!(linux_dialog_command(30, false, |_| true).is_none());
+        assert!(linux_dialog_command(30, true, |_| false).is_none());
+        assert!(linux_dialog_command(30, true, |program| program == "kdialog").is_some());
+    }
+
+    #[tokio::test]
+    async fn without_dialog() {
+        let definition = ActionDefinition {
+            name: "shutdown".to_string(),
+            ..Default::default()
+        };
+
+        for (headless, accepted) in [
+            (ConfirmationPolicy::Deny, false),
+            (ConfirmationPolicy::Accept, true),
+        ] {
+            let config = ConfirmationConfig {
+                headless,
+                ..Default::default()
+            };
+            let decision = decide(&config, &definition, None, String::new()).await;
+            assert_eq!(matches!(decision, Decision::Accepted(_)), accepted);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use crate::client::now;
+use crate::outbox::Outbox;
+use crate::reconnect::{self, Backoff, Endpoint, Socket};
+use common::client_config::ReconnectConfig;
+use common::ipc::ConnectionState;
+use common::ActionMessage;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use std::convert::TryInto;
+use std::ops::Add;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+
+// Connections that lasted this long reset the backoff after they are lost
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// Keeps the WebSocket connection to the server open, sending queued messages
+/// and forwarding the ones from the server
+pub struct Connection {
+    // Reconnects whenever this changes
+    pub server: watch::Receiver<Endpoint>,
+    // Read before every retry
+    pub reconnect: watch::Receiver<ReconnectConfig>,
+    pub updates: watch::Sender<ConnectionState>,
+    pub outbox: Outbox,
+    pub remote_events: Sender<ActionMessage>,
+    // Notified when the network changed, so a broken connection is noticed early
+    pub network_hints: Arc<Notify>,
+    // Set when the daemon exits, the outbox is flushed and the connection closed then
+    pub shutdown: watch::Receiver<bool>,
+}
+
+// Sends queued messages in order, keeping the ones that could not be sent
+async fn flush(
+    outbox: &Outbox,
+    sender: &mut SplitSink<Socket, Message>,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    while let Some((id, event)) = outbox.next() {
+        // Not the content, it may be sensitive
+        log::debug!("Sending {} message", event.kind());
+
+        let message: Message = match event.try_into() {
+            Ok(message) => message,
+            Err(err) => {
+                log::warn!("Failed to convert event to message: {}", err);
+                outbox.sent(id);
+                continue;
+            }
+        };
+
+        sender.send(message).await?;
+        outbox.sent(id);
+    }
+    Ok(())
+}
+
+impl Connection {
+    pub async fn run(mut self) {
+        let mut backoff = Backoff::default();
+        loop {
+            // Attempt to connect to server and retry if it fails
+            let socket = {
+                let mut fail_count = 0;
+                let mut last_failure = None;
+                loop {
+                    let endpoint = self.server.borrow_and_update().clone();
+                    if fail_count == 0 {
+                        log::info!("Connecting to {}", endpoint.url);
+                    }
+
+                    let result = select! {
+                        result = reconnect::connect(&endpoint) => result,
+                        Ok(()) = self.shutdown.changed() => return,
+                    };
+                    let error = match result {
+                        Ok(socket) => break socket,
+                        Err(error) => error,
+                    };
+                    fail_count += 1;
+
+                    // Retrying soon won't fix a wrong token, but the config might change
+                    let config = self.reconnect.borrow().clone();
+                    let delay = if error.failure.is_config_error() {
+                        backoff.max_delay(&config)
+                    } else {
+                        backoff.next_delay(&config)
+                    };
+
+                    if last_failure != Some(error.failure) {
+                        if error.failure.is_config_error() {
+                            log::error!("Failed to connect to server: {}", error);
+                        } else {
+                            log::warn!("Failed to connect to server: {}", error);
+                        }
+                        last_failure = Some(error.failure);
+                    }
+                    log::debug!("Retrying in {:.1}s", delay.as_secs_f64());
+
+                    self.updates.send_replace(ConnectionState::Connecting {
+                        attempts: fail_count,
+                        last_error: Some(error.message),
+                        failure: Some(error.failure),
+                        retry_at: Some(now() + delay.as_secs()),
+                    });
+
+                    select! {
+                        _ = tokio::time::sleep(delay) => (),
+                        _ = self.network_hints.notified() => backoff.reset(),
+                        Ok(()) = self.server.changed() => backoff.reset(),
+                        Ok(()) = self.shutdown.changed() => return,
+                    }
+                }
+            };
+            let connected_at = Instant::now();
+
+            log::info!("Connected to server");
+            self.updates
+                .send_replace(ConnectionState::Connected { since: now() });
+
+            let restarting = self.connected(socket).await;
+            if *self.shutdown.borrow() {
+                return;
+            }
+
+            // Connections that fail right away count as failed attempts, but a restarting
+            // server is back soon
+            if restarting || connected_at.elapsed() >= STABLE_CONNECTION {
+                backoff.reset();
+            }
+            let delay = backoff.next_delay(&self.reconnect.borrow());
+            log::info!(
+                "Disconnected from server - reconnecting in {:.1} seconds",
+                delay.as_secs_f64()
+            );
+            self.updates.send_replace(ConnectionState::Connecting {
+                attempts: 0,
+                last_error: None,
+                failure: None,
+                retry_at: Some(now() + delay.as_secs()),
+            });
+            select! {
+                _ = tokio::time::sleep(delay) => (),
+                _ = self.network_hints.notified() => (),
+                Ok(()) = self.server.changed() => (),
+                Ok(()) = self.shutdown.changed() => return,
+            }
+        }
+    }
+
+    // Handles the connection until it is lost, returning whether the server is restarting
+    async fn connected(&mut self, socket: Socket) -> bool {
+        let (mut socket_sender, socket_receiver) = socket.split();
+        // Peekable is needed to check if there is a pong message without consuming it
+        let mut socket_receiver = pin!(socket_receiver.peekable());
+
+        // Everything that was queued while disconnected
+        if let Err(e) = flush(&self.outbox, &mut socket_sender).await {
+            log::warn!("Failed to send message to server: {}", e);
+            return false;
+        }
+
+        // We start pinging around 5 seconds after connecting, as
+        // otherwise we might have a race between the initial ping and
+        // the initial data sent from the server.
+        // While we do handle that race correctly in the ping/pong handler,
+        // the delay helps make it less likely.
+        let mut ping_interval = tokio::time::interval_at(
+            Instant::now().add(Duration::from_secs(5)),
+            Duration::from_secs(60),
+        );
+
+        loop {
+            // Read something from the socket OR write something to the socket when we get an outgoing event
+            select! {
+                _ = self.outbox.wait() => {
+                    if let Err(e) = flush(&self.outbox, &mut socket_sender).await {
+                        log::warn!("Failed to send message to server: {}", e);
+                        break;
+                    }
+                }
+                event = socket_receiver.next() => {
+                    let Some(event) = event else { break };
+                    let Ok(event) = event else {
+                        log::warn!("Failed to receive remote event: {:?}", event);
+                        break;
+                    };
+
+                    match event {
+                        Message::Close(Some(frame)) if frame.code == CloseCode::Restart => {
+                            log::info!("Server is restarting");
+                            return true;
+                        }
+                        Message::Close(_) => {
+                            log::warn!("Server sent close");
+                            break;
+                        }
+                        Message::Pong(_) => {
+                            continue;
+                        }
+                        _ => (),
+                    };
+
+                    let action_message : ActionMessage = match event.try_into() {
+                        Ok(event) => event,
+                        Err(err) => {
+                            log::warn!("Failed to convert message to event: {}", err);
+                            continue;
+                        }
+                    };
+                    log::debug!("Received {} message", action_message.kind());
+
+                    self.remote_events.send(action_message).await.expect("Failed to send remote event");
+                }
+                Ok(()) = self.server.changed() => {
+                    log::info!("Server settings changed");
+                    break;
+                }
+                Ok(()) = self.shutdown.changed() => {
+                    if let Err(e) = flush(&self.outbox, &mut socket_sender).await {
+                        log::warn!("Failed to send message to server: {}", e);
+                    }
+                    let _ = socket_sender
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Away,
+                            reason: "daemon shutting down".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                _ = self.network_hints.notified() => {
+                    // Check right away whether the connection still works
+                    ping_interval.reset_immediately();
+                }
+                _ = ping_interval.tick() => {
+                    if let Err(e) = tokio::time::timeout(Duration::from_secs(5), socket_sender.send(Message::Ping(vec![1,2,3,4]))).await {
+                        log::warn!("Failed to send ping: {}", e);
+                        break;
+                    }
+
+                    // We cannot be certain that the next message is a pong,
+                    // as the server might send a message in between
+                    let Ok(Some(Ok(event))) = tokio::time::timeout(Duration::from_secs(5), socket_receiver.as_mut().peek()).await else {
+                        log::warn!("Failed to receive pong");
+                        break;
+                    };
+                    if let Message::Pong(_) = event {
+                        // Now actually consume the pong
+                        match socket_receiver.next().await {
+                            Some(Ok(Message::Pong(_))) => (),
+                            // If we somehow get something else, it must be a bug
+                            val => panic!("Expected buffered pong message, but got {:?}", val),
+                        };
+                        continue;
+                    } else {
+                        log::warn!("Received non-pong message");
+                        // We continue with that message
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::url::Url;
+    use common::action::{ActionResult, ActionStatus};
+    use common::ClipboardContent;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::WebSocketStream;
+
+    fn clipboard(text: &str) -> ActionMessage {
+        ActionMessage::Clipboard(ClipboardContent::Text(text.to_string()))
+    }
+
+    async fn accept(listener: &TcpListener) -> WebSocketStream<tokio::net::TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio_tungstenite::accept_async(stream).await.unwrap()
+    }
+
+    async fn receive(socket: &mut WebSocketStream<tokio::net::TcpStream>) -> ActionMessage {
+        loop {
+            let message = socket.next().await.unwrap().unwrap();
+            if message.is_text() || message.is_binary() {
+                return message.try_into().unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn flushes_outbox_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/ws", listener.local_addr().unwrap())).unwrap();
+        let result = ActionMessage::ActionResult(ActionResult {
+            id: Some(1),
+            action: "Shutdown".to_string(),
+            status: ActionStatus::Finished,
+            exit_code: Some(0),
+            message: None,
+        });
+
+        // Queued before the daemon could connect
+        let outbox = Outbox::new(10);
+        outbox.push(clipboard("stale"));
+        outbox.push(result.clone());
+        outbox.push(clipboard("latest"));
+
+        let (_server, server) = watch::channel(Endpoint {
+            url,
+            authorization: "Bearer token".to_string(),
+        });
+        let (_reconnect, reconnect) = watch::channel(ReconnectConfig {
+            initial_delay: 0.05,
+            max_delay: 0.1,
+            multiplier: 2.0,
+            jitter: 0.0,
+        });
+        let (updates, mut state) = watch::channel(ConnectionState::Connecting {
+            attempts: 0,
+            last_error: None,
+            failure: None,
+            retry_at: None,
+        });
+        let (remote_events, _remote) = tokio::sync::mpsc::channel(10);
+        let (stop, shutdown) = watch::channel(false);
+        let task = tokio::spawn(
+            Connection {
+                server,
+                reconnect,
+                updates,
+                outbox: outbox.clone(),
+                remote_events,
+                network_hints: Arc::new(Notify::new()),
+                shutdown,
+            }
+            .run(),
+        );
+
+        let mut socket = accept(&listener).await;
+        assert_eq!(receive(&mut socket).await, result);
+        assert_eq!(receive(&mut socket).await, clipboard("latest"));
+        drop(socket);
+
+        state
+            .wait_for(|state| matches!(state, ConnectionState::Connecting { .. }))
+            .await
+            .unwrap();
+        outbox.push(clipboard("offline"));
+        outbox.push(clipboard("offline again"));
+
+        let mut socket = accept(&listener).await;
+        assert_eq!(receive(&mut socket).await, clipboard("offline again"));
+
+        // Queued messages are sent before the connection is closed
+        outbox.push(result.clone());
+        stop.send_replace(true);
+        assert_eq!(receive(&mut socket).await, result);
+        match socket.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => {
+                assert_eq!(frame.reason, "daemon shutting down")
+            }
+            other => panic!("expected close frame, got {:?}", other),
+        }
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnects_promptly_after_server_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/ws", listener.local_addr().unwrap())).unwrap();
+
+        let (_server, server) = watch::channel(Endpoint {
+            url,
+            authorization: "Bearer token".to_string(),
+        });
+        // Only the first retry is quick
+        let (_reconnect, reconnect) = watch::channel(ReconnectConfig {
+            initial_delay: 0.05,
+            max_delay: 60.0,
+            multiplier: 1000.0,
+            jitter: 0.0,
+        });
+        let (updates, _state) = watch::channel(ConnectionState::Connecting {
+            attempts: 0,
+            last_error: None,
+            failure: None,
+            retry_at: None,
+        });
+        let (remote_events, _remote) = tokio::sync::mpsc::channel(10);
+        let (_stop, shutdown) = watch::channel(false);
+        let task = tokio::spawn(
+            Connection {
+                server,
+                reconnect,
+                updates,
+                outbox: Outbox::new(10),
+                remote_events,
+                network_hints: Arc::new(Notify::new()),
+                shutdown,
+            }
+            .run(),
+        );
+
+        // A lost connection uses up the quick retry
+        drop(accept(&listener).await);
+
+        let mut socket = accept(&listener).await;
+        socket
+            .send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Restart,
+                reason: "server restarting".into(),
+            })))
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), accept(&listener))
+            .await
+            .expect("did not reconnect after the restart");
+        task.abort();
+    }
+}
@@ -0,0 +1,461 @@
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use common::client_config::HistoryConfig;
+use common::ipc::{EntryOrigin, HistoryEntry};
+use common::sync::ContentKind;
+use common::ClipboardContent;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Encrypted history files start with this, followed by the salt, the nonce and the ciphertext
+const ENCRYPTED_MAGIC: &[u8] = b"MYSTIENC";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+const PREVIEW_CHARS: usize = 80;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    id: u64,
+    origin: EntryOrigin,
+    copied_at: u64,
+    #[serde(with = "stored_content")]
+    content: ClipboardContent,
+}
+
+// Stores images as base64 instead of a JSON array with a number per byte
+mod stored_content {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use common::ClipboardContent;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    enum StoredRef<'a> {
+        Text(&'a str),
+        Image(String),
+    }
+
+    #[derive(Deserialize)]
+    enum Stored {
+        Text(String),
+        Image(StoredImage),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredImage {
+        Base64(String),
+        // Written by older versions
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(
+        content: &ClipboardContent,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match content {
+            ClipboardContent::Text(text) => StoredRef::Text(text),
+            ClipboardContent::Image(bytes) => StoredRef::Image(STANDARD.encode(bytes)),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ClipboardContent, D::Error> {
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Text(text) => ClipboardContent::Text(text),
+            Stored::Image(StoredImage::Base64(encoded)) => {
+                ClipboardContent::Image(STANDARD.decode(encoded).map_err(serde::de::Error::custom)?)
+            }
+            Stored::Image(StoredImage::Bytes(bytes)) => ClipboardContent::Image(bytes),
+        })
+    }
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        match &self.content {
+            ClipboardContent::Text(text) => text.len(),
+            ClipboardContent::Image(bytes) => bytes.len(),
+        }
+    }
+
+    fn info(&self) -> HistoryEntry {
+        let preview = match &self.content {
+            ClipboardContent::Text(text) => {
+                let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                match line.char_indices().nth(PREVIEW_CHARS) {
+                    Some((end, _)) => format!("{}...", &line[..end]),
+                    None => line,
+                }
+            }
+            ClipboardContent::Image(bytes) => format!("Image ({} KB)", bytes.len() / 1024),
+        };
+
+        HistoryEntry {
+            id: self.id,
+            origin: self.origin,
+            kind: ContentKind::of(&self.content),
+            copied_at: self.copied_at,
+            size: self.size(),
+            preview,
+        }
+    }
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct State {
+    next_id: u64,
+    // Oldest first
+    entries: VecDeque<Entry>,
+}
+
+type Cipher = (ChaCha20Poly1305, [u8; SALT_LEN]);
+
+/// Clipboard entries copied on or received by this device, capped by count and size
+pub struct History {
+    config: HistoryConfig,
+    state: State,
+    // Key and salt for the history file, if it is encrypted
+    cipher: Option<Cipher>,
+    // Encrypting and writing the file happens on its own thread, off the event loop
+    saves: Option<mpsc::Sender<State>>,
+    saver: Option<JoinHandle<()>>,
+}
+
+fn derive_cipher(passphrase: &str, salt: [u8; SALT_LEN]) -> Result<ChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("failed to derive history key: {}", e))?;
+
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+impl History {
+    /// A history that is never written to a file, whatever the config says
+    pub fn in_memory(config: HistoryConfig) -> Self {
+        Self {
+            config: HistoryConfig {
+                file: None,
+                passphrase: None,
+                ..config
+            },
+            state: State::default(),
+            cipher: None,
+            saves: None,
+            saver: None,
+        }
+    }
+
+    pub fn new(config: HistoryConfig) -> Result<Self> {
+        let mut history = Self::in_memory(config.clone());
+        history.config = config;
+
+        let Some(path) = history.path() else {
+            return Ok(history);
+        };
+
+        let content = match std::fs::read(&path) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context(format!("failed to read {}", path.display())),
+        };
+
+        let salt = match &content {
+            Some(content) if content.starts_with(ENCRYPTED_MAGIC) => {
+                let start = ENCRYPTED_MAGIC.len();
+                content
+                    .get(start..start + SALT_LEN)
+                    .context("history file is truncated")?
+                    .try_into()?
+            }
+            _ => {
+                let mut salt = [0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                salt
+            }
+        };
+        if let Some(passphrase) = &history.config.passphrase {
+            history.cipher = Some((derive_cipher(passphrase, salt)?, salt));
+        }
+
+        if let Some(content) = content {
+            history.state = history
+                .decode(&content)
+                .context(format!("failed to load history from {}", path.display()))?;
+            log::info!(
+                "Loaded {} clipboard history entries",
+                history.state.entries.len()
+            );
+        }
+
+        let (saves, receiver) = mpsc::channel();
+        let cipher = history.cipher.clone();
+        history.saves = Some(saves);
+        history.saver = Some(std::thread::spawn(move || {
+            save_all(&path, cipher.as_ref(), receiver)
+        }));
+
+        Ok(history)
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        self.config.file.as_ref().map(PathBuf::from)
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<State> {
+        let Some(encrypted) = content.strip_prefix(ENCRYPTED_MAGIC) else {
+            return Ok(serde_json::from_slice(content)?);
+        };
+
+        let Some((cipher, _)) = &self.cipher else {
+            return Err(anyhow::anyhow!(
+                "the history is encrypted, but no passphrase is configured"
+            ));
+        };
+
+        let encrypted = &encrypted[SALT_LEN..];
+        if encrypted.len() < NONCE_LEN {
+            return Err(anyhow::anyhow!("history file is truncated"));
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("wrong passphrase or corrupted history"))?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    fn save(&self) {
+        if let Some(saves) = &self.saves {
+            // The saver only stops when the history is dropped
+            let _ = saves.send(self.state.clone());
+        }
+    }
+
+    pub fn add(&mut self, content: &ClipboardContent, origin: EntryOrigin) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+
+        // Setting received content makes the clipboard report it again as a local change
+        if self
+            .state
+            .entries
+            .back()
+            .is_some_and(|entry| entry.content == *content)
+        {
+            return;
+        }
+
+        // Copying an old entry again moves it to the front
+        self.state.entries.retain(|entry| entry.content != *content);
+
+        self.state.next_id += 1;
+        let entry = Entry {
+            id: self.state.next_id,
+            origin,
+            copied_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            content: content.clone(),
+        };
+        if entry.size() > self.config.max_bytes {
+            log::info!("Not adding clipboard content to history, it is too large");
+            return;
+        }
+        self.state.entries.push_back(entry);
+
+        let mut bytes: usize = self.state.entries.iter().map(Entry::size).sum();
+        while self.state.entries.len() > self.config.max_entries || bytes > self.config.max_bytes {
+            let Some(removed) = self.state.entries.pop_front() else {
+                break;
+            };
+            bytes -= removed.size();
+        }
+
+        self.save();
+    }
+
+    /// All entries, newest first
+    pub fn list(&self) -> Vec<HistoryEntry> {
+        self.state.entries.iter().rev().map(Entry::info).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&ClipboardContent> {
+        self.state
+            .entries
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(|entry| &entry.content)
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        // Wait for the last state to be written
+        self.saves.take();
+        if let Some(saver) = self.saver.take() {
+            let _ = saver.join();
+        }
+    }
+}
+
+// Writes the states sent to it, skipping ones that were replaced while writing
+fn save_all(path: &Path, cipher: Option<&Cipher>, receiver: mpsc::Receiver<State>) {
+    while let Ok(mut state) = receiver.recv() {
+        while let Ok(newer) = receiver.try_recv() {
+            state = newer;
+        }
+
+        let result = encode(&state, cipher).and_then(|content| write(path, &content));
+        if let Err(e) = result {
+            log::warn!("Failed to save clipboard history: {:#}", e);
+        }
+    }
+}
+
+fn encode(state: &State, cipher: Option<&Cipher>) -> Result<Vec<u8>> {
+    let plaintext = serde_json::to_vec(state)?;
+
+    let Some((cipher, salt)) = cipher else {
+        return Ok(plaintext);
+    };
+
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| anyhow::anyhow!("failed to encrypt history"))?;
+
+    let mut content = ENCRYPTED_MAGIC.to_vec();
+    content.extend(salt);
+    content.extend(nonce);
+    content.extend(ciphertext);
+    Ok(content)
+}
+
+fn write(path: &Path, content: &[u8]) -> Result<()> {
+    // Write to a temporary file first, so a crash never leaves a half-written file
+    let tmp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Only the user may read the history, even while it is being written
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    // The mode only applies to new files, a left-over one may have been created otherwise
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(content)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> ClipboardContent {
+        ClipboardContent::Text(text.to_string())
+    }
+
+    #[test]
+    fn capped_and_encrypted() {
+        let path = std::env::temp_dir().join(format!("mysti-history-{}", std::process::id()));
+        let config = HistoryConfig {
+            max_entries: 3,
+            max_bytes: 12,
+            file: Some(path.to_str().unwrap().to_string()),
+            passphrase: Some("secret".to_string()),
+        };
+
+        let mut history = History::new(config.clone()).unwrap();
+        history.add(&text("one"), EntryOrigin::Local);
+        history.add(&text("two"), EntryOrigin::Remote);
+        history.add(&text("two"), EntryOrigin::Local);
+        history.add(&text("three"), EntryOrigin::Local);
+        history.add(&text("four"), EntryOrigin::Local);
+        history.add(&text("much too large"), EntryOrigin::Local);
+
+        // "one" was dropped because of the count limit, the last one is larger than the byte limit
+        let entries = history.list();
+        let previews: Vec<_> = entries.iter().map(|e| e.preview.as_str()).collect();
+        assert_eq!(previews, ["four", "three", "two"]);
+        assert_eq!(entries[2].origin, EntryOrigin::Remote);
+        assert_eq!(history.get(entries[0].id), Some(&text("four")));
+
+        // Waits until the history is written
+        drop(history);
+        let stored = std::fs::read(&path).unwrap();
+        assert!(stored.starts_with(ENCRYPTED_MAGIC));
+        assert!(!String::from_utf8_lossy(&stored).contains("three"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert_eq!(History::new(config.clone()).unwrap().list(), entries);
+        assert!(History::new(HistoryConfig {
+            passphrase: Some("wrong".to_string()),
+            ..config
+        })
+        .is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn images_stored_as_base64() {
+        let path =
+            std::env::temp_dir().join(format!("mysti-history-images-{}", std::process::id()));
+        let config = HistoryConfig {
+            file: Some(path.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+
+        // Older versions stored the bytes as numbers
+        std::fs::write(
+            &path,
+            r#"{"next_id":1,"entries":[{"id":1,"origin":"local","copied_at":0,"content":{"Image":[1,2,3]}}]}"#,
+        )
+        .unwrap();
+
+        let mut history = History::new(config.clone()).unwrap();
+        assert_eq!(
+            history.get(1),
+            Some(&ClipboardContent::Image(vec![1, 2, 3]))
+        );
+        history.add(&ClipboardContent::Image(vec![4, 5, 6]), EntryOrigin::Remote);
+        drop(history);
+
+        let stored = std::fs::read_to_string(&path).unwrap();
+        assert!(stored.contains(r#"{"Image":"BAUG"}"#), "{}", stored);
+        let history = History::new(config).unwrap();
+        assert_eq!(
+            history.get(2),
+            Some(&ClipboardContent::Image(vec![4, 5, 6]))
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+use common::ipc::{IpcRequest, IpcResponse};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+/// A request from a local process, answered by sending the response to the responder
+pub struct IpcCall {
+    pub request: IpcRequest,
+    pub responder: oneshot::Sender<IpcResponse>,
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    calls: Sender<IpcCall>,
+) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+
+    let response = match serde_json::from_str::<IpcRequest>(&line) {
+        Ok(request) => {
+            let (responder, response) = oneshot::channel();
+            calls
+                .send(IpcCall { request, responder })
+                .await
+                .context("daemon is shutting down")?;
+            response.await?
+        }
+        Err(e) => IpcResponse::Error(format!("invalid request: {}", e)),
+    };
+
+    let mut line = serde_json::to_string(&response)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Accepts local connections and forwards their requests to the calls channel
+#[cfg(unix)]
+pub async fn serve(path: &Path, calls: Sender<IpcCall>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::UnixListener;
+
+    // A socket left over from a previous run would make binding fail
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow::anyhow!(
+                "another daemon is already listening at {}",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener =
+        UnixListener::bind(path).context(format!("failed to listen at {}", path.display()))?;
+    // Only the current user may control the daemon
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    log::info!("Listening for local requests at {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let calls = calls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, calls).await {
+                log::warn!("Failed to handle local request: {:#}", e);
+            }
+        });
+    }
+}
+
+/// Accepts local connections and forwards their requests to the calls channel
+#[cfg(windows)]
+pub async fn serve(path: &Path, calls: Sender<IpcCall>) -> Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .reject_remote_clients(true)
+        .create(path)
+        .context(format!("failed to listen at {}", path.display()))?;
+
+    log::info!("Listening for local requests at {}", path.display());
+
+    loop {
+        server.connect().await?;
+
+        // Create the next instance before handling this one, so clients never find no pipe
+        let connected = std::mem::replace(&mut server, ServerOptions::new().create(path)?);
+
+        let calls = calls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(connected, calls).await {
+                log::warn!("Failed to handle local request: {:#}", e);
+            }
+        });
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn request_response() {
+        let path = std::env::temp_dir().join(format!("mysti-test-{}.sock", std::process::id()));
+        let (calls, mut receiver) = channel(1);
+
+        let server_path = path.clone();
+        tokio::spawn(async move { serve(&server_path, calls).await });
+
+        tokio::spawn(async move {
+            while let Some(call) = receiver.recv().await {
+                let response = match call.request {
+                    IpcRequest::History => IpcResponse::History(Vec::new()),
+                    _ => IpcResponse::Error("unexpected".to_string()),
+                };
+                let _ = call.responder.send(response);
+            }
+        });
+
+        // Wait until the socket exists
+        for _ in 0..50 {
+            if path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let client_path = path.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            common::ipc::send_to(&client_path, &IpcRequest::History)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(response, IpcResponse::History(Vec::new()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
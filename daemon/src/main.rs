@@ -3,6 +3,16 @@ use image::ImageOutputFormat;
 
 mod client;
 mod clipboard;
+mod confirm;
+mod connection;
+mod history;
+mod ipc;
+mod metadata;
+mod outbox;
+mod reconnect;
+mod runner;
+mod sensitive;
+mod util;
 
 #[tokio::main]
 async fn main() {
@@ -13,9 +23,11 @@ async fn main() {
         .apply()
         .expect("Failed to initialize logger");
 
-    let config = common::client_config::find_parse_config().expect("Failed to parse config");
+    let (config_file, config) =
+        common::client_config::find_parse_config_file().expect("Failed to parse config");
 
-    let mut client = MystiClient::new(config, ImageOutputFormat::Png);
+    let mut client = MystiClient::new(config, Some(config_file), ImageOutputFormat::Png)
+        .expect("Failed to create client");
 
     client.run().await.expect("Failed to run client");
 }
@@ -0,0 +1,214 @@
+use common::name::{hostname, os_name, user_name};
+use common::types::{Battery, DeviceMetadata};
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use std::net::IpAddr;
+
+/// Collects information about this device. This runs commands, so it should not be
+/// called on the async runtime directly.
+pub fn collect() -> DeviceMetadata {
+    let (ips, macs) = addresses();
+
+    DeviceMetadata {
+        user: user_name(),
+        hostname: hostname(),
+        os: std::env::consts::OS.to_string(),
+        os_version: os_name(),
+        arch: std::env::consts::ARCH.to_string(),
+        daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+        ips,
+        macs,
+        idle_secs: idle_secs(),
+        battery: battery(),
+        last_activity: None,
+    }
+}
+
+// Addresses of all interfaces that are reachable from other devices
+pub(crate) fn addresses() -> (Vec<IpAddr>, Vec<String>) {
+    let interfaces = match NetworkInterface::show() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            log::warn!("Failed to list network interfaces: {}", e);
+            return (Vec::new(), Vec::new());
+        }
+    };
+
+    let mut ips = Vec::new();
+    let mut macs = Vec::new();
+    for interface in interfaces
+        .iter()
+        .filter(|i| !i.internal && !i.addr.is_empty())
+    {
+        ips.extend(interface.addr.iter().map(|addr| addr.ip()));
+
+        if let Some(mac) = &interface.mac_addr {
+            if mac != "00:00:00:00:00:00" && !macs.contains(mac) {
+                macs.push(mac.clone());
+            }
+        }
+    }
+
+    (ips, macs)
+}
+
+#[cfg(target_os = "linux")]
+fn idle_secs() -> Option<u64> {
+    use std::process::Command;
+
+    // GNOME on Wayland doesn't let other programs see input, but reports the idle time
+    if crate::util::is_installed("gdbus") {
+        let output = Command::new("gdbus")
+            .args([
+                "call",
+                "--session",
+                "--dest",
+                "org.gnome.Mutter.IdleMonitor",
+                "--object-path",
+                "/org/gnome/Mutter/IdleMonitor/Core",
+                "--method",
+                "org.gnome.Mutter.IdleMonitor.GetIdletime",
+            ])
+            .output()
+            .ok()?;
+        // Prints "(uint64 1234,)"
+        let millis = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|millis| millis.trim_end_matches([',', ')']).parse::<u64>().ok());
+        if let (true, Some(millis)) = (output.status.success(), millis) {
+            return Some(millis / 1000);
+        }
+    }
+
+    if std::env::var_os("DISPLAY").is_some() && crate::util::is_installed("xprintidle") {
+        let output = Command::new("xprintidle").output().ok()?;
+        let millis = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<u64>();
+        return millis.ok().map(|millis| millis / 1000);
+    }
+
+    None
+}
+
+#[cfg(target_os = "macos")]
+fn idle_secs() -> Option<u64> {
+    let output = std::process::Command::new("ioreg")
+        .args(["-c", "IOHIDSystem", "-d", "4"])
+        .output()
+        .ok()?;
+
+    // Contains a line like "HIDIdleTime" = 1234567 (in nanoseconds)
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|line| line.contains("\"HIDIdleTime\""))
+        .and_then(|line| line.rsplit('=').next())
+        .and_then(|nanos| nanos.trim().parse::<u64>().ok())
+        .map(|nanos| nanos / 1_000_000_000)
+}
+
+#[cfg(target_os = "windows")]
+fn idle_secs() -> Option<u64> {
+    use winapi::um::sysinfoapi::GetTickCount;
+    use winapi::um::winuser::{GetLastInputInfo, LASTINPUTINFO};
+
+    let mut info = LASTINPUTINFO {
+        cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+        dwTime: 0,
+    };
+
+    unsafe {
+        if GetLastInputInfo(&mut info) == 0 {
+            return None;
+        }
+        // Both wrap around after 49 days
+        Some(GetTickCount().wrapping_sub(info.dwTime) as u64 / 1000)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+fn idle_secs() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn battery() -> Option<Battery> {
+    let read = |path: &std::path::Path| std::fs::read_to_string(path).ok();
+
+    std::fs::read_dir("/sys/class/power_supply")
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| read(&path.join("type")).is_some_and(|t| t.trim() == "Battery"))
+        .and_then(|path| {
+            let percent = read(&path.join("capacity"))?.trim().parse().ok()?;
+            let status = read(&path.join("status")).unwrap_or_default();
+            Some(Battery {
+                percent,
+                charging: status.trim() == "Charging",
+            })
+        })
+}
+
+#[cfg(target_os = "macos")]
+fn battery() -> Option<Battery> {
+    let output = std::process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .ok()?;
+
+    // Contains a line like "-InternalBattery-0 (id=1234)	85%; charging; 1:05 remaining"
+    let output = String::from_utf8_lossy(&output.stdout);
+    let line = output.lines().find(|line| line.contains('%'))?;
+    let percent = line
+        .split('%')
+        .next()?
+        .rsplit(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()?;
+
+    Some(Battery {
+        percent,
+        charging: line.contains("; charging"),
+    })
+}
+
+#[cfg(target_os = "windows")]
+fn battery() -> Option<Battery> {
+    use winapi::um::winbase::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    let mut status: SYSTEM_POWER_STATUS = unsafe { std::mem::zeroed() };
+    if unsafe { GetSystemPowerStatus(&mut status) } == 0 {
+        return None;
+    }
+
+    // 128 means there is no battery, 255 that the state is unknown
+    if status.BatteryFlag & 128 != 0 || status.BatteryLifePercent == 255 {
+        return None;
+    }
+
+    Some(Battery {
+        percent: status.BatteryLifePercent,
+        charging: status.ACLineStatus == 1,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+fn battery() -> Option<Battery> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_basics() {
+        let metadata = collect();
+        assert_eq!(metadata.os, std::env::consts::OS);
+        assert_eq!(metadata.arch, std::env::consts::ARCH);
+        assert!(!metadata.hostname.is_empty());
+        assert!(metadata.battery.is_none_or(|b| b.percent <= 100));
+    }
+}
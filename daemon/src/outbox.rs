@@ -0,0 +1,187 @@
+use common::ActionMessage;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Messages for the server that are kept until they were sent, so nothing is lost
+/// while the daemon is disconnected
+#[derive(Clone)]
+pub struct Outbox {
+    queue: Arc<Mutex<Queue>>,
+    ready: Arc<Notify>,
+}
+
+struct Queue {
+    // Numbered, so a message that was replaced while it was being sent is not removed by mistake
+    messages: VecDeque<(u64, ActionMessage)>,
+    next_id: u64,
+    max_size: usize,
+}
+
+// Messages of the same kind replace each other, as only the latest one matters
+fn coalesce_key(message: &ActionMessage) -> Option<&'static str> {
+    match message {
+        ActionMessage::Clipboard(_)
+        | ActionMessage::ExpiringClipboard { .. }
+        | ActionMessage::ClearClipboard => Some("clipboard"),
+        ActionMessage::PauseState(_) => Some("pause_state"),
+        ActionMessage::Capabilities(_) => Some("capabilities"),
+        ActionMessage::Metadata(_) => Some("metadata"),
+        _ => None,
+    }
+}
+
+impl Outbox {
+    pub fn new(max_size: usize) -> Self {
+        Outbox {
+            queue: Arc::new(Mutex::new(Queue {
+                messages: VecDeque::new(),
+                next_id: 0,
+                max_size,
+            })),
+            ready: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn set_max_size(&self, max_size: usize) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.max_size = max_size;
+        queue.truncate();
+    }
+
+    pub fn push(&self, message: ActionMessage) {
+        let mut queue = self.queue.lock().unwrap();
+
+        if let Some(key) = coalesce_key(&message) {
+            queue
+                .messages
+                .retain(|(_, queued)| coalesce_key(queued) != Some(key));
+        }
+
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.messages.push_back((id, message));
+        queue.truncate();
+        drop(queue);
+
+        self.ready.notify_one();
+    }
+
+    /// The oldest message, which stays queued until it is marked as sent
+    pub fn next(&self) -> Option<(u64, ActionMessage)> {
+        self.queue.lock().unwrap().messages.front().cloned()
+    }
+
+    /// The kinds of the queued messages, oldest first
+    pub fn pending_kinds(&self) -> Vec<&'static str> {
+        let queue = self.queue.lock().unwrap();
+        queue.messages.iter().map(|(_, m)| m.kind()).collect()
+    }
+
+    pub fn sent(&self, id: u64) {
+        self.queue
+            .lock()
+            .unwrap()
+            .messages
+            .retain(|(queued, _)| *queued != id);
+    }
+
+    /// Waits until a message was pushed
+    pub async fn wait(&self) {
+        self.ready.notified().await
+    }
+}
+
+impl Queue {
+    fn truncate(&mut self) {
+        while self.messages.len() > self.max_size {
+            if let Some((_, message)) = self.messages.pop_front() {
+                // Not the content, it may be sensitive
+                log::warn!("Outbox is full, dropping {} message", message.kind());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::action::{ActionResult, ActionStatus};
+    use common::ClipboardContent;
+
+    fn result(action: &str) -> ActionMessage {
+        ActionMessage::ActionResult(ActionResult {
+            id: None,
+            action: action.to_string(),
+            status: ActionStatus::Finished,
+            exit_code: Some(0),
+            message: None,
+        })
+    }
+
+    fn drain(outbox: &Outbox) -> Vec<ActionMessage> {
+        let mut messages = Vec::new();
+        while let Some((id, message)) = outbox.next() {
+            outbox.sent(id);
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn coalesces_clipboard() {
+        let outbox = Outbox::new(10);
+        outbox.push(ActionMessage::Clipboard(ClipboardContent::Text(
+            "a".to_string(),
+        )));
+        outbox.push(result("first"));
+        outbox.push(ActionMessage::ClearClipboard);
+        outbox.push(result("second"));
+        outbox.push(ActionMessage::Clipboard(ClipboardContent::Text(
+            "b".to_string(),
+        )));
+
+        assert_eq!(
+            outbox.pending_kinds(),
+            ["action_result", "action_result", "clipboard"]
+        );
+        assert_eq!(
+            drain(&outbox),
+            [
+                result("first"),
+                result("second"),
+                ActionMessage::Clipboard(ClipboardContent::Text("b".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn replaced_while_sending() {
+        let outbox = Outbox::new(10);
+        outbox.push(ActionMessage::Clipboard(ClipboardContent::Text(
+            "a".to_string(),
+        )));
+
+        let (id, _) = outbox.next().unwrap();
+        outbox.push(ActionMessage::Clipboard(ClipboardContent::Text(
+            "b".to_string(),
+        )));
+        outbox.sent(id);
+
+        assert_eq!(
+            drain(&outbox),
+            [ActionMessage::Clipboard(ClipboardContent::Text(
+                "b".to_string()
+            ))]
+        );
+    }
+
+    #[test]
+    fn drops_oldest() {
+        let outbox = Outbox::new(2);
+        for action in ["1", "2", "3"] {
+            outbox.push(result(action));
+        }
+        assert_eq!(drain(&outbox), [result("2"), result("3")]);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::metadata;
+use ::url::Url;
+use common::client_config::ReconnectConfig;
+use common::ipc::ConnectionFailure;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue};
+use tokio_tungstenite::tungstenite::Error;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// How often network interfaces are checked for changes
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delays between connection attempts, growing exponentially with random jitter
+#[derive(Default)]
+pub struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    pub fn next_delay(&mut self, config: &ReconnectConfig) -> Duration {
+        let base = (config.initial_delay * config.multiplier.powi(self.attempts as i32))
+            .min(config.max_delay);
+        self.attempts = self.attempts.saturating_add(1);
+
+        jittered(base, config)
+    }
+
+    // Used when retrying soon is pointless, e.g. because the token is wrong
+    pub fn max_delay(&mut self, config: &ReconnectConfig) -> Duration {
+        self.attempts = self.attempts.saturating_add(1);
+        jittered(config.max_delay, config)
+    }
+}
+
+fn jittered(secs: f64, config: &ReconnectConfig) -> Duration {
+    let factor = 1.0 + config.jitter * (fastrand::f64() * 2.0 - 1.0);
+    Duration::from_secs_f64(secs * factor)
+}
+
+/// Where to connect to and how to authenticate
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub url: Url,
+    // Value of the Authorization header
+    pub authorization: String,
+}
+
+/// A failed connection attempt
+#[derive(Debug)]
+pub struct ConnectError {
+    pub failure: ConnectionFailure,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.failure, self.message)
+    }
+}
+
+fn classify(error: &Error) -> ConnectionFailure {
+    match error {
+        Error::Http(response) => match response.status().as_u16() {
+            401 | 403 => ConnectionFailure::Unauthorized,
+            404 => ConnectionFailure::NotFound,
+            _ => ConnectionFailure::Protocol,
+        },
+        Error::Tls(_) => ConnectionFailure::Tls,
+        Error::Io(_) | Error::ConnectionClosed | Error::AlreadyClosed => ConnectionFailure::Network,
+        _ => ConnectionFailure::Protocol,
+    }
+}
+
+/// Connects to the server, telling apart why it failed
+pub async fn connect(endpoint: &Endpoint) -> Result<Socket, ConnectError> {
+    let url = &endpoint.url;
+    // Resolved separately, as the WebSocket library reports it like any other IO error
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    if let Err(e) = tokio::net::lookup_host((host, port)).await {
+        return Err(ConnectError {
+            failure: ConnectionFailure::Dns,
+            message: e.to_string(),
+        });
+    }
+
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| ConnectError {
+            failure: classify(&e),
+            message: e.to_string(),
+        })?;
+    let authorization =
+        HeaderValue::from_str(&endpoint.authorization).map_err(|_| ConnectError {
+            failure: ConnectionFailure::Unauthorized,
+            message: "the token contains invalid characters".to_string(),
+        })?;
+    request
+        .headers_mut()
+        .insert(header::AUTHORIZATION, authorization);
+
+    match connect_async(request).await {
+        Ok((socket, _)) => Ok(socket),
+        Err(e) => Err(ConnectError {
+            failure: classify(&e),
+            message: e.to_string(),
+        }),
+    }
+}
+
+/// Notifies when the network addresses change or the device wakes up from sleep,
+/// as the connection is most likely broken or the server reachable again
+pub async fn watch_network(hints: Arc<Notify>) {
+    let mut interval = tokio::time::interval(NETWORK_CHECK_INTERVAL);
+    let mut known = None;
+    let mut last_check = SystemTime::now();
+
+    loop {
+        interval.tick().await;
+
+        // The timer doesn't advance while the device is suspended, the clock does
+        let resumed = last_check
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed > NETWORK_CHECK_INTERVAL * 3);
+        last_check = SystemTime::now();
+
+        let Ok((mut addresses, _)) = tokio::task::spawn_blocking(metadata::addresses).await else {
+            continue;
+        };
+        addresses.sort();
+
+        let changed = known.as_ref().is_some_and(|known| *known != addresses);
+        if resumed || changed {
+            log::info!(
+                "{}, reconnecting",
+                if resumed {
+                    "Woke up from sleep"
+                } else {
+                    "Network addresses changed"
+                }
+            );
+            hints.notify_one();
+        }
+        known = Some(addresses);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn backoff() {
+        let config = ReconnectConfig {
+            initial_delay: 1.0,
+            max_delay: 10.0,
+            multiplier: 2.0,
+            jitter: 0.1,
+        };
+        let mut backoff = Backoff::default();
+
+        let delays: Vec<f64> = (0..6)
+            .map(|_| backoff.next_delay(&config).as_secs_f64())
+            .collect();
+        for (delay, expected) in delays.iter().zip([1.0, 2.0, 4.0, 8.0, 10.0, 10.0]) {
+            assert!(
+                (expected * 0.9..=expected * 1.1).contains(delay),
+                "{:?}",
+                delays
+            );
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay(&config) <= Duration::from_secs_f64(1.1));
+    }
+
+    #[tokio::test]
+    async fn classifies_rejected_token() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let read = stream.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..read]).to_lowercase();
+            assert!(
+                request.contains("authorization: bearer token"),
+                "{}",
+                request
+            );
+            let _ = stream
+                .write_all(b"HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\n\r\n")
+                .await;
+        });
+
+        let endpoint = |url: &str| Endpoint {
+            url: Url::parse(url).unwrap(),
+            authorization: "Bearer token".to_string(),
+        };
+        let error = connect(&endpoint(&format!("ws://127.0.0.1:{}/ws", port)))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.failure, ConnectionFailure::Unauthorized);
+        assert!(error.failure.is_config_error());
+
+        let error = connect(&endpoint("ws://mysti.invalid/ws"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.failure, ConnectionFailure::Dns);
+    }
+}
@@ -0,0 +1,355 @@
+use crate::confirm::{self, Decision};
+use anyhow::{Context, Result};
+use common::action::{Action, ActionDefinition, ActionResult, ActionStatus, RunMode};
+use common::client_config::ConfirmationConfig;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+
+// How much of the output of an attached command is reported back
+const MAX_REPORTED_OUTPUT: usize = 1024;
+
+/// Runs actions while enforcing their timeout and concurrency limits.
+/// Every outcome, including refusals, is sent to the results channel.
+#[derive(Clone)]
+pub struct ActionRunner {
+    running: Arc<Mutex<HashMap<String, usize>>>,
+    results: Sender<ActionResult>,
+    confirmation: ConfirmationConfig,
+}
+
+// Counts a running instance of an action until dropped
+struct RunningGuard {
+    running: Arc<Mutex<HashMap<String, usize>>>,
+    name: String,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap();
+        if let Some(count) = running.get_mut(&self.name) {
+            *count -= 1;
+            if *count == 0 {
+                running.remove(&self.name);
+            }
+        }
+    }
+}
+
+impl ActionRunner {
+    pub fn new(results: Sender<ActionResult>, confirmation: ConfirmationConfig) -> Self {
+        Self {
+            running: Arc::new(Mutex::new(HashMap::new())),
+            results,
+            confirmation,
+        }
+    }
+
+    pub fn set_confirmation(&mut self, confirmation: ConfirmationConfig) {
+        self.confirmation = confirmation;
+    }
+
+    fn acquire(&self, definition: &ActionDefinition) -> Option<RunningGuard> {
+        let mut running = self.running.lock().unwrap();
+        let count = running.entry(definition.name.clone()).or_insert(0);
+
+        if let Some(max_concurrent) = definition.max_concurrent {
+            if *count >= max_concurrent {
+                return None;
+            }
+        }
+
+        *count += 1;
+        Some(RunningGuard {
+            running: self.running.clone(),
+            name: definition.name.clone(),
+        })
+    }
+
+    pub async fn report(&self, result: ActionResult) {
+        log::info!("Action {}: {}", result.action, result);
+
+        if let Err(e) = self.results.send(result).await {
+            log::warn!("Failed to report action result: {}", e);
+        }
+    }
+
+    /// Starts the action in the background, asking the user first if it needs confirmation
+    pub async fn run(&self, definition: ActionDefinition, action: Action) {
+        if !definition.confirm {
+            self.start(definition, action).await;
+            return;
+        }
+
+        let runner = self.clone();
+        tokio::spawn(async move {
+            match confirm::ask(&runner.confirmation, &definition, &action).await {
+                Decision::Accepted(reason) => {
+                    log::info!("Action {}: {}", action.action, reason);
+                    runner.start(definition, action).await;
+                }
+                Decision::Denied(reason) => {
+                    runner
+                        .report(ActionResult::new(
+                            &action,
+                            ActionStatus::Refused,
+                            Some(reason),
+                        ))
+                        .await;
+                }
+            }
+        });
+    }
+
+    async fn start(&self, definition: ActionDefinition, action: Action) {
+        let Some(guard) = self.acquire(&definition) else {
+            self.report(ActionResult::new(
+                &action,
+                ActionStatus::Refused,
+                Some(format!(
+                    "already running {} time(s), which is the limit",
+                    definition.max_concurrent.unwrap_or_default()
+                )),
+            ))
+            .await;
+            return;
+        };
+
+        let runner = self.clone();
+        tokio::spawn(async move {
+            let result = match runner.execute(&definition, &action).await {
+                Ok(result) => result,
+                Err(e) => {
+                    ActionResult::new(&action, ActionStatus::Failed, Some(format!("{:#}", e)))
+                }
+            };
+            drop(guard);
+
+            // Detached commands already reported that they started
+            if definition.mode == RunMode::Detached && result.status == ActionStatus::Finished {
+                return;
+            }
+            runner.report(result).await;
+        });
+    }
+
+    async fn execute(
+        &self,
+        definition: &ActionDefinition,
+        action: &Action,
+    ) -> Result<ActionResult> {
+        let mut command = definition.to_command(&action.args)?;
+        // Run in its own process group, so a timeout also kills what the command started
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut command = tokio::process::Command::from(command);
+
+        let attached = definition.mode == RunMode::Attached;
+        command.stdin(if definition.stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        if attached {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+
+        let mut child = command
+            .spawn()
+            .context(format!("failed to run command {}", definition.name))?;
+
+        if !attached {
+            self.report(ActionResult::new(action, ActionStatus::Started, None))
+                .await;
+        }
+
+        // Read the output concurrently, otherwise the command might block on a full pipe
+        let stdout = child.stdout.take().map(|out| tokio::spawn(read_all(out)));
+        let stderr = child.stderr.take().map(|err| tokio::spawn(read_all(err)));
+
+        // Writing stdin counts towards the timeout, the command might never read it
+        let stdin = child.stdin.take();
+        let run = async {
+            if let (Some(content), Some(mut stdin)) = (&definition.stdin, stdin) {
+                stdin
+                    .write_all(content.as_bytes())
+                    .await
+                    .context("failed to write stdin")?;
+                // Dropping stdin closes it, so the command sees EOF
+            }
+            Ok::<_, anyhow::Error>(child.wait().await?)
+        };
+
+        let status = match definition.timeout.map(Duration::from_secs) {
+            Some(timeout) => match tokio::time::timeout(timeout, run).await {
+                Ok(status) => status,
+                Err(_) => {
+                    kill(&mut child).await?;
+                    return Ok(ActionResult::new(
+                        action,
+                        ActionStatus::Killed,
+                        Some(format!("timed out after {}s", timeout.as_secs())),
+                    ));
+                }
+            },
+            None => run.await,
+        };
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                kill(&mut child).await?;
+                return Err(e);
+            }
+        };
+
+        let stdout = match stdout {
+            Some(stdout) => stdout.await?,
+            None => Vec::new(),
+        };
+        let stderr = match stderr {
+            Some(stderr) => stderr.await?,
+            None => Vec::new(),
+        };
+
+        let (status_kind, message) = if status.success() {
+            (ActionStatus::Finished, tail(&stdout))
+        } else {
+            (
+                ActionStatus::Failed,
+                tail(&stderr).or_else(|| tail(&stdout)),
+            )
+        };
+
+        Ok(ActionResult {
+            exit_code: status.code(),
+            ..ActionResult::new(action, status_kind, message)
+        })
+    }
+}
+
+// Kills the command and everything it started
+async fn kill(child: &mut tokio::process::Child) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: the child has not been waited for, so its process group still exists
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+    child.kill().await.context("failed to kill command")
+}
+
+async fn read_all(mut reader: impl AsyncRead + Unpin) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let _ = reader.read_to_end(&mut buf).await;
+    buf
+}
+
+fn tail(output: &[u8]) -> Option<String> {
+    let output = String::from_utf8_lossy(output);
+    let output = output.trim();
+    if output.is_empty() {
+        return None;
+    }
+
+    let start = output
+        .char_indices()
+        .map(|(i, _)| i)
+        .find(|&i| output.len() - i <= MAX_REPORTED_OUTPUT)
+        .unwrap_or(0);
+    Some(output[start..].to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    fn action(name: &str) -> Action {
+        Action {
+            action: name.to_string(),
+            args: Vec::new(),
+            id: Some(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_and_concurrency() {
+        let (results, mut receiver) = channel(10);
+        let runner = ActionRunner::new(results, ConfirmationConfig::default());
+
+        let definition = ActionDefinition {
+            name: "sleep".to_string(),
+            linux: Some("sleep 10".to_string()),
+            macos: Some("sleep 10".to_string()),
+            timeout: Some(1),
+            max_concurrent: Some(1),
+            mode: RunMode::Attached,
+            ..Default::default()
+        };
+
+        runner.run(definition.clone(), action("sleep")).await;
+        runner.run(definition, action("sleep")).await;
+
+        let refused = receiver.recv().await.unwrap();
+        assert_eq!(refused.status, ActionStatus::Refused);
+
+        let killed = receiver.recv().await.unwrap();
+        assert_eq!(killed.status, ActionStatus::Killed);
+        assert_eq!(killed.id, Some(1));
+    }
+
+    #[tokio::test]
+    async fn unread_stdin_times_out() {
+        let (results, mut receiver) = channel(10);
+        let runner = ActionRunner::new(results, ConfirmationConfig::default());
+
+        // The child starts a command in the background, which must be killed as well
+        let command = Some("sh -c \"sleep 10 & wait\"".to_string());
+        let definition = ActionDefinition {
+            name: "ignore-stdin".to_string(),
+            linux: command.clone(),
+            macos: command,
+            // Larger than a pipe buffer, so writing blocks
+            stdin: Some("x".repeat(1024 * 1024)),
+            timeout: Some(1),
+            mode: RunMode::Attached,
+            ..Default::default()
+        };
+
+        runner.run(definition, action("ignore-stdin")).await;
+
+        let result = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.status, ActionStatus::Killed);
+    }
+
+    #[tokio::test]
+    async fn attached_output() {
+        let (results, mut receiver) = channel(10);
+        let runner = ActionRunner::new(results, ConfirmationConfig::default());
+
+        let command = Some("sh -c \"cat; echo $MYSTI_TEST >&2; exit 3\"".to_string());
+        let definition = ActionDefinition {
+            name: "output".to_string(),
+            linux: command.clone(),
+            macos: command,
+            stdin: Some("input ".to_string()),
+            env: [("MYSTI_TEST".to_string(), "env".to_string())].into(),
+            mode: RunMode::Attached,
+            ..Default::default()
+        };
+
+        runner.run(definition, action("output")).await;
+
+        let result = receiver.recv().await.unwrap();
+        assert_eq!(result.status, ActionStatus::Failed);
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.message.as_deref(), Some("env"));
+    }
+}
@@ -0,0 +1,18 @@
+/// Whether the program can be found on the PATH, e.g. before running an optional helper tool
+#[cfg(target_os = "linux")]
+pub fn is_installed(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn installed() {
+        assert!(is_installed("sh"));
+        assert!(!is_installed("mysti-no-such-program"));
+    }
+}
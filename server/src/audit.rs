@@ -0,0 +1,201 @@
+use crate::storage::Storage;
+use anyhow::Result;
+use common::types::AuditEntry;
+use log::{error, info};
+use serde::Deserialize;
+use std::sync::Mutex;
+
+const STORAGE_NAME: &str = "audit";
+
+// The log is rotated at this size, keeping the previous file, so at most twice this is stored
+const MAX_FILE_BYTES: u64 = 5 * 1024 * 1024;
+
+fn default_limit() -> usize {
+    100
+}
+
+/// Outcome of a message that was sent to devices
+pub fn sent_to(devices: usize) -> String {
+    match devices {
+        1 => "sent to 1 device".to_string(),
+        devices => format!("sent to {} devices", devices),
+    }
+}
+
+/// Filters of `GET /audit`
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    // Unix timestamp of the oldest entry
+    pub since: Option<u64>,
+    // Entries caused by or aimed at this device
+    pub device: Option<String>,
+    // The event type, e.g. "action" or "wake_on_lan"
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    // The most recent entries are returned
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.since.is_none_or(|since| entry.time >= since)
+            && self
+                .kind
+                .as_ref()
+                .is_none_or(|kind| entry.event.kind() == kind)
+            && self.device.as_ref().is_none_or(|device| {
+                entry.actor.device.as_ref() == Some(device) || entry.target.as_ref() == Some(device)
+            })
+    }
+}
+
+/// Append-only record of remote actions and clipboard events, stored as JSON lines
+#[derive(Default)]
+pub struct AuditLog {
+    // Nothing is persisted without storage, e.g. in tests
+    storage: Option<Storage>,
+    max_file_bytes: u64,
+    // Held while writing, so no entry is appended to a file that is being rotated
+    writing: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(storage: Storage) -> Self {
+        AuditLog {
+            storage: Some(storage),
+            max_file_bytes: MAX_FILE_BYTES,
+            writing: Mutex::new(()),
+        }
+    }
+
+    pub fn record(&self, entry: &AuditEntry) {
+        info!("Audit: {}", entry);
+
+        let Some(storage) = &self.storage else {
+            return;
+        };
+
+        let _writing = self.writing.lock().unwrap();
+        match storage.rotate_lines(STORAGE_NAME, self.max_file_bytes) {
+            Ok(true) => info!("Rotated the audit log"),
+            Ok(false) => (),
+            Err(e) => error!("Failed to rotate audit log: {:#}", e),
+        }
+        if let Err(e) = storage.append(STORAGE_NAME, entry) {
+            error!("Failed to write audit log: {:#}", e);
+        }
+    }
+
+    /// The most recent entries matching the query, oldest first
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+        let Some(storage) = &self.storage else {
+            return Ok(Vec::new());
+        };
+
+        let load = |name: &str| -> Result<Vec<AuditEntry>> {
+            let mut entries: Vec<AuditEntry> = storage.load_lines(name)?;
+            entries.retain(|entry| query.matches(entry));
+            Ok(entries)
+        };
+
+        // The rotated file is only needed if the current one has too few entries
+        let mut entries = load(STORAGE_NAME)?;
+        if entries.len() < query.limit {
+            let mut older = load(&format!("{}.1", STORAGE_NAME))?;
+            older.append(&mut entries);
+            entries = older;
+        }
+
+        let skip = entries.len().saturating_sub(query.limit);
+        Ok(entries.split_off(skip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::types::{AuditActor, AuditEvent, Credential};
+
+    #[test]
+    fn query() {
+        let dir = std::env::temp_dir().join(format!("mysti-audit-{}", std::process::id()));
+        let log = AuditLog::new(Storage::new(dir.to_str().unwrap()).unwrap());
+        let actor = |device: Option<&str>| AuditActor {
+            credential: if device.is_some() {
+                Credential::Device
+            } else {
+                Credential::Token
+            },
+            device: device.map(str::to_string),
+            ip: Some("1.2.3.4".parse().unwrap()),
+        };
+
+        let shutdown = AuditEntry::new(
+            actor(None),
+            AuditEvent::Action {
+                action: "Shutdown".to_string(),
+                args: Vec::new(),
+                id: Some(1),
+            },
+        )
+        .target("desktop")
+        .outcome(false, "device not connected");
+        log.record(&shutdown);
+        log.record(&AuditEntry::new(
+            actor(Some("laptop")),
+            AuditEvent::Clipboard {
+                kind: "text".to_string(),
+                bytes: 5,
+                ttl: None,
+            },
+        ));
+        log.record(&AuditEntry::new(
+            actor(None),
+            AuditEvent::WakeOnLan {
+                mac: "AA:AA:AA:AA:AA:AA".to_string(),
+            },
+        ));
+
+        let query = |device: Option<&str>, kind: Option<&str>, limit: usize| {
+            log.query(&AuditQuery {
+                since: None,
+                device: device.map(str::to_string),
+                kind: kind.map(str::to_string),
+                limit,
+            })
+            .unwrap()
+        };
+        assert_eq!(query(None, None, 100).len(), 3);
+        assert_eq!(
+            query(Some("desktop"), None, 100),
+            std::slice::from_ref(&shutdown)
+        );
+        assert_eq!(query(Some("laptop"), None, 100).len(), 1);
+        assert_eq!(query(None, Some("wake_on_lan"), 100).len(), 1);
+        let latest = query(None, None, 1);
+        assert_eq!(latest[0].event.kind(), "wake_on_lan");
+
+        // Every entry ends up in a new file, only the previous one is kept
+        let log = AuditLog {
+            max_file_bytes: 1,
+            ..log
+        };
+        log.record(&shutdown);
+        log.record(&shutdown);
+        assert!(dir.join("audit.1.jsonl").exists());
+        let query = |limit| {
+            log.query(&AuditQuery {
+                since: None,
+                device: None,
+                kind: None,
+                limit,
+            })
+            .unwrap()
+        };
+        assert_eq!(query(100).len(), 2);
+        assert_eq!(query(1), [shutdown]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
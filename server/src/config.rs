@@ -1,6 +1,8 @@
 use std::{net::IpAddr, str::FromStr};
 
 use anyhow::{Context, Result};
+use common::sync::SyncRule;
+use ipnet::IpNet;
 use macaddr::MacAddr6;
 use serde::Deserialize;
 
@@ -10,8 +12,202 @@ pub struct Config {
     pub wake_on_lan: WakeOnLanConfig,
     pub token: String,
 
+    // Also accept the token as ?token= query parameter, which ends up in proxy logs.
+    // Deprecated, clients send an Authorization header instead.
+    #[serde(default = "default_allow_query_token")]
+    pub allow_query_token: bool,
+
+    // Where state like scheduled actions is stored
+    #[serde(default = "default_data_dir")]
+    pub data_dir: String,
+
     #[serde(default = "Vec::new", rename = "clipboard_action")]
     pub clipboard_actions: Vec<ClipboardAction>,
+
+    #[serde(default = "Vec::new", rename = "group")]
+    pub groups: Vec<DeviceGroup>,
+
+    #[serde(default = "Vec::new", rename = "sync_rule")]
+    pub sync_rules: Vec<DeviceSyncRule>,
+
+    #[serde(default)]
+    pub connections: ConnectionConfig,
+
+    // Seconds to wait for connections to close and clipboard actions to finish when shutting down
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+
+    // Serves HTTPS and WSS directly if set
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    // Serves Prometheus metrics at GET /metrics if set
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+
+    // Bearer token for reading the audit log at GET /audit, which is off without it
+    #[serde(default)]
+    pub audit_token: Option<String>,
+}
+
+/// Limits against clients guessing the token and devices sending too much.
+/// A limit of 0 turns it off.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // Requests from one IP address per minute
+    pub requests_per_minute: u32,
+    // Failed authentication attempts from one IP address before it is locked out
+    pub max_auth_failures: u32,
+    // Seconds in which failed attempts are counted
+    pub auth_failure_window: u64,
+    // Seconds an IP address stays locked out
+    pub lockout: u64,
+    // Reverse proxies (addresses or networks like "172.16.0.0/12") whose X-Forwarded-For header is trusted
+    pub trusted_proxies: Vec<String>,
+    #[serde(skip)]
+    pub(crate) trusted_networks: Vec<IpNet>,
+    // Clipboard changes and actions one device may send per minute
+    pub clipboard_per_minute: u32,
+    pub actions_per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_minute: 300,
+            max_auth_failures: 10,
+            auth_failure_window: 600,
+            lockout: 900,
+            trusted_proxies: Vec::new(),
+            trusted_networks: Vec::new(),
+            clipboard_per_minute: 120,
+            actions_per_minute: 60,
+        }
+    }
+}
+
+/// Certificate of the server and, for mutual TLS, the CA that signs client certificates
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    // PEM files, reloaded when they change
+    pub cert: String,
+    pub key: String,
+
+    // Clients with a certificate signed by this CA don't need the token
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    // Reject clients without a valid certificate
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MetricsConfig {
+    // Bearer token of the scraper, separate from the token that controls devices
+    pub token: String,
+}
+
+fn default_allow_query_token() -> bool {
+    true
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+/// What happens when a device doesn't read its messages fast enough
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    // Drop messages that don't fit into the queue
+    #[default]
+    Drop,
+    // Close the connection, the daemon reconnects and gets the latest clipboard
+    Disconnect,
+}
+
+/// Heartbeat and send queue settings of WebSocket connections
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    // Seconds between pings sent to every device
+    pub ping_interval: u64,
+    // Connections that sent nothing, not even a pong, for this many seconds are closed
+    pub timeout: u64,
+    // How many messages may wait to be sent to a device
+    pub send_queue: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            ping_interval: 30,
+            timeout: 90,
+            send_queue: 64,
+            overflow: OverflowPolicy::Drop,
+        }
+    }
+}
+
+/// A named set of devices that actions can be sent to at once
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceGroup {
+    pub name: String,
+
+    // Device names as shown by GET /devices
+    #[serde(default)]
+    pub devices: Vec<String>,
+
+    // Devices that report any of these tags are members as well
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl DeviceGroup {
+    pub fn contains(&self, name: &str, tags: &[String]) -> bool {
+        self.devices.iter().any(|d| d == name) || self.tags.iter().any(|t| tags.contains(t))
+    }
+}
+
+/// Clipboard sync rule for the matching devices. If several rules match a device,
+/// earlier rules take precedence for the fields they set.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceSyncRule {
+    // Matches all devices if both devices and tags are empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    #[serde(flatten)]
+    pub rule: SyncRule,
+}
+
+impl DeviceSyncRule {
+    pub fn matches(&self, name: &str, tags: &[String]) -> bool {
+        (self.devices.is_empty() && self.tags.is_empty())
+            || self.devices.iter().any(|d| d == name)
+            || self.tags.iter().any(|t| tags.contains(t))
+    }
+}
+
+impl Config {
+    /// Resolves the sync rule of a device, preferring what the device itself configured
+    pub fn sync_rule(&self, name: &str, tags: &[String], device_rule: SyncRule) -> SyncRule {
+        self.sync_rules
+            .iter()
+            .filter(|r| r.matches(name, tags))
+            .fold(device_rule, |rule, fallback| rule.or(&fallback.rule))
+    }
+}
+
+fn default_data_dir() -> String {
+    "data".to_string()
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -43,6 +239,51 @@ pub fn parse(content: &str) -> Result<Config> {
 
     for action in config.clipboard_actions.iter_mut() {
         action.compiled_regex = Some(regex::Regex::new(&action.regex)?);
+        common::substitution::check_posix_script(&action.command)
+            .context(format!("Clipboard action for {}", action.regex))?;
+    }
+
+    if config.connections.ping_interval == 0 || config.connections.send_queue == 0 {
+        return Err(anyhow::anyhow!(
+            "connections.ping_interval and connections.send_queue must be positive"
+        ));
+    }
+    if config.connections.timeout <= config.connections.ping_interval {
+        return Err(anyhow::anyhow!(
+            "connections.timeout must be longer than connections.ping_interval"
+        ));
+    }
+
+    for proxy in &config.rate_limit.trusted_proxies {
+        let network = match proxy.parse::<IpAddr>() {
+            Ok(addr) => IpNet::from(addr),
+            Err(_) => proxy
+                .parse()
+                .context(format!("Invalid trusted proxy {}", proxy))?,
+        };
+        config.rate_limit.trusted_networks.push(network);
+    }
+
+    if let Some(tls) = &config.tls {
+        if tls.require_client_cert && tls.client_ca.is_none() {
+            return Err(anyhow::anyhow!(
+                "tls.require_client_cert needs tls.client_ca to check client certificates"
+            ));
+        }
+    }
+
+    if config.metrics.as_ref().is_some_and(|m| m.token.is_empty()) {
+        return Err(anyhow::anyhow!("metrics.token must not be empty"));
+    }
+    if config.audit_token.as_ref().is_some_and(|t| t.is_empty()) {
+        return Err(anyhow::anyhow!("audit_token must not be empty"));
+    }
+
+    let mut group_names = std::collections::HashSet::new();
+    for group in &config.groups {
+        if !group_names.insert(&group.name) {
+            return Err(anyhow::anyhow!("Duplicate group name {}", group.name));
+        }
     }
 
     Ok(config)
@@ -57,6 +298,13 @@ impl std::fmt::Display for ParseableMacAddr {
     }
 }
 
+impl ParseableMacAddr {
+    /// Whether the address equals a MAC address in any common notation
+    pub fn matches(&self, mac: &str) -> bool {
+        MacAddr6::from_str(mac).is_ok_and(|mac| mac == self.0)
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for ParseableMacAddr {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -110,6 +358,107 @@ mod tests {
         assert_config(config_str);
     }
 
+    #[test]
+    fn parse_connections() {
+        let config = parse(
+            r#"
+    web_port = 9138
+    token = "some_token"
+
+    [wake_on_lan]
+    target_addr = "AA:AA:AA:AA:AA:AA"
+
+    [connections]
+    ping_interval = 10
+    overflow = "disconnect"
+    "#,
+        )
+        .unwrap();
+        assert_eq!(config.connections.ping_interval, 10);
+        assert_eq!(config.connections.timeout, 90);
+        assert_eq!(config.connections.overflow, OverflowPolicy::Disconnect);
+
+        assert!(parse(
+            r#"
+    web_port = 9138
+    token = "some_token"
+
+    [wake_on_lan]
+    target_addr = "AA:AA:AA:AA:AA:AA"
+
+    [connections]
+    ping_interval = 120
+    "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_groups() {
+        let config = parse(
+            r#"
+    web_port = 9138
+    token = "some_token"
+
+    [wake_on_lan]
+    target_addr = "AA:AA:AA:AA:AA:AA"
+
+    [[group]]
+    name = "lab"
+    devices = ["pc1"]
+    tags = ["lab"]
+    "#,
+        )
+        .unwrap();
+
+        let group = &config.groups[0];
+        assert!(group.contains("pc1", &[]));
+        assert!(group.contains("pc2", &["office".to_string(), "lab".to_string()]));
+        assert!(!group.contains("pc2", &["office".to_string()]));
+    }
+
+    #[test]
+    fn parse_sync_rules() {
+        let config = parse(
+            r#"
+    web_port = 9138
+    token = "some_token"
+
+    [wake_on_lan]
+    target_addr = "AA:AA:AA:AA:AA:AA"
+
+    [[sync_rule]]
+    devices = ["kiosk"]
+    send = false
+
+    [[sync_rule]]
+    content = ["text"]
+    max_image_size = 1000
+    "#,
+        )
+        .unwrap();
+
+        let kiosk = config.sync_rule("kiosk", &[], SyncRule::default());
+        assert!(!kiosk.sends());
+        assert!(kiosk.receives());
+        assert_eq!(kiosk.max_image_size, Some(1000));
+
+        // The device configuration wins
+        let kiosk = config.sync_rule(
+            "kiosk",
+            &[],
+            SyncRule {
+                send: Some(true),
+                ..Default::default()
+            },
+        );
+        assert!(kiosk.sends());
+
+        let pc = config.sync_rule("pc", &[], SyncRule::default());
+        assert!(pc.sends());
+        assert!(!pc.allows(&common::ClipboardContent::Image(Vec::new())));
+    }
+
     #[test]
     fn parse_cfg_different_mac_format() {
         let config_str = r#"
@@ -1,18 +1,51 @@
-use common::types::ConnectedClientInfo;
-use common::{ActionMessage, ClipboardContent};
-use log::{debug, error, info};
+use common::action::{ActionInfo, ActionResult};
+use common::sync::{PauseState, SyncRule};
+use common::types::{
+    AuditEntry, ConnectedClientInfo, DeviceActionResult, DeviceEvent, DeviceMetadata,
+    RateLimitStats,
+};
+use common::{ActionMessage, Capabilities, ClipboardContent};
+use log::{debug, error, info, warn};
 
-use std::collections::HashMap;
-use std::sync::atomic::AtomicUsize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use tokio::sync::mpsc::UnboundedSender;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, watch};
+
+use crate::audit::{AuditLog, AuditQuery};
+use crate::config::OverflowPolicy;
+use crate::metrics::metrics;
+use crate::rate_limit::{self, DeviceLimit, Limiter};
+use crate::registry::Registry;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+
+/// The sending side of a WebSocket connection
+#[derive(Clone)]
+pub struct ConnectionChannel {
+    pub messages: Sender<ActionMessage>,
+    // Notified when the connection should be closed because its queue is full
+    pub close: Arc<Notify>,
+}
 
 pub struct ConnectionInfo {
     name: String,
     pub connected_at: std::time::SystemTime,
-    channel: UnboundedSender<ActionMessage>,
-    supported_actions: Vec<(String, usize)>,
+    channel: ConnectionChannel,
+    supported_actions: Vec<ActionInfo>,
+    tags: Vec<String>,
+    // The rule the device configured for itself, and the one resolved with the server config
+    device_sync: SyncRule,
+    sync: SyncRule,
+    paused: Option<PauseState>,
+    os: Option<String>,
+    version: Option<String>,
+    metadata: Option<DeviceMetadata>,
+    ip: Option<IpAddr>,
 }
 
 pub struct Manager {
@@ -20,85 +53,478 @@ pub struct Manager {
     counter: AtomicUsize,
     pub(crate) config: crate::config::Config,
 
-    pub last_clipboard_content: RwLock<ClipboardContent>,
+    last_clipboard_content: RwLock<ClipboardContent>,
+    // When the last clipboard content expires, if it was sent with a TTL
+    last_clipboard_expiry: RwLock<Option<SystemTime>>,
+    // Sync groups of the device the last clipboard content came from, None if it was set via HTTP
+    last_clipboard_groups: RwLock<Option<Vec<String>>>,
 
     // In case we got a message while nobody was connected, we save it here - unless it's clipboard related
     last_message: RwLock<Option<ActionMessage>>,
+
+    action_counter: AtomicU64,
+    // The most recent results reported by clients, oldest first
+    action_results: RwLock<VecDeque<DeviceActionResult>>,
+
+    // Observers of GET /devices/events
+    device_events: broadcast::Sender<DeviceEvent>,
+    // Devices that were connected at some point, to list them while they are offline
+    registry: RwLock<Registry>,
+
+    // Set when the server shuts down, which ends WebSocket connections and event streams
+    shutdown: watch::Sender<bool>,
+    // How many clipboard action commands are running
+    running_actions: Arc<AtomicUsize>,
+
+    limiter: Limiter,
+    audit: AuditLog,
 }
 
+// How many action results are kept for GET /actions/{id}
+const MAX_ACTION_RESULTS: usize = 256;
+// How many device events a slow observer may fall behind
+const DEVICE_EVENT_BUFFER: usize = 64;
+
 impl Manager {
     // Create a new ConnectionManager.
-    pub fn new(config: crate::config::Config) -> Self {
+    pub fn new(config: crate::config::Config, registry: Registry, audit: AuditLog) -> Self {
         Manager {
-            config: config,
+            config,
             connections: Arc::new(RwLock::new(HashMap::new())),
             counter: AtomicUsize::new(0),
             last_clipboard_content: RwLock::new(ClipboardContent::Text("".to_string())),
+            last_clipboard_expiry: RwLock::new(None),
+            last_clipboard_groups: RwLock::new(None),
             last_message: RwLock::new(None),
+            action_counter: AtomicU64::new(1),
+            action_results: RwLock::new(VecDeque::new()),
+            device_events: broadcast::channel(DEVICE_EVENT_BUFFER).0,
+            registry: RwLock::new(registry),
+            shutdown: watch::channel(false).0,
+            running_actions: Arc::new(AtomicUsize::new(0)),
+            limiter: Limiter::default(),
+            audit,
         }
     }
 
     // Add a new WebSocket connection to the manager.
     pub fn add_connection(
         &self,
-        tx: &UnboundedSender<ActionMessage>,
-        name: &String,
-        supported_actions: Vec<(String, usize)>,
+        tx: &ConnectionChannel,
+        name: &str,
+        supported_actions: Vec<ActionInfo>,
+        tags: Vec<String>,
+        sync: SyncRule,
+        ip: Option<IpAddr>,
     ) -> usize {
         let id = self
             .counter
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
+        let device_sync = sync;
+        let sync = self.config.sync_rule(name, &tags, device_sync.clone());
+        if !sync.is_empty() {
+            debug!("Clipboard sync rule for {}: {:?}", name, sync);
+        }
+
         let mut connections = self.connections.write().unwrap();
         connections.insert(
             id,
             ConnectionInfo {
                 connected_at: std::time::SystemTime::now(),
-                name: name.clone(),
+                name: name.to_string(),
                 channel: tx.clone(),
                 supported_actions,
+                tags,
+                device_sync,
+                sync,
+                paused: None,
+                os: None,
+                version: None,
+                metadata: None,
+                ip,
             },
         );
+        drop(connections);
+        self.notify(id, DeviceEvent::Connected);
 
         if let Some(last_message) = self.last_message.read().unwrap().clone() {
-            tx.send(last_message).unwrap();
+            let _ = tx.messages.try_send(last_message);
         }
 
         id
     }
 
+    /// Closes all WebSocket connections and event streams
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn subscribe_shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    pub fn running_actions(&self) -> usize {
+        self.running_actions
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
     pub fn client_count(&self) -> usize {
         self.connections.read().unwrap().len()
     }
 
-    pub fn list_clients(&self) -> Vec<ConnectedClientInfo> {
+    fn wakeable(&self, metadata: Option<&DeviceMetadata>) -> bool {
+        let target = &self.config.wake_on_lan.target_addr;
+        metadata.is_some_and(|m| m.macs.iter().any(|mac| target.matches(mac)))
+    }
+
+    fn client_info(&self, id: usize, info: &ConnectionInfo) -> ConnectedClientInfo {
+        let earlier_uptime = self
+            .registry
+            .read()
+            .unwrap()
+            .get(&info.name)
+            .map(|device| device.uptime)
+            .unwrap_or_default();
+        let uptime = earlier_uptime
+            + SystemTime::now()
+                .duration_since(info.connected_at)
+                .unwrap_or_default();
+
+        ConnectedClientInfo {
+            name: info.name.clone(),
+            id,
+            connected_at: info.connected_at,
+            supported_actions: info.supported_actions.clone(),
+            tags: info.tags.clone(),
+            paused: info.paused.clone(),
+            os: info.os.clone(),
+            version: info.version.clone(),
+            metadata: info.metadata.clone(),
+            online: true,
+            last_seen: None,
+            last_ip: info.ip,
+            disconnect_reason: None,
+            uptime_secs: uptime.as_secs(),
+            wakeable: self.wakeable(info.metadata.as_ref()),
+        }
+    }
+
+    /// The connected devices and, if requested, the known devices that are offline
+    pub fn list_clients(&self, include_offline: bool) -> Vec<ConnectedClientInfo> {
         let connections = self.connections.read().unwrap();
 
+        let mut clients: Vec<_> = connections
+            .iter()
+            .map(|(&id, info)| self.client_info(id, info))
+            .collect();
+
+        if include_offline {
+            let registry = self.registry.read().unwrap();
+            let offline = registry
+                .devices()
+                .filter(|device| !clients.iter().any(|c| c.name == device.info.name))
+                .map(|device| ConnectedClientInfo {
+                    online: false,
+                    paused: None,
+                    last_seen: Some(device.last_seen),
+                    last_ip: device.last_ip,
+                    disconnect_reason: device.disconnect_reason.clone(),
+                    uptime_secs: device.uptime.as_secs(),
+                    wakeable: self.wakeable(device.info.metadata.as_ref()),
+                    ..device.info.clone()
+                })
+                .collect::<Vec<_>>();
+            clients.extend(offline);
+        }
+
+        clients
+    }
+
+    pub fn subscribe_device_events(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.device_events.subscribe()
+    }
+
+    // Remembers the current state of the connection and tells observers about it
+    fn notify(&self, id: usize, event: fn(ConnectedClientInfo) -> DeviceEvent) {
+        let Some((info, ip)) = self
+            .connections
+            .read()
+            .unwrap()
+            .get(&id)
+            .map(|info| (self.client_info(id, info), info.ip))
+        else {
+            return;
+        };
+
+        self.registry.write().unwrap().update(info.clone(), ip);
+
+        // Sending only fails if nobody is observing
+        let _ = self.device_events.send(event(info));
+    }
+
+    /// Returns the IDs and names of all connections in the group. If there is no group
+    /// with that name in the config, it is treated as a tag.
+    pub fn group_members(&self, group: &str) -> Vec<(usize, String)> {
+        let definition = self.config.groups.iter().find(|g| g.name == group);
+
+        let connections = self.connections.read().unwrap();
         connections
             .iter()
-            .map(|(&id, info)| ConnectedClientInfo {
-                name: info.name.clone(),
-                id,
-                connected_at: info.connected_at,
-                supported_actions: info.supported_actions.clone(),
+            .filter(|(_, info)| match definition {
+                Some(definition) => definition.contains(&info.name, &info.tags),
+                None => info.tags.iter().any(|t| t == group),
             })
+            .map(|(&id, info)| (id, info.name.clone()))
+            .collect()
+    }
+
+    // Assign a unique ID to an action, so results can be matched to it
+    pub fn next_action_id(&self) -> u64 {
+        self.action_counter
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    }
+
+    pub fn record_action_result(&self, id: usize, result: ActionResult) {
+        let device_name = match self.connections.read().unwrap().get(&id) {
+            Some(info) => info.name.clone(),
+            None => format!("client {}", id),
+        };
+
+        info!("Client {} reported action result: {}", id, result);
+
+        let mut results = self.action_results.write().unwrap();
+        if results.len() >= MAX_ACTION_RESULTS {
+            results.pop_front();
+        }
+        results.push_back(DeviceActionResult {
+            device_id: id,
+            device_name,
+            received_at: std::time::SystemTime::now(),
+            result,
+        });
+    }
+
+    pub fn action_results(&self, action_id: u64) -> Vec<DeviceActionResult> {
+        self.action_results
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|r| r.result.id == Some(action_id))
+            .cloned()
             .collect()
     }
 
+    /// The last clipboard content, or empty text if it expired
+    pub fn clipboard(&self) -> ClipboardContent {
+        let expired = self
+            .last_clipboard_expiry
+            .read()
+            .unwrap()
+            .is_some_and(|expiry| expiry <= SystemTime::now());
+        if expired {
+            debug!("Clipboard content expired");
+            self.clear_clipboard();
+        }
+
+        self.last_clipboard_content.read().unwrap().clone()
+    }
+
+    fn clear_clipboard(&self) {
+        *self.last_clipboard_content.write().unwrap() = ClipboardContent::Text("".to_string());
+        *self.last_clipboard_expiry.write().unwrap() = None;
+    }
+
+    /// The message with the last clipboard content, if the sync rules allow sending it to the connection
+    pub fn clipboard_for(&self, id: usize) -> Option<ActionMessage> {
+        let content = self.clipboard();
+
+        let connections = self.connections.read().unwrap();
+        let sync = &connections.get(&id)?.sync;
+
+        let shared = match &*self.last_clipboard_groups.read().unwrap() {
+            Some(groups) => sync.shares_with(groups),
+            None => true,
+        };
+        if !shared || !sync.receives() || !sync.allows(&content) {
+            return None;
+        }
+
+        // The remaining time is passed on, so the new device clears it at the same time
+        Some(match *self.last_clipboard_expiry.read().unwrap() {
+            Some(expiry) => ActionMessage::ExpiringClipboard {
+                content,
+                ttl: expiry
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .as_secs()
+                    .max(1),
+            },
+            None => ActionMessage::Clipboard(content),
+        })
+    }
+
     // Remove a WebSocket connection from the manager.
-    pub fn remove_connection(&self, id: usize) {
-        let mut connections = self.connections.write().unwrap();
-        connections.remove(&id);
+    pub fn remove_connection(&self, id: usize, reason: String) {
+        let Some(info) = self.connections.write().unwrap().remove(&id) else {
+            return;
+        };
+
+        self.registry
+            .write()
+            .unwrap()
+            .disconnected(&info.name, info.connected_at, reason);
+        let _ = self.device_events.send(DeviceEvent::Disconnected { id });
+    }
+
+    /// The address a request came from, see [`rate_limit::client_ip`]
+    pub fn client_ip(&self, remote: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        rate_limit::client_ip(&self.config.rate_limit, remote, forwarded_for)
+    }
+
+    /// Whether a request may be handled, otherwise the seconds until the client may retry
+    pub fn check_request(&self, ip: IpAddr) -> Result<(), u64> {
+        self.limiter.check_request(&self.config.rate_limit, ip)
+    }
+
+    pub fn auth_failed(&self, ip: IpAddr) {
+        self.limiter.auth_failed(&self.config.rate_limit, ip)
     }
 
-    pub fn send_to_specific(&self, id: usize, message: &ActionMessage) {
+    /// Whether a device may send this message, or has sent too many of its kind recently
+    pub fn allow_message(&self, id: usize, message: &ActionMessage) -> bool {
+        let limit = match message {
+            ActionMessage::Clipboard(_)
+            | ActionMessage::ExpiringClipboard { .. }
+            | ActionMessage::ClearClipboard => DeviceLimit::Clipboard,
+            ActionMessage::Action(_) => DeviceLimit::Actions,
+            _ => return true,
+        };
+
         let connections = self.connections.read().unwrap();
+        let Some(info) = connections.get(&id) else {
+            return true;
+        };
+        self.limiter
+            .allow_device(&self.config.rate_limit, &info.name, limit)
+    }
 
-        if let Some(tx) = connections.get(&id) {
-            let _ = tx.channel.send(message.clone());
+    pub fn rate_limit_stats(&self) -> RateLimitStats {
+        self.limiter.stats()
+    }
+
+    pub fn audit(&self, entry: AuditEntry) {
+        self.audit.record(&entry);
+    }
+
+    pub fn query_audit(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEntry>> {
+        self.audit.query(query)
+    }
+
+    /// The name of a connected device
+    pub fn device_name(&self, id: usize) -> Option<String> {
+        let connections = self.connections.read().unwrap();
+        connections.get(&id).map(|info| info.name.clone())
+    }
+
+    /// Replaces the configuration, e.g. after the config file changed
+    pub fn set_config(&mut self, config: crate::config::Config) {
+        self.config = config;
+
+        // Sync rules of the server may have changed
+        for info in self.connections.write().unwrap().values_mut() {
+            info.sync = self
+                .config
+                .sync_rule(&info.name, &info.tags, info.device_sync.clone());
+        }
+    }
+
+    /// Updates what a connected device offers, e.g. after its configuration changed
+    pub fn update_capabilities(&self, id: usize, capabilities: Capabilities) {
+        {
+            let mut connections = self.connections.write().unwrap();
+            let Some(info) = connections.get_mut(&id) else {
+                return;
+            };
+
+            debug!(
+                "Client {} supports {} actions",
+                id,
+                capabilities.supported_actions.len()
+            );
+            info.supported_actions = capabilities.supported_actions;
+            info.tags = capabilities.tags;
+            info.device_sync = capabilities.sync;
+            info.sync = self
+                .config
+                .sync_rule(&info.name, &info.tags, info.device_sync.clone());
+            info.os = capabilities.os;
+            info.version = capabilities.version;
+        }
+
+        self.notify(id, DeviceEvent::Updated);
+    }
+
+    pub fn set_metadata(&self, id: usize, metadata: DeviceMetadata) {
+        if let Some(info) = self.connections.write().unwrap().get_mut(&id) {
+            info.metadata = Some(metadata);
+        }
+
+        self.notify(id, DeviceEvent::Updated);
+    }
+
+    pub fn set_pause_state(&self, id: usize, paused: Option<PauseState>) {
+        if let Some(info) = self.connections.write().unwrap().get_mut(&id) {
+            match &paused {
+                Some(paused) => info!("Client {} {} clipboard sync", id, paused),
+                None => info!("Client {} resumed clipboard sync", id),
+            }
+            info.paused = paused;
+        }
+
+        self.notify(id, DeviceEvent::Updated);
+    }
+
+    // Queue a message for the connection, applying the overflow policy if the queue is full
+    fn deliver(&self, id: usize, info: &ConnectionInfo, message: &ActionMessage) -> bool {
+        match info.channel.messages.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                match self.config.connections.overflow {
+                    OverflowPolicy::Drop => {
+                        warn!("Send queue of client {} is full, dropping message", id)
+                    }
+                    OverflowPolicy::Disconnect => {
+                        warn!("Send queue of client {} is full, disconnecting", id);
+                        info.channel.close.notify_one();
+                    }
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    // Send a message to the connection, returning whether it was queued
+    pub fn send_to_specific(&self, id: usize, message: &ActionMessage) -> bool {
+        let connections = self.connections.read().unwrap();
+
+        match connections.get(&id) {
+            Some(info) => self.deliver(id, info, message),
+            None => false,
         }
     }
 
+    // Send a message to all connections of the device with the given name, returning how many got it
+    pub fn send_to_name(&self, name: &str, message: &ActionMessage) -> usize {
+        let connections = self.connections.read().unwrap();
+
+        connections
+            .iter()
+            .filter(|(&id, info)| info.name == name && self.deliver(id, info, message))
+            .count()
+    }
+
     fn clipboard_action(&mut self, text: &str) {
         for action in self.config.clipboard_actions.iter() {
             let (matches, args) = action.matches(text);
@@ -111,78 +537,244 @@ impl Manager {
                 let args = args.clone();
 
                 // Spawn thread in background, but don't wait for it to finish
+                let running = self.running_actions.clone();
+                running.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
                 thread::spawn(move || {
                     if let Err(e) = action.run(args) {
                         error!("Error running action: {}", e);
                     }
+                    running.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
                 });
             }
         }
     }
 
-    fn custom_message_action(&mut self, message: &ActionMessage) {
+    fn custom_message_action(&mut self, message: &ActionMessage, sender_sync: Option<&SyncRule>) {
         // Sometimes we have custom logic for certain messages.
-        match &message {
-            ActionMessage::Clipboard(content) => {
-                {
-                    let mut last_clipboard_content = self.last_clipboard_content.write().unwrap();
-
-                    // if equal content, stop
-                    if *last_clipboard_content == content.clone() {
-                        return;
+        if let ActionMessage::ClearClipboard = message {
+            info!("Clearing clipboard");
+            self.clear_clipboard();
+            return;
+        }
+
+        if let Some(content) = message.clipboard_content() {
+            {
+                let current_content = self.clipboard();
+
+                // Content sent with a TTL expires even if it didn't change
+                *self.last_clipboard_expiry.write().unwrap() = match message {
+                    ActionMessage::ExpiringClipboard { ttl, .. } => {
+                        SystemTime::now().checked_add(Duration::from_secs(*ttl))
                     }
+                    _ => None,
+                };
 
-                    *last_clipboard_content = content.clone();
+                // if equal content, stop
+                if current_content == *content {
+                    return;
                 }
 
-                debug!("Received clipboard content");
+                *self.last_clipboard_content.write().unwrap() = content.clone();
+                *self.last_clipboard_groups.write().unwrap() =
+                    sender_sync.map(SyncRule::sync_groups);
+            }
 
-                // If the clipboard content is text, then we should run the clipboard actions.
-                if let ClipboardContent::Text(text) = content {
-                    self.clipboard_action(text);
-                }
+            debug!("Received clipboard content");
+
+            // If the clipboard content is text, then we should run the clipboard actions.
+            if let ClipboardContent::Text(text) = content {
+                self.clipboard_action(text);
             }
-            _ => (),
         }
     }
 
     // Broadcast a message to all WebSocket connections, except for the sender if given.
-    pub fn broadcast(&mut self, message: &ActionMessage, sender: Option<usize>) {
-        self.custom_message_action(message);
+    // Returns how many devices it was queued for.
+    pub fn broadcast(&mut self, message: &ActionMessage, sender: Option<usize>) -> usize {
+        let sender_sync = sender.and_then(|id| {
+            let connections = self.connections.read().unwrap();
+            connections.get(&id).map(|info| info.sync.clone())
+        });
+
+        if let (Some(content), Some(sync)) = (message.clipboard_content(), &sender_sync) {
+            if !sync.sends() || !sync.allows(content) {
+                debug!(
+                    "Sync rules of client {:?} don't allow sending {:?}",
+                    sender, content
+                );
+                return 0;
+            }
+        }
+
+        self.custom_message_action(message, sender_sync.as_ref());
 
         let connections = self.connections.read().unwrap();
 
         if connections.is_empty() {
             // Save the message for later
             match message {
-                ActionMessage::Clipboard(_) => (),
+                ActionMessage::Clipboard(_)
+                | ActionMessage::ExpiringClipboard { .. }
+                | ActionMessage::ClearClipboard => (),
                 _ => {
                     let mut last_message = self.last_message.write().unwrap();
                     *last_message = Some(message.clone());
                 }
             }
 
-            return;
+            return 0;
         }
 
+        let mut delivered = 0;
         info!(
             "Broadcasting message{} to {} other clients: {:?}",
-            if sender.is_some() {
-                " by client ".to_string() + &sender.unwrap().to_string()
-            } else {
-                "".to_string()
+            match sender {
+                Some(sender) => " by client ".to_string() + &sender.to_string(),
+                None => "".to_string(),
             },
             connections.len().max(1) - if sender.is_some() { 1 } else { 0 },
             message,
         );
 
-        for (_, tx) in connections.iter().filter(|(&id, _)| {
-            if let Some(sender_id) = sender {
-                return id != sender_id;
+        for (&id, info) in connections.iter().filter(|(&id, info)| {
+            if sender == Some(id) {
+                return false;
+            }
+
+            // Clipboard content is only sent where the sync rules allow it
+            match message.clipboard_content() {
+                Some(content) => {
+                    info.sync.receives()
+                        && info.sync.allows(content)
+                        && sender_sync
+                            .as_ref()
+                            .is_none_or(|sync| info.sync.shares_with(&sync.sync_groups()))
+                }
+                None => true,
             }
-            true
         }) {
-            let _ = tx.channel.send(message.clone());
+            if self.deliver(id, info, message) {
+                delivered += 1;
+            }
+        }
+
+        if delivered > 0 {
+            metrics()
+                .messages_broadcast
+                .with_label_values(&[message.kind()])
+                .inc();
+        }
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_events() {
+        let config = crate::config::parse(
+            r#"
+            web_port = 9138
+            token = "token"
+
+            [wake_on_lan]
+            target_addr = "AA:AA:AA:AA:AA:AA"
+            "#,
+        )
+        .unwrap();
+        let manager = Manager::new(config, Registry::default(), AuditLog::default());
+        let mut events = manager.subscribe_device_events();
+
+        let (messages, _rx) = tokio::sync::mpsc::channel(8);
+        let tx = ConnectionChannel {
+            messages,
+            close: Arc::new(Notify::new()),
+        };
+        let id = manager.add_connection(
+            &tx,
+            "laptop",
+            Vec::new(),
+            Vec::new(),
+            SyncRule::default(),
+            None,
+        );
+        assert!(matches!(events.try_recv(), Ok(DeviceEvent::Connected(info)) if info.id == id));
+
+        manager.update_capabilities(
+            id,
+            Capabilities {
+                supported_actions: Vec::new(),
+                tags: vec!["lab".to_string()],
+                sync: SyncRule::default(),
+                os: Some("Linux".to_string()),
+                version: None,
+            },
+        );
+        match events.try_recv() {
+            Ok(DeviceEvent::Updated(info)) => {
+                assert_eq!(info.tags, ["lab"]);
+                assert_eq!(info.os.as_deref(), Some("Linux"));
+            }
+            other => panic!("expected update, got {:?}", other),
+        }
+        assert_eq!(manager.group_members("lab").len(), 1);
+
+        manager.remove_connection(id, "closed".to_string());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(DeviceEvent::Disconnected { id: removed }) if removed == id
+        ));
+
+        assert!(manager.list_clients(false).is_empty());
+        let offline = manager.list_clients(true);
+        assert_eq!(offline.len(), 1);
+        assert!(!offline[0].online);
+        assert_eq!(offline[0].tags, ["lab"]);
+        assert_eq!(offline[0].disconnect_reason.as_deref(), Some("closed"));
+    }
+
+    #[tokio::test]
+    async fn full_send_queue() {
+        for (policy, closes) in [("drop", false), ("disconnect", true)] {
+            let config = crate::config::parse(&format!(
+                r#"
+                web_port = 9138
+                token = "token"
+
+                [wake_on_lan]
+                target_addr = "AA:AA:AA:AA:AA:AA"
+
+                [connections]
+                overflow = "{}"
+                "#,
+                policy
+            ))
+            .unwrap();
+            let manager = Manager::new(config, Registry::default(), AuditLog::default());
+
+            let (messages, mut rx) = tokio::sync::mpsc::channel(1);
+            let tx = ConnectionChannel {
+                messages,
+                close: Arc::new(Notify::new()),
+            };
+            let id = manager.add_connection(
+                &tx,
+                "laptop",
+                Vec::new(),
+                Vec::new(),
+                SyncRule::default(),
+                None,
+            );
+
+            assert!(manager.send_to_specific(id, &ActionMessage::ClearClipboard));
+            assert!(!manager.send_to_specific(id, &ActionMessage::ResumeSync));
+            assert!(matches!(rx.try_recv(), Ok(ActionMessage::ClearClipboard)));
+            assert!(rx.try_recv().is_err());
+
+            let closed = tokio::time::timeout(Duration::from_millis(10), tx.close.notified());
+            assert_eq!(closed.await.is_ok(), closes, "policy {}", policy);
         }
     }
 }
@@ -6,24 +6,68 @@ use log::info;
 use web_server::start_web_server;
 
 mod connection;
+mod metrics;
+mod rate_limit;
+mod registry;
+mod reload;
+mod scheduler;
 mod server_action;
+mod shutdown;
+mod storage;
+mod tls;
 mod websocket;
 
+mod audit;
 mod config;
 use config::parse_file;
+use registry::Registry;
+use scheduler::Scheduler;
+use storage::Storage;
+
+const CONFIG_FILE: &str = "config.toml";
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let config = parse_file("config.toml").expect("Failed to parse config file");
+    let config = parse_file(CONFIG_FILE).expect("Failed to parse config file");
 
     info!(
         "Loaded config with {} clipboard actions",
         config.clipboard_actions.len()
     );
 
-    let server_data = Arc::new(RwLock::new(Manager::new(config.clone())));
+    let storage = Storage::new(&config.data_dir).expect("Failed to open data directory");
+
+    let registry = Registry::new(storage.clone()).expect("Failed to load known devices");
+    let audit = audit::AuditLog::new(storage.clone());
+    let server_data = Arc::new(RwLock::new(Manager::new(config.clone(), registry, audit)));
+
+    let scheduler = Arc::new(Scheduler::new(storage).expect("Failed to load schedules"));
+    tokio::spawn(scheduler.clone().run(server_data.clone()));
+    tokio::spawn(reload::watch_config(
+        CONFIG_FILE.to_string(),
+        server_data.clone(),
+    ));
+
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|tls| tls::TlsServer::new(tls).expect("Failed to load TLS certificate"));
+
+    // The web server stops accepting connections once this is sent
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let web_server = {
+        let manager = server_data.clone();
+        tokio::spawn(async move {
+            start_web_server(&config, manager, scheduler, tls, async {
+                let _ = stopped.await;
+            })
+            .await
+        })
+    };
 
-    start_web_server(&config, server_data).await;
+    shutdown::signal().await;
+    let _ = stop.send(());
+    shutdown::shutdown(server_data, web_server).await;
 }
@@ -0,0 +1,153 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The server's metrics. They are counted even if `GET /metrics` is not enabled.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    pub connected_clients: IntGauge,
+    pub messages_broadcast: IntCounterVec,
+    pub bytes_relayed: IntCounter,
+    pub image_conversion_failures: IntCounter,
+    pub clipboard_action_runs: IntCounterVec,
+    pub clipboard_action_failures: IntCounterVec,
+    pub clipboard_action_duration: HistogramVec,
+    pub auth_failures: IntCounter,
+    pub websocket_errors: IntCounter,
+    pub wake_on_lan_sent: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("mysti".to_string()), None).expect("Invalid metrics prefix");
+
+        // The names are fixed, so registering can only fail on a typo
+        fn register<T: prometheus::core::Collector + Clone + 'static>(
+            registry: &Registry,
+            metric: prometheus::Result<T>,
+        ) -> T {
+            let metric = metric.expect("Invalid metric");
+            registry
+                .register(Box::new(metric.clone()))
+                .expect("Failed to register metric");
+            metric
+        }
+
+        let action_duration = HistogramOpts::new(
+            "clipboard_action_duration_seconds",
+            "How long clipboard actions ran",
+        )
+        .buckets(vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0]);
+
+        Metrics {
+            connected_clients: register(
+                &registry,
+                IntGauge::new("connected_clients", "Currently connected devices"),
+            ),
+            messages_broadcast: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "messages_broadcast_total",
+                        "Messages broadcast to at least one device",
+                    ),
+                    &["type"],
+                ),
+            ),
+            bytes_relayed: register(
+                &registry,
+                IntCounter::new(
+                    "bytes_relayed_total",
+                    "Bytes sent to devices over WebSockets",
+                ),
+            ),
+            image_conversion_failures: register(
+                &registry,
+                IntCounter::new(
+                    "image_conversion_failures_total",
+                    "Clipboard images that could not be converted to JPEG",
+                ),
+            ),
+            clipboard_action_runs: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("clipboard_action_runs_total", "Clipboard actions started"),
+                    &["regex"],
+                ),
+            ),
+            clipboard_action_failures: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "clipboard_action_failures_total",
+                        "Clipboard actions that failed",
+                    ),
+                    &["regex"],
+                ),
+            ),
+            clipboard_action_duration: register(
+                &registry,
+                HistogramVec::new(action_duration, &["regex"]),
+            ),
+            auth_failures: register(
+                &registry,
+                IntCounter::new("auth_failures_total", "Requests with a wrong token"),
+            ),
+            websocket_errors: register(
+                &registry,
+                IntCounter::new(
+                    "websocket_errors_total",
+                    "Errors receiving, sending or converting WebSocket messages",
+                ),
+            ),
+            wake_on_lan_sent: register(
+                &registry,
+                IntCounter::new("wake_on_lan_sent_total", "Wake on LAN packets sent"),
+            ),
+            registry,
+        }
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_else(|e| {
+                log::error!("Failed to encode metrics: {}", e);
+                String::new()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        metrics()
+            .messages_broadcast
+            .with_label_values(&["clipboard"])
+            .inc();
+        metrics()
+            .clipboard_action_duration
+            .with_label_values(&["^https://"])
+            .observe(0.2);
+
+        let text = metrics().encode();
+        assert!(text.contains("mysti_messages_broadcast_total{type=\"clipboard\"}"));
+        assert!(text.contains(
+            "mysti_clipboard_action_duration_seconds_bucket{regex=\"^https://\",le=\"0.5\"}"
+        ));
+        assert!(text.contains("# TYPE mysti_auth_failures_total counter"));
+    }
+}
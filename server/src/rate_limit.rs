@@ -0,0 +1,409 @@
+use crate::config::RateLimitConfig;
+use common::types::RateLimitStats;
+use log::warn;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Beyond this many addresses, entries that don't matter anymore are removed
+const MAX_TRACKED: usize = 4096;
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Allows `per_minute` events within a minute, refilling continuously
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Set while events are rejected, so that is only logged once
+    throttled: bool,
+}
+
+impl Bucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        Bucket {
+            tokens: per_minute as f64,
+            updated: now,
+            throttled: false,
+        }
+    }
+
+    fn take(&mut self, per_minute: u32, now: Instant) -> bool {
+        let capacity = per_minute as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity / MINUTE.as_secs_f64()).min(capacity);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.throttled = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Seconds until the next event is allowed
+    fn retry_after(&self, per_minute: u32) -> u64 {
+        ((1.0 - self.tokens) * MINUTE.as_secs_f64() / per_minute as f64).ceil() as u64
+    }
+}
+
+struct Failures {
+    count: u32,
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// What a device sends that is limited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceLimit {
+    Clipboard,
+    Actions,
+}
+
+/// Per-IP request limits, lockouts after failed authentication and per-device message limits
+#[derive(Default)]
+pub struct Limiter {
+    requests: Mutex<HashMap<IpAddr, Bucket>>,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    // By device name, so reconnecting doesn't reset the limit
+    devices: Mutex<HashMap<(String, DeviceLimit), Bucket>>,
+
+    rejected_requests: AtomicU64,
+    auth_failures: AtomicU64,
+    lockouts: AtomicU64,
+    dropped_clipboard: AtomicU64,
+    dropped_actions: AtomicU64,
+
+    // Set once the warning about a missing trusted proxy was logged
+    proxy_warned: AtomicBool,
+}
+
+// Addresses a reverse proxy on the same host or network connects from
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private(),
+        IpAddr::V6(ip) => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+impl Limiter {
+    /// Whether a request from this address may be handled, otherwise the seconds until it may retry
+    pub fn check_request(&self, config: &RateLimitConfig, ip: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+
+        if let Some(until) = self.locked_until(ip, now) {
+            self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+            return Err(until.saturating_duration_since(now).as_secs().max(1));
+        }
+        if config.requests_per_minute == 0 {
+            return Ok(());
+        }
+
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() >= MAX_TRACKED {
+            // Idle for a minute means the bucket is full again
+            requests.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < MINUTE);
+        }
+
+        let bucket = requests
+            .entry(ip)
+            .or_insert_with(|| Bucket::new(config.requests_per_minute, now));
+        if bucket.take(config.requests_per_minute, now) {
+            return Ok(());
+        }
+
+        self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+        if !bucket.throttled {
+            bucket.throttled = true;
+            warn!("Too many requests from {}, rejecting them for now", ip);
+        }
+        Err(bucket.retry_after(config.requests_per_minute))
+    }
+
+    /// Records a request with a wrong token, locking the address out after too many
+    pub fn auth_failed(&self, config: &RateLimitConfig, ip: IpAddr) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+        if config.max_auth_failures == 0 {
+            return;
+        }
+
+        // Without trusted_proxies, all requests through a local reverse proxy share its
+        // address, and locking it out would lock out every device
+        if config.trusted_networks.is_empty() && is_local(ip) {
+            if !self.proxy_warned.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Not locking out the local address {} after failed authentication; if it is a reverse proxy, add it to rate_limit.trusted_proxies",
+                    ip
+                );
+            }
+            return;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_secs(config.auth_failure_window);
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED {
+            failures.retain(|_, failures| {
+                failures.locked_until.is_some_and(|until| until > now)
+                    || now.saturating_duration_since(failures.since) < window
+            });
+        }
+
+        let entry = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            since: now,
+            locked_until: None,
+        });
+        if now.saturating_duration_since(entry.since) >= window {
+            entry.count = 0;
+            entry.since = now;
+        }
+
+        entry.count += 1;
+        if entry.count >= config.max_auth_failures {
+            warn!(
+                "Locking out {} for {}s after {} failed authentication attempts",
+                ip, config.lockout, entry.count
+            );
+            entry.count = 0;
+            entry.since = now;
+            entry.locked_until = Some(now + Duration::from_secs(config.lockout));
+            self.lockouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn locked_until(&self, ip: IpAddr, now: Instant) -> Option<Instant> {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .and_then(|failures| failures.locked_until)
+            .filter(|until| *until > now)
+    }
+
+    /// Whether a device may send another message of this kind
+    pub fn allow_device(&self, config: &RateLimitConfig, device: &str, limit: DeviceLimit) -> bool {
+        let per_minute = match limit {
+            DeviceLimit::Clipboard => config.clipboard_per_minute,
+            DeviceLimit::Actions => config.actions_per_minute,
+        };
+        if per_minute == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut devices = self.devices.lock().unwrap();
+        if devices.len() >= MAX_TRACKED {
+            devices.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < MINUTE);
+        }
+
+        let bucket = devices
+            .entry((device.to_string(), limit))
+            .or_insert_with(|| Bucket::new(per_minute, now));
+        if bucket.take(per_minute, now) {
+            return true;
+        }
+
+        match limit {
+            DeviceLimit::Clipboard => &self.dropped_clipboard,
+            DeviceLimit::Actions => &self.dropped_actions,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        if !bucket.throttled {
+            bucket.throttled = true;
+            warn!(
+                "{} sends more than {} {:?} messages per minute, dropping them",
+                device, per_minute, limit
+            );
+        }
+        false
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        let now = Instant::now();
+        RateLimitStats {
+            rejected_requests: self.rejected_requests.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            lockouts: self.lockouts.load(Ordering::Relaxed),
+            locked_out: self
+                .failures
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|failures| failures.locked_until.is_some_and(|until| until > now))
+                .count(),
+            dropped_clipboard: self.dropped_clipboard.load(Ordering::Relaxed),
+            dropped_actions: self.dropped_actions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The address of the client. Requests that came through a trusted proxy are attributed
+/// to the address in `X-Forwarded-For` the proxies got them from.
+pub fn client_ip(
+    config: &RateLimitConfig,
+    remote: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+) -> Option<IpAddr> {
+    let remote = remote?;
+    let trusted = |ip: &IpAddr| config.trusted_networks.iter().any(|net| net.contains(ip));
+    let Some(forwarded_for) = forwarded_for.filter(|_| trusted(&remote)) else {
+        return Some(remote);
+    };
+
+    // Every proxy appends the address it got the request from, so the client is the
+    // last address that isn't a trusted proxy; anything before it could be made up
+    let mut client = remote;
+    for addr in forwarded_for.rsplit(',') {
+        let Ok(addr) = addr.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = addr;
+        if !trusted(&addr) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> RateLimitConfig {
+        crate::config::parse(&format!(
+            r#"
+            web_port = 9138
+            token = "token"
+
+            [wake_on_lan]
+            target_addr = "AA:AA:AA:AA:AA:AA"
+
+            [rate_limit]
+            {}
+            "#,
+            extra
+        ))
+        .unwrap()
+        .rate_limit
+    }
+
+    #[test]
+    fn forwarded_for() {
+        let config = config(r#"trusted_proxies = ["10.0.0.1", "172.16.0.0/12"]"#);
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        // Only trusted proxies may set the header
+        assert_eq!(
+            client_ip(&config, ip("1.2.3.4"), Some("5.6.7.8")),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(&config, ip("10.0.0.1"), Some("5.6.7.8")),
+            ip("5.6.7.8")
+        );
+        assert_eq!(client_ip(&config, ip("10.0.0.1"), None), ip("10.0.0.1"));
+        // The client can't hide behind an address it made up
+        assert_eq!(
+            client_ip(
+                &config,
+                ip("10.0.0.1"),
+                Some("9.9.9.9, 5.6.7.8, 172.18.0.3")
+            ),
+            ip("5.6.7.8")
+        );
+        assert_eq!(
+            client_ip(&config, ip("10.0.0.1"), Some("garbage")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn locks_out_after_failures() {
+        let config = config("max_auth_failures = 3\nrequests_per_minute = 0");
+        let limiter = Limiter::default();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let other: IpAddr = "5.6.7.8".parse().unwrap();
+
+        for _ in 0..2 {
+            limiter.auth_failed(&config, ip);
+        }
+        assert!(limiter.check_request(&config, ip).is_ok());
+
+        limiter.auth_failed(&config, ip);
+        assert!(limiter.check_request(&config, ip).unwrap_err() > 800);
+        assert!(limiter.check_request(&config, other).is_ok());
+
+        let stats = limiter.stats();
+        assert_eq!(
+            (stats.auth_failures, stats.lockouts, stats.locked_out),
+            (3, 1, 1)
+        );
+        assert_eq!(stats.rejected_requests, 1);
+    }
+
+    #[test]
+    fn limits_requests_and_devices() {
+        let config = config("requests_per_minute = 5\nclipboard_per_minute = 2");
+        let limiter = Limiter::default();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+
+        for _ in 0..5 {
+            assert!(limiter.check_request(&config, ip).is_ok());
+        }
+        assert_eq!(limiter.check_request(&config, ip), Err(12));
+
+        assert!(limiter.allow_device(&config, "laptop", DeviceLimit::Clipboard));
+        assert!(limiter.allow_device(&config, "laptop", DeviceLimit::Clipboard));
+        assert!(!limiter.allow_device(&config, "laptop", DeviceLimit::Clipboard));
+        assert!(limiter.allow_device(&config, "laptop", DeviceLimit::Actions));
+        assert!(limiter.allow_device(&config, "phone", DeviceLimit::Clipboard));
+        assert_eq!(limiter.stats().dropped_clipboard, 1);
+    }
+
+    #[test]
+    fn prunes_idle_devices() {
+        let config = config("");
+        let limiter = Limiter::default();
+
+        let idle = Instant::now() - 2 * MINUTE;
+        limiter
+            .devices
+            .lock()
+            .unwrap()
+            .extend((0..MAX_TRACKED).map(|i| {
+                (
+                    (format!("device-{}", i), DeviceLimit::Clipboard),
+                    Bucket::new(1, idle),
+                )
+            }));
+
+        assert!(limiter.allow_device(&config, "laptop", DeviceLimit::Clipboard));
+        assert_eq!(limiter.devices.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn local_proxy_is_not_locked_out() {
+        let config = config("max_auth_failures = 1");
+        let limiter = Limiter::default();
+
+        // Probably a reverse proxy that isn't in trusted_proxies
+        for ip in ["127.0.0.1", "192.168.1.2", "::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            limiter.auth_failed(&config, ip);
+            assert!(limiter.check_request(&config, ip).is_ok());
+        }
+
+        let public: IpAddr = "1.2.3.4".parse().unwrap();
+        limiter.auth_failed(&config, public);
+        assert!(limiter.check_request(&config, public).is_err());
+
+        // Once proxies are configured, local clients are locked out like any other
+        let config = self::config("max_auth_failures = 1\ntrusted_proxies = [\"10.0.0.1\"]");
+        let local: IpAddr = "192.168.1.2".parse().unwrap();
+        limiter.auth_failed(&config, local);
+        assert!(limiter.check_request(&config, local).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use common::types::ConnectedClientInfo;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Storage;
+
+const STORAGE_NAME: &str = "devices";
+
+/// What the server remembers about a device after it disconnected
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownDevice {
+    // As reported during the last connection
+    pub info: ConnectedClientInfo,
+    pub last_seen: SystemTime,
+    pub last_ip: Option<IpAddr>,
+    pub disconnect_reason: Option<String>,
+    // Time connected in all finished connections
+    pub uptime: Duration,
+}
+
+/// Devices that were connected at some point, by name
+#[derive(Default)]
+pub struct Registry {
+    // Nothing is persisted without storage, e.g. in tests
+    storage: Option<Storage>,
+    devices: BTreeMap<String, KnownDevice>,
+}
+
+impl Registry {
+    pub fn new(storage: Storage) -> Result<Self> {
+        let devices: BTreeMap<String, KnownDevice> = storage.load(STORAGE_NAME)?;
+
+        info!("Loaded {} known devices", devices.len());
+
+        Ok(Registry {
+            storage: Some(storage),
+            devices,
+        })
+    }
+
+    fn save(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+
+        if let Err(e) = storage.save(STORAGE_NAME, &self.devices) {
+            error!("Failed to save known devices: {:#}", e);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&KnownDevice> {
+        self.devices.get(name)
+    }
+
+    /// Remembers the current state of a connected device
+    pub fn update(&mut self, info: ConnectedClientInfo, ip: Option<IpAddr>) {
+        let name = info.name.clone();
+        let device = self.devices.entry(name).or_insert_with(|| KnownDevice {
+            info: info.clone(),
+            last_seen: SystemTime::now(),
+            last_ip: None,
+            disconnect_reason: None,
+            uptime: Duration::ZERO,
+        });
+
+        device.info = info;
+        device.last_seen = SystemTime::now();
+        device.last_ip = ip.or(device.last_ip);
+        device.disconnect_reason = None;
+
+        self.save();
+    }
+
+    pub fn disconnected(&mut self, name: &str, connected_at: SystemTime, reason: String) {
+        let Some(device) = self.devices.get_mut(name) else {
+            return;
+        };
+
+        let now = SystemTime::now();
+        device.uptime += now.duration_since(connected_at).unwrap_or_default();
+        device.last_seen = now;
+        device.disconnect_reason = Some(reason);
+
+        self.save();
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &KnownDevice> {
+        self.devices.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(name: &str) -> ConnectedClientInfo {
+        ConnectedClientInfo {
+            name: name.to_string(),
+            id: 0,
+            connected_at: SystemTime::now() - Duration::from_secs(60),
+            supported_actions: Vec::new(),
+            tags: Vec::new(),
+            paused: None,
+            os: None,
+            version: None,
+            metadata: None,
+            online: true,
+            last_seen: None,
+            last_ip: None,
+            disconnect_reason: None,
+            uptime_secs: 0,
+            wakeable: false,
+        }
+    }
+
+    #[test]
+    fn remembers_devices() {
+        let dir = std::env::temp_dir().join(format!("mysti-registry-{}", std::process::id()));
+        let storage = Storage::new(dir.to_str().unwrap()).unwrap();
+
+        let mut registry = Registry::new(storage.clone()).unwrap();
+        let laptop = info("laptop");
+        let connected_at = laptop.connected_at;
+        registry.update(laptop, Some("10.0.0.2".parse().unwrap()));
+        registry.update(info("laptop"), None);
+        registry.disconnected("laptop", connected_at, "connection reset".to_string());
+
+        let registry = Registry::new(storage).unwrap();
+        let device = registry.get("laptop").unwrap();
+        assert_eq!(device.last_ip, Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(
+            device.disconnect_reason.as_deref(),
+            Some("connection reset")
+        );
+        assert!(device.uptime >= Duration::from_secs(60));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
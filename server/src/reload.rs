@@ -0,0 +1,74 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use common::watch::FileWatcher;
+use log::{error, info, warn};
+
+use crate::config::parse_file;
+use crate::connection::Manager;
+
+// How often the config file is checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reloads the config file whenever it changes or the server receives SIGHUP.
+/// If the new config is invalid, the previous one stays active.
+pub async fn watch_config(path: String, manager: Arc<RwLock<Manager>>) {
+    let mut watcher = FileWatcher::new(&path);
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to listen for SIGHUP");
+
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = interval.tick() => {
+                if !watcher.changed() {
+                    continue;
+                }
+            }
+            _ = hangup.recv() => info!("Received SIGHUP"),
+        }
+
+        #[cfg(not(unix))]
+        {
+            interval.tick().await;
+            if !watcher.changed() {
+                continue;
+            }
+        }
+
+        reload(&path, &manager);
+    }
+}
+
+fn reload(path: &str, manager: &RwLock<Manager>) {
+    let config = match parse_file(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(
+                "Failed to reload {}, keeping the previous config: {:#}",
+                path, e
+            );
+            return;
+        }
+    };
+
+    let mut manager = manager.write().unwrap();
+
+    // These are only read on startup
+    let current = &manager.config;
+    if config.web_port != current.web_port
+        || config.token != current.token
+        || config.allow_query_token != current.allow_query_token
+        || config.data_dir != current.data_dir
+        || config.tls != current.tls
+        || config.metrics != current.metrics
+    {
+        warn!("Changes of web_port, token, allow_query_token, data_dir, tls and metrics only take effect after a restart");
+    }
+
+    manager.set_config(config);
+    info!("Reloaded {}", path);
+}
@@ -0,0 +1,394 @@
+use crate::audit;
+use crate::connection::Manager;
+use crate::storage::Storage;
+use anyhow::{Context, Result};
+use chrono::{Local, TimeZone};
+use common::types::{
+    AuditActor, AuditEntry, AuditEvent, Credential, ScheduleRequest, ScheduledAction,
+};
+use common::ActionMessage;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const STORAGE_NAME: &str = "schedules";
+
+// Schedules that are due longer than this, e.g. because the server was down, are skipped
+// instead of running at an unexpected time
+const MISSED_GRACE_SECS: u64 = 5 * 60;
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    next_id: u64,
+    schedules: Vec<ScheduledAction>,
+}
+
+/// Runs actions at given times, either once or repeatedly. Schedules survive restarts.
+pub struct Scheduler {
+    storage: Storage,
+    state: Mutex<State>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn parse_cron(expression: &str) -> Result<cron::Schedule> {
+    // Allow the common 5-field format, the cron crate also expects seconds
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+
+    cron::Schedule::from_str(&expression).context(format!("Invalid cron expression {}", expression))
+}
+
+/// The next time the cron expression matches after the given unix timestamp
+fn next_cron_run(expression: &str, after: u64) -> Result<u64> {
+    let after = Local
+        .timestamp_opt(after as i64, 0)
+        .single()
+        .context("Invalid timestamp")?;
+
+    parse_cron(expression)?
+        .after(&after)
+        .next()
+        .map(|time| time.timestamp() as u64)
+        .context(format!("Cron expression {} never matches", expression))
+}
+
+impl Scheduler {
+    pub fn new(storage: Storage) -> Result<Self> {
+        let state: State = storage.load(STORAGE_NAME)?;
+
+        info!("Loaded {} scheduled actions", state.schedules.len());
+
+        Ok(Scheduler {
+            storage,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn save(&self, state: &State) {
+        if let Err(e) = self.storage.save(STORAGE_NAME, state) {
+            error!("Failed to save schedules: {:#}", e);
+        }
+    }
+
+    pub fn list(&self) -> Vec<ScheduledAction> {
+        self.state.lock().unwrap().schedules.clone()
+    }
+
+    pub fn create(&self, request: ScheduleRequest) -> Result<ScheduledAction> {
+        let now = now();
+        let next_run = match (&request.cron, request.at, request.delay_secs) {
+            (Some(cron), None, None) => next_cron_run(cron, now)?,
+            // It would run right away or be skipped as missed
+            (None, Some(at), None) if at < now => {
+                return Err(anyhow::anyhow!(
+                    "at is {}s in the past, the server time is {}",
+                    now - at,
+                    now
+                ))
+            }
+            (None, Some(at), None) => at,
+            (None, None, Some(delay)) => {
+                now.checked_add(delay).context("delay_secs is too large")?
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Exactly one of cron, at and delay_secs must be given"
+                ))
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+
+        let schedule = ScheduledAction {
+            id: state.next_id,
+            action: request.action,
+            target: request.target,
+            cron: request.cron,
+            next_run,
+        };
+        info!(
+            "Scheduled action {} as #{}, next run at {}",
+            schedule.action, schedule.id, schedule.next_run
+        );
+
+        state.schedules.push(schedule.clone());
+        self.save(&state);
+
+        Ok(schedule)
+    }
+
+    /// Removes the schedule, returning whether it existed
+    pub fn cancel(&self, id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let count = state.schedules.len();
+        state.schedules.retain(|s| s.id != id);
+        if state.schedules.len() == count {
+            return false;
+        }
+
+        info!("Cancelled scheduled action #{}", id);
+        self.save(&state);
+        true
+    }
+
+    /// Removes all schedules that are due, returning the ones to run and the ones that were
+    /// missed. Repeating schedules are re-added with their next run time.
+    fn take_due(&self, time: u64) -> (Vec<ScheduledAction>, Vec<ScheduledAction>) {
+        let mut state = self.state.lock().unwrap();
+
+        let (due, mut pending): (Vec<_>, Vec<_>) =
+            state.schedules.drain(..).partition(|s| s.next_run <= time);
+
+        for schedule in &due {
+            let Some(cron) = &schedule.cron else {
+                continue;
+            };
+
+            match next_cron_run(cron, time) {
+                Ok(next_run) => pending.push(ScheduledAction {
+                    next_run,
+                    ..schedule.clone()
+                }),
+                Err(e) => warn!("Dropping schedule #{}: {:#}", schedule.id, e),
+            }
+        }
+
+        state.schedules = pending;
+        if !due.is_empty() {
+            self.save(&state);
+        }
+
+        due.into_iter()
+            .partition(|s| s.next_run.saturating_add(MISSED_GRACE_SECS) >= time)
+    }
+
+    fn missed(schedule: ScheduledAction, manager: &Arc<RwLock<Manager>>) {
+        warn!(
+            "Skipping scheduled action #{}, it was due at {}",
+            schedule.id, schedule.next_run
+        );
+
+        let mut entry = AuditEntry::new(
+            AuditActor {
+                credential: Credential::Scheduler,
+                device: None,
+                ip: None,
+            },
+            AuditEvent::Action {
+                action: schedule.action.action,
+                args: schedule.action.args,
+                id: None,
+            },
+        );
+        if let Some(target) = schedule.target {
+            entry = entry.target(target);
+        }
+
+        manager
+            .read()
+            .unwrap()
+            .audit(entry.outcome(false, format!("schedule #{}, missed", schedule.id)));
+    }
+
+    fn dispatch(schedule: ScheduledAction, manager: &Arc<RwLock<Manager>>) {
+        let mut manager = manager.write().unwrap();
+
+        let mut action = schedule.action;
+        action.id = Some(manager.next_action_id());
+
+        info!("Running scheduled action #{}: {}", schedule.id, action);
+
+        let mut entry = AuditEntry::new(
+            AuditActor {
+                credential: Credential::Scheduler,
+                device: None,
+                ip: None,
+            },
+            AuditEvent::Action {
+                action: action.action.clone(),
+                args: action.args.clone(),
+                id: action.id,
+            },
+        );
+
+        let sent = match &schedule.target {
+            Some(target) => {
+                entry = entry.target(target);
+                let sent = manager.send_to_name(target, &ActionMessage::Action(action));
+                if sent == 0 {
+                    warn!(
+                        "Scheduled action #{} targets {}, which is not connected",
+                        schedule.id, target
+                    );
+                }
+                sent
+            }
+            None => manager.broadcast(&ActionMessage::Action(action), None),
+        };
+
+        manager.audit(entry.outcome(
+            sent > 0,
+            format!("schedule #{}, {}", schedule.id, audit::sent_to(sent)),
+        ));
+    }
+
+    pub async fn run(self: Arc<Self>, manager: Arc<RwLock<Manager>>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            interval.tick().await;
+
+            let (due, missed) = self.take_due(now());
+            for schedule in missed {
+                Self::missed(schedule, &manager);
+            }
+            for schedule in due {
+                Self::dispatch(schedule, &manager);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::action::Action;
+
+    fn scheduler(name: &str) -> (Scheduler, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("mysti-{}-{}", name, std::process::id()));
+        let storage = Storage::new(dir.to_str().unwrap()).unwrap();
+        (Scheduler::new(storage).unwrap(), dir)
+    }
+
+    fn request() -> ScheduleRequest {
+        ScheduleRequest {
+            action: Action {
+                action: "Shutdown".to_string(),
+                args: Vec::new(),
+                id: None,
+            },
+            target: Some("office-pc".to_string()),
+            cron: None,
+            at: None,
+            delay_secs: None,
+        }
+    }
+
+    #[test]
+    fn one_shot_and_cron() {
+        let (scheduler, dir) = scheduler("scheduler");
+
+        let once = scheduler
+            .create(ScheduleRequest {
+                delay_secs: Some(60),
+                ..request()
+            })
+            .unwrap();
+        let repeating = scheduler
+            .create(ScheduleRequest {
+                cron: Some("0 23 * * Mon-Fri".to_string()),
+                ..request()
+            })
+            .unwrap();
+
+        assert!(scheduler
+            .create(ScheduleRequest {
+                cron: Some("0 23 * * Mon-Fri".to_string()),
+                delay_secs: Some(1),
+                ..request()
+            })
+            .is_err());
+        assert!(scheduler
+            .create(ScheduleRequest {
+                cron: Some("not a cron expression".to_string()),
+                ..request()
+            })
+            .is_err());
+
+        assert!(scheduler
+            .create(ScheduleRequest {
+                delay_secs: Some(u64::MAX),
+                ..request()
+            })
+            .is_err());
+        assert!(scheduler
+            .create(ScheduleRequest {
+                at: Some(now() - 10),
+                ..request()
+            })
+            .is_err());
+        let later = scheduler
+            .create(ScheduleRequest {
+                at: Some(now() + 3600),
+                ..request()
+            })
+            .unwrap();
+        assert!(scheduler.cancel(later.id));
+
+        let (due, missed) = scheduler.take_due(once.next_run + 60);
+        assert_eq!((due.len(), missed.len()), (1, 0));
+        assert_eq!(due[0].id, once.id);
+
+        let remaining = scheduler.list();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, repeating.id);
+        assert_eq!(remaining[0].next_run, repeating.next_run);
+
+        // Schedules are persisted
+        let storage = Storage::new(dir.to_str().unwrap()).unwrap();
+        let reloaded = Scheduler::new(storage).unwrap();
+        assert_eq!(reloaded.list().len(), 1);
+
+        assert!(!reloaded.cancel(once.id));
+        assert!(reloaded.cancel(repeating.id));
+        assert!(reloaded.list().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_missed_runs() {
+        let (scheduler, dir) = scheduler("scheduler-missed");
+
+        let once = scheduler
+            .create(ScheduleRequest {
+                delay_secs: Some(60),
+                ..request()
+            })
+            .unwrap();
+        let repeating = scheduler
+            .create(ScheduleRequest {
+                cron: Some("0 23 * * Mon-Fri".to_string()),
+                ..request()
+            })
+            .unwrap();
+
+        // The server was down for a week
+        let later = now() + 7 * 24 * 60 * 60;
+        let (due, missed) = scheduler.take_due(later);
+        assert!(due.is_empty());
+        assert_eq!(missed.len(), 2);
+
+        // The one-shot schedule is gone, the repeating one runs next time instead of now
+        let remaining = scheduler.list();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, repeating.id);
+        assert!(remaining[0].next_run > later);
+        assert!(!scheduler.cancel(once.id));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,7 +1,9 @@
 use anyhow::Context;
+use common::substitution;
 use log::info;
 
 use crate::config::ClipboardAction;
+use crate::metrics::metrics;
 
 impl ClipboardAction {
     pub fn matches(&self, clipboard: &str) -> (bool, Vec<String>) {
@@ -24,12 +26,32 @@ impl ClipboardAction {
     }
 
     pub fn run(&self, args: Vec<String>) -> anyhow::Result<()> {
-        let mut command = self.command.clone();
-
-        for (i, arg) in args.iter().enumerate() {
-            command = command.replace(&format!("${}", i + 1), arg);
-            command = command.replace(&format!("%{}", i + 1), arg);
+        let labels = [self.regex.as_str()];
+        metrics()
+            .clipboard_action_runs
+            .with_label_values(&labels)
+            .inc();
+
+        let timer = metrics()
+            .clipboard_action_duration
+            .with_label_values(&labels)
+            .start_timer();
+        let result = self.execute(args);
+        timer.observe_duration();
+
+        if result.is_err() {
+            metrics()
+                .clipboard_action_failures
+                .with_label_values(&labels)
+                .inc();
         }
+        result
+    }
+
+    fn execute(&self, args: Vec<String>) -> anyhow::Result<()> {
+        // The captures come from the clipboard, so they must be quoted for bash
+        let command = substitution::posix_script(&self.command, &args)
+            .context("Failed to substitute clipboard content into command")?;
 
         // get default $PATH
         let mut command_path = std::env::var("PATH").unwrap_or_default();
@@ -41,7 +63,7 @@ impl ClipboardAction {
         ) {
             let mut new_paths = Vec::new();
 
-            for dir in hostpath.split(":") {
+            for dir in hostpath.split(':') {
                 new_paths.push(format!("{}{}", hostmount, dir));
             }
 
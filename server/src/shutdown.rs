@@ -0,0 +1,71 @@
+use crate::connection::Manager;
+use log::{info, warn};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Resolves on Ctrl-C or SIGTERM
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("Failed to listen for SIGTERM");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Checks the condition until it holds or the deadline is reached, returning whether it held
+async fn wait_for(deadline: Instant, mut done: impl FnMut() -> bool) -> bool {
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
+
+/// Closes all connections and waits for running work, after the web server stopped accepting connections
+pub async fn shutdown(manager: Arc<RwLock<Manager>>, web_server: JoinHandle<()>) {
+    let timeout = Duration::from_secs(manager.read().unwrap().config.shutdown_timeout);
+    let deadline = Instant::now() + timeout;
+
+    info!("Shutting down, closing connections");
+    manager.read().unwrap().shutdown();
+
+    // Devices are marked offline in the registry as they disconnect
+    if !wait_for(deadline, || manager.read().unwrap().client_count() == 0).await {
+        warn!(
+            "{} connections did not close in time",
+            manager.read().unwrap().client_count()
+        );
+    }
+
+    let running = manager.read().unwrap().running_actions();
+    if running > 0 {
+        info!("Waiting for {} clipboard actions to finish", running);
+    }
+    if !wait_for(deadline, || manager.read().unwrap().running_actions() == 0).await {
+        warn!(
+            "{} clipboard actions are still running, not waiting for them",
+            manager.read().unwrap().running_actions()
+        );
+    }
+
+    if tokio::time::timeout_at(deadline, web_server).await.is_err() {
+        warn!("Some requests did not finish in time");
+    }
+
+    info!("Shutdown complete");
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// Persists server state as JSON files in the data directory
+#[derive(Debug, Clone)]
+pub struct Storage {
+    dir: PathBuf,
+}
+
+impl Storage {
+    pub fn new(dir: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!("Failed to create data directory {}", dir))?;
+
+        Ok(Storage {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    /// Loads the value stored under the name, or the default if nothing was stored yet
+    pub fn load<T: DeserializeOwned + Default>(&self, name: &str) -> Result<T> {
+        let path = self.path(name);
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .context(format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(e).context(format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        let path = self.path(name);
+        let content = serde_json::to_string_pretty(value)?;
+
+        // Write to a temporary file first, so a crash never leaves a half-written file
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, content)
+            .context(format!("Failed to write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &path).context(format!("Failed to write {}", path.display()))
+    }
+
+    fn lines_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", name))
+    }
+
+    /// Appends the value as a line of JSON, for logs that are only ever added to
+    pub fn append<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        let path = self.lines_path(name);
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(format!("Failed to open {}", path.display()))?;
+        // A single write, so lines of concurrent writers don't get mixed up
+        file.write_all(&line)
+            .context(format!("Failed to write {}", path.display()))
+    }
+
+    /// Moves the lines appended under the name to `<name>.1` once they take up `max_bytes`,
+    /// replacing the lines moved there before. Returns whether they were moved.
+    pub fn rotate_lines(&self, name: &str, max_bytes: u64) -> Result<bool> {
+        let path = self.lines_path(name);
+        match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.len() >= max_bytes => (),
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+        }
+
+        let rotated = self.lines_path(&format!("{}.1", name));
+        std::fs::rename(&path, &rotated).context(format!("Failed to rotate {}", path.display()))?;
+        Ok(true)
+    }
+
+    /// Reads all lines appended under the name, skipping ones that can't be parsed
+    pub fn load_lines<T: DeserializeOwned>(&self, name: &str) -> Result<Vec<T>> {
+        let path = self.lines_path(name);
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+        };
+
+        let mut values = Vec::new();
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line.context(format!("Failed to read {}", path.display()))?;
+            // E.g. a line that was cut off by a crash
+            match serde_json::from_str(&line) {
+                Ok(value) => values.push(value),
+                Err(e) => log::warn!("Skipping invalid line in {}: {}", path.display(), e),
+            }
+        }
+        Ok(values)
+    }
+}
@@ -0,0 +1,441 @@
+use crate::config::TlsConfig;
+use anyhow::{Context, Result};
+use common::watch::FileWatcher;
+use log::{error, info, warn};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ResolvesServerCert,
+};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use warp::hyper::server::accept;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::{Body, Request};
+use warp::Filter;
+
+// How often the certificate files are checked for changes
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// Clients that don't finish the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a request came from, as `warp::addr::remote` doesn't know it for TLS connections
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Set on requests of clients that presented a certificate signed by the configured CA
+#[derive(Debug, Clone, Copy)]
+pub struct ClientCertificate;
+
+fn read_certs(path: &str) -> Result<Vec<Certificate>> {
+    let file = std::fs::File::open(path).context(format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .context(format!("Failed to parse {}", path))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificates found in {}", path));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &str) -> Result<PrivateKey> {
+    let file = std::fs::File::open(path).context(format!("Failed to open {}", path))?;
+    let mut reader = BufReader::new(file);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader).context(format!("Failed to parse {}", path))? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(anyhow::anyhow!("No private key found in {}", path)),
+        }
+    }
+}
+
+fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey> {
+    let certs = read_certs(&config.cert)?;
+    let key = sign::any_supported_type(&read_key(&config.key)?)
+        .map_err(|_| anyhow::anyhow!("Unsupported private key type in {}", config.key))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Serves the certificate that was loaded last, so it can be replaced without a restart
+pub struct ReloadableCert {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    pub fn new(config: TlsConfig) -> Result<Self> {
+        let current = RwLock::new(Arc::new(load_certified_key(&config)?));
+        Ok(ReloadableCert { config, current })
+    }
+
+    /// Loads the files again, keeping the current certificate if they are invalid
+    pub fn reload(&self) -> Result<()> {
+        let key = load_certified_key(&self.config)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+
+    async fn watch(self: Arc<Self>) {
+        let mut cert = FileWatcher::new(self.config.cert.clone());
+        let mut key = FileWatcher::new(self.config.key.clone());
+        let mut interval = tokio::time::interval(CERT_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            // Both are checked, so a changed key doesn't trigger another reload later
+            let cert_changed = cert.changed();
+            let key_changed = key.changed();
+            if !cert_changed && !key_changed {
+                continue;
+            }
+
+            match self.reload() {
+                Ok(()) => info!("Reloaded TLS certificate"),
+                Err(e) => error!(
+                    "Failed to reload TLS certificate, keeping the previous one: {:#}",
+                    e
+                ),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+fn server_config(config: &TlsConfig, cert: Arc<ReloadableCert>) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(&cert)
+                    .context(format!("Invalid CA certificate in {}", path))?;
+            }
+
+            if config.require_client_cert {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            } else {
+                builder.with_client_cert_verifier(
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                )
+            }
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_cert_resolver(cert))
+}
+
+// Accepts connections and does the handshakes, without one slow client holding up the others
+async fn accept_connections(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    connections: mpsc::Sender<TlsStream<TcpStream>>,
+) {
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            // The server stopped
+            _ = connections.closed() => return,
+        };
+
+        let acceptor = acceptor.clone();
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = connections.send(stream).await;
+                }
+                Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", remote, e),
+                Err(_) => warn!("TLS handshake with {} timed out", remote),
+            }
+        });
+    }
+}
+
+/// Certificates and settings for serving HTTPS, loaded on startup so mistakes show up early
+pub struct TlsServer {
+    cert: Arc<ReloadableCert>,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsServer {
+    pub fn new(config: &TlsConfig) -> Result<Self> {
+        let cert = Arc::new(ReloadableCert::new(config.clone())?);
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(config, cert.clone())?));
+        Ok(TlsServer { cert, acceptor })
+    }
+
+    /// Serves the routes until the shutdown future resolves
+    pub async fn serve<F>(
+        self,
+        routes: F,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()>
+    where
+        F: Filter + Clone + Send + Sync + 'static,
+        F::Extract: warp::Reply,
+    {
+        let watcher = tokio::spawn(self.cert.watch());
+
+        let (connections, incoming) = mpsc::channel(16);
+        tokio::spawn(accept_connections(listener, self.acceptor, connections));
+        let incoming = futures::stream::unfold(incoming, |mut incoming| async move {
+            incoming
+                .recv()
+                .await
+                .map(|stream| (Ok::<_, Infallible>(stream), incoming))
+        });
+
+        let service = warp::service(routes);
+        let make_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
+            let (tcp, session) = stream.get_ref();
+            let peer = tcp.peer_addr().ok().map(PeerAddr);
+            // The verifier only lets certificates signed by the CA through
+            let client_cert = session.peer_certificates().is_some();
+
+            let service = service.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+                    if let Some(peer) = peer {
+                        request.extensions_mut().insert(peer);
+                    }
+                    if client_cert {
+                        request.extensions_mut().insert(ClientCertificate);
+                    }
+                    service.clone().call(request)
+                }))
+            }
+        });
+
+        let result = warp::hyper::Server::builder(accept::from_stream(incoming))
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown)
+            .await;
+        watcher.abort();
+
+        result.context("TLS server failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate as GeneratedCert, CertificateParams, DnType, IsCa};
+    use std::path::{Path, PathBuf};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{ClientConfig, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    struct Pki {
+        dir: PathBuf,
+        ca: GeneratedCert,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("mysti-tls-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let mut params = CertificateParams::new(Vec::new());
+            params
+                .distinguished_name
+                .push(DnType::CommonName, format!("mysti {} CA", name));
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = GeneratedCert::from_params(params).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+            Pki { dir, ca }
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.dir.join(name).to_string_lossy().to_string()
+        }
+
+        // Writes a certificate signed by the CA to <name>.pem and <name>.key
+        fn issue(&self, name: &str, subject: &str) {
+            let mut params = CertificateParams::new(vec![subject.to_string()]);
+            params.distinguished_name.push(DnType::CommonName, subject);
+            let cert = GeneratedCert::from_params(params).unwrap();
+            let pem = cert.serialize_pem_with_signer(&self.ca).unwrap();
+            std::fs::write(self.path(&format!("{}.pem", name)), pem).unwrap();
+            std::fs::write(
+                self.path(&format!("{}.key", name)),
+                cert.serialize_private_key_pem(),
+            )
+            .unwrap();
+        }
+
+        fn config(&self, require_client_cert: bool) -> TlsConfig {
+            TlsConfig {
+                cert: self.path("server.pem"),
+                key: self.path("server.key"),
+                client_ca: Some(self.path("ca.pem")),
+                require_client_cert,
+            }
+        }
+
+        fn client(&self, client_cert: Option<&str>) -> TlsConnector {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(&self.path("ca.pem")).unwrap() {
+                roots.add(&cert).unwrap();
+            }
+            let builder = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots);
+            let config = match client_cert {
+                Some(name) => builder
+                    .with_client_auth_cert(
+                        read_certs(&self.path(&format!("{}.pem", name))).unwrap(),
+                        read_key(&self.path(&format!("{}.key", name))).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            TlsConnector::from(Arc::new(config))
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Answers whether the request came with a client certificate
+    async fn start(config: &TlsConfig) -> (SocketAddr, tokio::sync::oneshot::Sender<()>) {
+        let routes = warp::ext::optional::<ClientCertificate>()
+            .and(warp::ext::optional::<PeerAddr>())
+            .map(|cert: Option<ClientCertificate>, peer: Option<PeerAddr>| {
+                assert!(peer.is_some());
+                if cert.is_some() {
+                    "certificate"
+                } else {
+                    "anonymous"
+                }
+            });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TlsServer::new(config).unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(server.serve(routes, listener, async {
+            let _ = stopped.await;
+        }));
+        (addr, stop)
+    }
+
+    // Returns the body of the response and the certificate the server presented
+    async fn get(
+        addr: SocketAddr,
+        client: &TlsConnector,
+    ) -> std::io::Result<(String, Certificate)> {
+        let tcp = TcpStream::connect(addr).await?;
+        let mut stream = client
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await?;
+        let cert = stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+        Ok((body.to_string(), cert))
+    }
+
+    fn served(path: &Path) -> Certificate {
+        read_certs(&path.to_string_lossy()).unwrap().remove(0)
+    }
+
+    #[tokio::test]
+    async fn client_certificates() {
+        let pki = Pki::new("client");
+        pki.issue("server", "localhost");
+        pki.issue("client", "laptop");
+
+        let (addr, _stop) = start(&pki.config(false)).await;
+        let (body, _) = get(addr, &pki.client(None)).await.unwrap();
+        assert_eq!(body, "anonymous");
+        let (body, _) = get(addr, &pki.client(Some("client"))).await.unwrap();
+        assert_eq!(body, "certificate");
+
+        // A certificate from another CA is rejected
+        let other = Pki::new("other");
+        other.issue("client", "laptop");
+        for file in ["client.pem", "client.key"] {
+            std::fs::copy(
+                other.dir.join(file),
+                pki.dir.join(format!("foreign-{}", file)),
+            )
+            .unwrap();
+        }
+        assert!(get(addr, &pki.client(Some("foreign-client")))
+            .await
+            .is_err());
+
+        let (addr, _stop) = start(&pki.config(true)).await;
+        assert!(get(addr, &pki.client(None)).await.is_err());
+        let (body, _) = get(addr, &pki.client(Some("client"))).await.unwrap();
+        assert_eq!(body, "certificate");
+    }
+
+    #[tokio::test]
+    async fn reloads_certificate() {
+        let pki = Pki::new("reload");
+        pki.issue("server", "localhost");
+        let config = pki.config(false);
+        let cert = ReloadableCert::new(config.clone()).unwrap();
+        let first = served(&pki.dir.join("server.pem"));
+        assert_eq!(cert.current().cert[0], first);
+
+        // Broken files keep the previous certificate
+        std::fs::write(&config.key, "not a key").unwrap();
+        assert!(cert.reload().is_err());
+        assert_eq!(cert.current().cert[0], first);
+
+        pki.issue("server", "localhost");
+        cert.reload().unwrap();
+        let second = served(&pki.dir.join("server.pem"));
+        assert_ne!(first, second);
+        assert_eq!(cert.current().cert[0], second);
+
+        // New connections get the new certificate
+        let (addr, _stop) = start(&config).await;
+        let (_, presented) = get(addr, &pki.client(None)).await.unwrap();
+        assert_eq!(presented, second);
+    }
+}
@@ -1,13 +1,26 @@
+use crate::audit::{self, AuditQuery};
 use crate::config::Config;
+use crate::metrics::metrics;
+use crate::scheduler::Scheduler;
+use crate::tls::{ClientCertificate, PeerAddr, TlsServer};
 use crate::websocket::{handle_client_message, handle_ws_route, DeviceInfoFilter};
 use crate::Manager;
 use common::action::Action;
-use common::{ActionMessage, ClipboardContent};
-use log::info;
+use common::sync::PauseRequest;
+use common::types::{
+    ActionCreated, AuditActor, AuditEntry, AuditEvent, Credential, GroupDispatchResult,
+    GroupMemberResult, ScheduleRequest,
+};
+use common::{ActionMessage, ClipboardContent, MAX_CLIPBOARD_TTL};
+use futures::StreamExt;
+use log::{error, info, warn};
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
 use subtle::ConstantTimeEq;
+use tokio::sync::broadcast::error::RecvError;
 use warp::reject::Rejection;
 use warp::reply::Reply;
 
@@ -22,18 +35,46 @@ fn with_manager(
     warp::any().map(move || manager.clone())
 }
 
-fn with_config(
-    config: Arc<Config>,
-) -> impl Filter<Extract = (Arc<Config>,), Error = Infallible> + Clone {
-    warp::any().map(move || config.clone())
+fn with_scheduler(
+    scheduler: Arc<Scheduler>,
+) -> impl Filter<Extract = (Arc<Scheduler>,), Error = Infallible> + Clone {
+    warp::any().map(move || scheduler.clone())
 }
 
-fn handle_wake_on_lan_route(config: Arc<Config>) -> impl Reply {
-    let magic_packet = MagicPacket::new(&config.wake_on_lan.target_addr.0.into_array());
+fn handle_wake_on_lan_route(caller: AuditActor, manager: Arc<RwLock<Manager>>) -> impl Reply {
+    // The config can be reloaded at any time
+    let wake_on_lan = manager.read().unwrap().config.wake_on_lan.clone();
+    let magic_packet = MagicPacket::new(&wake_on_lan.target_addr.0.into_array());
 
-    let res = magic_packet.send();
+    let res = match wake_on_lan.router_addr {
+        Some(router_addr) => magic_packet.send_to(
+            SocketAddr::new(router_addr, 9),
+            SocketAddr::new(
+                match router_addr {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                },
+                0,
+            ),
+        ),
+        None => magic_packet.send(),
+    };
 
-    log::info!("Sending WoL packet to {}", config.wake_on_lan.target_addr);
+    log::info!("Sending WoL packet to {}", wake_on_lan.target_addr);
+
+    let entry = AuditEntry::new(
+        caller,
+        AuditEvent::WakeOnLan {
+            mac: wake_on_lan.target_addr.to_string(),
+        },
+    );
+    manager.read().unwrap().audit(match &res {
+        Ok(()) => {
+            metrics().wake_on_lan_sent.inc();
+            entry
+        }
+        Err(e) => entry.outcome(false, e.to_string()),
+    });
 
     match res {
         Ok(()) => {
@@ -49,31 +90,225 @@ fn handle_wake_on_lan_route(config: Arc<Config>) -> impl Reply {
 }
 
 /// get a JSON message like {"action": "shutdown"} and broadcast it as an ActionMessage::Action
-fn handle_action_route(wrapper: Action, manager: Arc<RwLock<Manager>>) -> impl Reply {
-    manager
-        .write()
-        .unwrap()
-        .broadcast(&ActionMessage::Action(wrapper), None);
-    warp::reply::html("OK")
+fn action_event(action: &Action) -> AuditEvent {
+    AuditEvent::Action {
+        action: action.action.clone(),
+        args: action.args.clone(),
+        id: action.id,
+    }
+}
+
+fn handle_action_route(
+    mut wrapper: Action,
+    caller: AuditActor,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Reply {
+    let mut manager = manager.write().unwrap();
+
+    let id = manager.next_action_id();
+    wrapper.id = Some(id);
+
+    let event = action_event(&wrapper);
+    let sent = manager.broadcast(&ActionMessage::Action(wrapper), None);
+    manager.audit(AuditEntry::new(caller, event).outcome(sent > 0, audit::sent_to(sent)));
+
+    warp::reply::json(&ActionCreated { id })
 }
 
 fn handle_specific_action_route(
     id: usize,
-    wrapper: Action,
+    mut wrapper: Action,
+    caller: AuditActor,
     manager: Arc<RwLock<Manager>>,
 ) -> impl Reply {
-    manager
-        .write()
-        .unwrap()
-        .send_to_specific(id, &ActionMessage::Action(wrapper));
-    warp::reply::html("OK")
+    let manager = manager.read().unwrap();
+
+    let action_id = manager.next_action_id();
+    wrapper.id = Some(action_id);
+
+    let event = action_event(&wrapper);
+    let sent = manager.send_to_specific(id, &ActionMessage::Action(wrapper));
+    manager.audit(audit_device_entry(&manager, caller, event, id, sent));
+
+    warp::reply::json(&ActionCreated { id: action_id })
+}
+
+// An entry for a message that was sent to one device
+fn audit_device_entry(
+    manager: &Manager,
+    caller: AuditActor,
+    event: AuditEvent,
+    id: usize,
+    sent: bool,
+) -> AuditEntry {
+    let target = manager
+        .device_name(id)
+        .unwrap_or_else(|| format!("device {}", id));
+    let entry = AuditEntry::new(caller, event).target(target);
+    if sent {
+        entry
+    } else {
+        entry.outcome(false, "device not connected")
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct WaitQuery {
+    // Seconds to wait for results of the group members
+    #[serde(default = "default_group_wait")]
+    wait: u64,
+}
+
+fn default_group_wait() -> u64 {
+    5
+}
+
+const MAX_GROUP_WAIT: u64 = 120;
+
+/// Sends the action to all connected members of the group and waits for their results
+async fn handle_group_action_route(
+    group: String,
+    query: WaitQuery,
+    mut wrapper: Action,
+    caller: AuditActor,
+    manager: Arc<RwLock<Manager>>,
+) -> Result<warp::reply::Response, Infallible> {
+    let (id, members) = {
+        let manager = manager.read().unwrap();
+
+        let members = manager.group_members(&group);
+        let id = manager.next_action_id();
+        wrapper.id = Some(id);
+
+        let mut sent = 0;
+        for (member, _) in &members {
+            if manager.send_to_specific(*member, &ActionMessage::Action(wrapper.clone())) {
+                sent += 1;
+            }
+        }
+
+        let entry = AuditEntry::new(caller, action_event(&wrapper)).target(format!("@{}", group));
+        manager.audit(entry.outcome(sent > 0, audit::sent_to(sent)));
+
+        (id, members)
+    };
+
+    if members.is_empty() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&format!("No connected devices in group {}", group)),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    }
+
+    info!(
+        "Sent action {} to {} devices in group {}",
+        wrapper,
+        members.len(),
+        group
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(query.wait.min(MAX_GROUP_WAIT));
+    let results = loop {
+        let results = manager.read().unwrap().action_results(id);
+
+        let all_reported = members
+            .iter()
+            .all(|(member, _)| results.iter().any(|r| r.device_id == *member));
+        if all_reported || Instant::now() >= deadline {
+            break results;
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    };
+
+    let devices = members
+        .into_iter()
+        .map(|(device_id, device_name)| GroupMemberResult {
+            device_id,
+            device_name,
+            // Use the latest result, e.g. a failure after the command started
+            result: results
+                .iter()
+                .rev()
+                .find(|r| r.device_id == device_id)
+                .map(|r| r.result.clone()),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&GroupDispatchResult { id, devices }).into_response())
+}
+
+fn handle_action_results_route(id: u64, manager: Arc<RwLock<Manager>>) -> impl Reply {
+    warp::reply::json(&manager.read().unwrap().action_results(id))
+}
+
+fn handle_create_schedule_route(
+    request: ScheduleRequest,
+    caller: AuditActor,
+    scheduler: Arc<Scheduler>,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Reply {
+    let mut entry = AuditEntry::new(
+        caller,
+        AuditEvent::Schedule {
+            action: request.action.action.clone(),
+            args: request.action.args.clone(),
+        },
+    );
+    if let Some(target) = &request.target {
+        entry = entry.target(target);
+    }
+
+    let result = scheduler.create(request);
+    manager.read().unwrap().audit(match &result {
+        Ok(schedule) => entry.outcome(true, format!("scheduled as #{}", schedule.id)),
+        Err(e) => entry.outcome(false, format!("{:#}", e)),
+    });
+
+    match result {
+        Ok(schedule) => warp::reply::json(&schedule).into_response(),
+        Err(e) => warp::reply::with_status(
+            warp::reply::json(&format!("{:#}", e)),
+            warp::http::StatusCode::BAD_REQUEST,
+        )
+        .into_response(),
+    }
+}
+
+fn handle_list_schedules_route(scheduler: Arc<Scheduler>) -> impl Reply {
+    warp::reply::json(&scheduler.list())
+}
+
+fn handle_cancel_schedule_route(
+    id: u64,
+    caller: AuditActor,
+    scheduler: Arc<Scheduler>,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Reply {
+    let cancelled = scheduler.cancel(id);
+    let entry = AuditEntry::new(caller, AuditEvent::CancelSchedule { id });
+    manager.read().unwrap().audit(if cancelled {
+        entry
+    } else {
+        entry.outcome(false, "schedule not found")
+    });
+
+    if cancelled {
+        warp::reply::html("OK").into_response()
+    } else {
+        warp::reply::with_status(
+            warp::reply::html("Schedule not found"),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response()
+    }
 }
 
 fn handle_read_clipboard_route(manager: Arc<RwLock<Manager>>) -> impl Reply {
-    let manager = manager.write().unwrap();
-    let last_clipboard_content = manager.last_clipboard_content.read().unwrap();
+    let content = manager.read().unwrap().clipboard();
 
-    match last_clipboard_content.clone() {
+    match content {
         ClipboardContent::Text(text) => warp::reply::with_header(
             warp::reply::html(text),
             "Content-Type",
@@ -84,11 +319,13 @@ fn handle_read_clipboard_route(manager: Arc<RwLock<Manager>>) -> impl Reply {
         .into_response(),
         ClipboardContent::Image(bytes) => {
             let Ok(image) = image::load_from_memory(&bytes) else {
+                metrics().image_conversion_failures.inc();
                 return warp::reply::html("Error loading image from clipboard").into_response();
             };
 
             let mut jpeg_bytes = std::io::Cursor::new(Vec::new());
             let Ok(_) = image.write_to(&mut jpeg_bytes, image::ImageOutputFormat::Jpeg(100)) else {
+                metrics().image_conversion_failures.inc();
                 return warp::reply::html("Error converting image to JPEG").into_response();
             };
 
@@ -104,17 +341,38 @@ fn handle_read_clipboard_route(manager: Arc<RwLock<Manager>>) -> impl Reply {
     }
 }
 
+#[derive(serde::Deserialize)]
+struct ClipboardQuery {
+    // Seconds until the clipboard is cleared again
+    ttl: Option<u64>,
+}
+
 fn handle_write_clipboard_route(
+    query: ClipboardQuery,
     body: warp::hyper::body::Bytes,
+    caller: AuditActor,
     manager: Arc<RwLock<Manager>>,
 ) -> impl Reply {
+    if query.ttl.is_some_and(|ttl| ttl > MAX_CLIPBOARD_TTL) {
+        return warp::reply::with_status(
+            warp::reply::json(&format!(
+                "ttl must be at most {} seconds",
+                MAX_CLIPBOARD_TTL
+            )),
+            warp::http::StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    }
+
     let text = String::from_utf8_lossy(&body).to_string();
 
-    let result = futures::executor::block_on(handle_client_message(
-        ActionMessage::Clipboard(ClipboardContent::Text(text.clone())),
-        manager,
-        None,
-    ));
+    let content = ClipboardContent::Text(text.clone());
+    let message = match query.ttl {
+        Some(ttl) => ActionMessage::ExpiringClipboard { content, ttl },
+        None => ActionMessage::Clipboard(content),
+    };
+
+    let result = futures::executor::block_on(handle_client_message(message, manager, None, caller));
 
     match result {
         Ok(_) => warp::reply::html(text).into_response(),
@@ -126,91 +384,758 @@ fn handle_write_clipboard_route(
     }
 }
 
-fn handle_client_list(manager: Arc<RwLock<Manager>>) -> impl Reply {
-    warp::reply::json(&manager.read().unwrap().list_clients())
+fn handle_clear_clipboard_route(caller: AuditActor, manager: Arc<RwLock<Manager>>) -> impl Reply {
+    let _ = futures::executor::block_on(handle_client_message(
+        ActionMessage::ClearClipboard,
+        manager,
+        None,
+        caller,
+    ));
+
+    warp::reply::html("OK")
+}
+
+fn handle_pause_route(
+    id: usize,
+    request: PauseRequest,
+    caller: AuditActor,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Reply {
+    let message = ActionMessage::PauseSync(request);
+    send_to_device(id, message, caller, AuditEvent::PauseSync, manager)
+}
+
+fn handle_resume_route(id: usize, caller: AuditActor, manager: Arc<RwLock<Manager>>) -> impl Reply {
+    let message = ActionMessage::ResumeSync;
+    send_to_device(id, message, caller, AuditEvent::ResumeSync, manager)
+}
+
+fn send_to_device(
+    id: usize,
+    message: ActionMessage,
+    caller: AuditActor,
+    event: AuditEvent,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Reply {
+    let sent = {
+        let manager = manager.read().unwrap();
+        let sent = manager.send_to_specific(id, &message);
+        manager.audit(audit_device_entry(&manager, caller, event, id, sent));
+        sent
+    };
+
+    if sent {
+        warp::reply::html("OK").into_response()
+    } else {
+        warp::reply::with_status(
+            warp::reply::html("Device not connected"),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response()
+    }
+}
+
+fn handle_metrics_route(manager: Arc<RwLock<Manager>>) -> impl Reply {
+    let metrics = metrics();
+    metrics
+        .connected_clients
+        .set(manager.read().unwrap().client_count() as i64);
+
+    warp::reply::with_header(metrics.encode(), "Content-Type", prometheus::TEXT_FORMAT)
+}
+
+fn handle_audit_route(query: AuditQuery, manager: Arc<RwLock<Manager>>) -> impl Reply {
+    match manager.read().unwrap().query_audit(&query) {
+        Ok(entries) => warp::reply::json(&entries).into_response(),
+        Err(e) => warp::reply::with_status(
+            warp::reply::json(&format!("{:#}", e)),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct DeviceListQuery {
+    // Also list devices that were connected before
+    #[serde(default)]
+    include_offline: bool,
+}
+
+fn handle_client_list(query: DeviceListQuery, manager: Arc<RwLock<Manager>>) -> impl Reply {
+    warp::reply::json(&manager.read().unwrap().list_clients(query.include_offline))
+}
+
+// Streams device changes as server-sent events until the observer disconnects
+fn handle_device_events_route(manager: Arc<RwLock<Manager>>) -> impl Reply {
+    let receiver = manager.read().unwrap().subscribe_device_events();
+    let mut shutdown = manager.read().unwrap().subscribe_shutdown();
+
+    let events = futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    return Some((warp::sse::Event::default().json_data(event), receiver));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Device event observer missed {} events", skipped);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    // The stream would otherwise keep the server from shutting down
+    let events = events.take_until(async move {
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    });
+
+    warp::sse::reply(warp::sse::keep_alive().stream(events))
 }
 
 // Define a struct to represent the query parameters
 #[derive(serde::Deserialize)]
 struct AuthQuery {
-    token: String,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+// Warned about once, as daemons reconnect often
+static QUERY_TOKEN_WARNED: AtomicBool = AtomicBool::new(false);
+
+fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+fn query_token_valid(token: &str, query: &str) -> bool {
+    let valid = bool::from(query.as_bytes().ct_eq(token.as_bytes()));
+    if valid && !QUERY_TOKEN_WARNED.swap(true, Ordering::Relaxed) {
+        warn!(
+            "A client sent the token in the URL, which is deprecated; \
+            update it to send an Authorization header"
+        );
+    }
+    valid
+}
+
+// Rejection of requests with a wrong token, answered with 404 to not reveal the endpoint
+#[derive(Debug)]
+struct InvalidToken {
+    ip: Option<IpAddr>,
+}
+
+impl warp::reject::Reject for InvalidToken {}
+
+#[derive(Debug)]
+struct TooManyRequests {
+    retry_after: u64,
 }
 
-// Define a filter for authentication
-fn with_auth(token: String) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+impl warp::reject::Reject for TooManyRequests {}
+
+// Define a filter for authentication: the token in an Authorization header, a trusted
+// client certificate or, if still allowed, the token in the query string
+fn with_auth(
+    config: &Config,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let token = config.token.clone();
+    let allow_query_token = config.allow_query_token;
+
     warp::any()
+        .and(warp::header::optional::<String>("authorization"))
         .and(warp::filters::query::query::<AuthQuery>())
-        .map(move |query: AuthQuery| query.token.as_bytes().ct_eq(token.as_bytes()).into())
-        .and_then(|is_valid| async move {
+        .and(warp::ext::optional::<ClientCertificate>())
+        .map(
+            move |header: Option<String>, query: AuthQuery, cert: Option<ClientCertificate>| {
+                cert.is_some()
+                    || header
+                        .as_deref()
+                        .and_then(bearer_token)
+                        .is_some_and(|given| given.as_bytes().ct_eq(token.as_bytes()).into())
+                    || (allow_query_token
+                        && query
+                            .token
+                            .is_some_and(|query| query_token_valid(&token, &query)))
+            },
+        )
+        .and(with_client_ip(manager))
+        .and_then(|is_valid, ip: Option<IpAddr>| async move {
             if is_valid {
                 Ok(())
             } else {
-                Err(warp::reject::not_found())
+                Err(warp::reject::custom(InvalidToken { ip }))
             }
         })
         .untuple_one()
 }
 
-pub async fn start_web_server(config: &Config, connection_manager: Arc<RwLock<Manager>>) {
+// The scraper's token, which is separate from the one that controls devices.
+// Without a [metrics] section, GET /metrics doesn't exist.
+fn with_metrics_auth(
+    config: &Config,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_own_token(
+        config.metrics.as_ref().map(|metrics| metrics.token.clone()),
+        manager,
+    )
+}
+
+fn with_audit_auth(
+    config: &Config,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_own_token(config.audit_token.clone(), manager)
+}
+
+// For routes that don't accept the device token; without a token of their own they don't exist
+fn with_own_token(
+    token: Option<String>,
+    manager: Arc<RwLock<Manager>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_client_ip(manager))
+        .and_then(move |header: Option<String>, ip: Option<IpAddr>| {
+            let token = token.clone();
+            async move {
+                let Some(token) = token else {
+                    return Err(warp::reject::not_found());
+                };
+
+                if header
+                    .as_deref()
+                    .and_then(bearer_token)
+                    .is_some_and(|given| given.as_bytes().ct_eq(token.as_bytes()).into())
+                {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(InvalidToken { ip }))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// The address of the client, see Manager::client_ip
+fn with_client_ip(
+    manager: Arc<RwLock<Manager>>,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    remote_addr()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(with_manager(manager))
+        .map(
+            |remote: Option<SocketAddr>,
+             forwarded_for: Option<String>,
+             manager: Arc<RwLock<Manager>>| {
+                manager
+                    .read()
+                    .unwrap()
+                    .client_ip(remote.map(|addr| addr.ip()), forwarded_for.as_deref())
+            },
+        )
+}
+
+// Who sent the request, for the audit log
+fn with_caller(
+    manager: Arc<RwLock<Manager>>,
+) -> impl Filter<Extract = (AuditActor,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::ext::optional::<ClientCertificate>())
+        .and(with_client_ip(manager))
+        .map(
+            |header: Option<String>, cert: Option<ClientCertificate>, ip: Option<IpAddr>| {
+                // with_auth accepted one of them
+                let credential = if header.as_deref().and_then(bearer_token).is_some() {
+                    Credential::Token
+                } else if cert.is_some() {
+                    Credential::ClientCertificate
+                } else {
+                    Credential::QueryToken
+                };
+
+                AuditActor {
+                    credential,
+                    device: None,
+                    ip,
+                }
+            },
+        )
+}
+
+// Rejects clients that sent too many requests or are locked out
+fn with_rate_limit(
+    manager: Arc<RwLock<Manager>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_client_ip(manager.clone())
+        .and(with_manager(manager))
+        .and_then(
+            |ip: Option<IpAddr>, manager: Arc<RwLock<Manager>>| async move {
+                match ip.map(|ip| manager.read().unwrap().check_request(ip)) {
+                    Some(Err(retry_after)) => {
+                        Err(warp::reject::custom(TooManyRequests { retry_after }))
+                    }
+                    _ => Ok(()),
+                }
+            },
+        )
+        .untuple_one()
+}
+
+async fn handle_rejection(
+    rejection: Rejection,
+    manager: Arc<RwLock<Manager>>,
+) -> Result<warp::reply::Response, Rejection> {
+    if let Some(TooManyRequests { retry_after }) = rejection.find() {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                "Too many requests",
+                warp::http::StatusCode::TOO_MANY_REQUESTS,
+            ),
+            "retry-after",
+            retry_after.to_string(),
+        )
+        .into_response());
+    }
+
+    // Found once, even if several routes checked the token
+    if let Some(InvalidToken { ip }) = rejection.find() {
+        metrics().auth_failures.inc();
+        if let Some(ip) = ip {
+            manager.read().unwrap().auth_failed(*ip);
+        }
+        return Ok(warp::http::StatusCode::NOT_FOUND.into_response());
+    }
+
+    Err(rejection)
+}
+
+// The address of the client, which warp only knows for plain HTTP connections
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .map(|remote: Option<SocketAddr>, peer: Option<PeerAddr>| {
+            remote.or(peer.map(|peer| peer.0))
+        })
+}
+
+pub async fn start_web_server(
+    config: &Config,
+    connection_manager: Arc<RwLock<Manager>>,
+    scheduler: Arc<Scheduler>,
+    tls: Option<TlsServer>,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) {
     let ws_route = warp::path("ws")
-        .and(with_auth(config.token.to_string()))
+        .and(with_auth(config, connection_manager.clone()))
         .and(warp::query::<DeviceInfoFilter>())
         .and(warp::ws().map(|ws: warp::ws::Ws| ws.max_frame_size(64 << 20)))
+        .and(with_client_ip(connection_manager.clone()))
         .and(with_manager(connection_manager.clone()))
         .map(handle_ws_route);
 
     let wake_on_lan_route = warp::path("wol")
-        .and(with_auth(config.token.to_string()))
+        .and(with_auth(config, connection_manager.clone()))
         .and(warp::post())
-        .and(with_config(Arc::new(config.clone())))
+        .and(with_caller(connection_manager.clone()))
+        .and(with_manager(connection_manager.clone()))
         .map(handle_wake_on_lan_route);
 
     let action_route = warp::path!("actions" / "create")
-        .and(with_auth(config.token.to_string()))
+        .and(with_auth(config, connection_manager.clone()))
         .and(warp::post())
         .and(warp::body::json())
+        .and(with_caller(connection_manager.clone()))
         .and(with_manager(connection_manager.clone()))
         .map(handle_action_route);
 
     let action_route_specific = warp::path!("actions" / "create" / usize)
-        .and(with_auth(config.token.to_string()))
+        .and(with_auth(config, connection_manager.clone()))
         .and(warp::post())
         .and(warp::body::json())
+        .and(with_caller(connection_manager.clone()))
         .and(with_manager(connection_manager.clone()))
         .map(handle_specific_action_route);
 
+    let group_action_route = warp::path!("groups" / String / "actions")
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::post())
+        .and(warp::query::<WaitQuery>())
+        .and(warp::body::json())
+        .and(with_caller(connection_manager.clone()))
+        .and(with_manager(connection_manager.clone()))
+        .and_then(handle_group_action_route);
+
+    let action_results_route = warp::path!("actions" / u64)
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::get())
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_action_results_route);
+
+    let schedule_create_route = warp::path!("schedules")
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_caller(connection_manager.clone()))
+        .and(with_scheduler(scheduler.clone()))
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_create_schedule_route);
+
+    let schedule_list_route = warp::path!("schedules")
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::get())
+        .and(with_scheduler(scheduler.clone()))
+        .map(handle_list_schedules_route);
+
+    let schedule_cancel_route = warp::path!("schedules" / u64)
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::delete())
+        .and(with_caller(connection_manager.clone()))
+        .and(with_scheduler(scheduler.clone()))
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_cancel_schedule_route);
+
     let clipboard_read_route = warp::path!("devices" / "clipboard")
-        .and(with_auth(config.token.to_string()))
+        .and(with_auth(config, connection_manager.clone()))
         .and(warp::get())
         .and(with_manager(connection_manager.clone()))
         .map(handle_read_clipboard_route);
 
     let clipboard_write_route = warp::path!("devices" / "clipboard")
-        .and(with_auth(config.token.to_string()))
+        .and(with_auth(config, connection_manager.clone()))
         .and(warp::post())
+        .and(warp::query::<ClipboardQuery>())
         .and(warp::body::content_length_limit(1024 * 32))
         .and(warp::body::bytes())
+        .and(with_caller(connection_manager.clone()))
         .and(with_manager(connection_manager.clone()))
         .map(handle_write_clipboard_route);
 
+    let clipboard_clear_route = warp::path!("devices" / "clipboard")
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::delete())
+        .and(with_caller(connection_manager.clone()))
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_clear_clipboard_route);
+
+    let pause_route = warp::path!("devices" / usize / "pause")
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_caller(connection_manager.clone()))
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_pause_route);
+
+    let resume_route = warp::path!("devices" / usize / "resume")
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::post())
+        .and(with_caller(connection_manager.clone()))
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_resume_route);
+
+    let device_events_route = warp::path!("devices" / "events")
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::get())
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_device_events_route);
+
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_metrics_auth(config, connection_manager.clone()))
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_metrics_route);
+
+    // The log reveals what every device did, so it needs its own token
+    let audit_route = warp::path!("audit")
+        .and(warp::get())
+        .and(with_audit_auth(config, connection_manager.clone()))
+        .and(warp::query::<AuditQuery>())
+        .and(with_manager(connection_manager.clone()))
+        .map(handle_audit_route);
+
+    let rate_limits_route = warp::path!("rate-limits")
+        .and(with_auth(config, connection_manager.clone()))
+        .and(warp::get())
+        .and(with_manager(connection_manager.clone()))
+        .map(|manager: Arc<RwLock<Manager>>| {
+            warp::reply::json(&manager.read().unwrap().rate_limit_stats())
+        });
+
     let client_list_route = warp::path!("devices")
-        .and(with_auth(config.token.to_string()))
+        .and(with_auth(config, connection_manager.clone()))
         .and(warp::get())
+        .and(warp::query::<DeviceListQuery>())
         .and(with_manager(connection_manager.clone()))
         .map(handle_client_list);
 
     let routes = ws_route
         .or(action_route)
         .or(action_route_specific)
+        .or(group_action_route)
+        .or(action_results_route)
+        .or(schedule_create_route)
+        .or(schedule_list_route)
+        .or(schedule_cancel_route)
         .or(wake_on_lan_route)
         .or(client_list_route)
+        .or(device_events_route)
         .or(clipboard_read_route)
-        .or(clipboard_write_route);
+        .or(clipboard_write_route)
+        .or(clipboard_clear_route)
+        .or(pause_route)
+        .or(resume_route)
+        .or(rate_limits_route)
+        .or(audit_route)
+        .or(metrics_route);
+
+    let rejection_manager = connection_manager.clone();
+    let routes = with_rate_limit(connection_manager.clone())
+        .and(routes)
+        .recover(move |rejection| handle_rejection(rejection, rejection_manager.clone()));
 
     let addr: SocketAddr = ("[::]:".to_owned() + &config.web_port.to_string())
         .parse()
         .unwrap();
 
-    info!("Starting web server on port {}", config.web_port);
-    warp::serve(routes).run(addr).await;
+    let Some(tls) = tls else {
+        info!("Starting web server on port {}", config.web_port);
+        let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, shutdown);
+        server.await;
+        return;
+    };
+
+    info!("Starting HTTPS web server on port {}", config.web_port);
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .expect("Failed to bind web server");
+    if let Err(e) = tls.serve(routes, listener, shutdown).await {
+        error!("{:#}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allow_query_token: bool) -> Config {
+        crate::config::parse(&format!(
+            r#"
+            web_port = 9138
+            token = "secret"
+            allow_query_token = {}
+
+            [wake_on_lan]
+            target_addr = "AA:AA:AA:AA:AA:AA"
+            "#,
+            allow_query_token
+        ))
+        .unwrap()
+    }
+
+    async fn authorized(config: &Config, request: warp::test::RequestBuilder) -> bool {
+        let manager = Arc::new(RwLock::new(Manager::new(
+            config.clone(),
+            crate::registry::Registry::default(),
+            crate::audit::AuditLog::default(),
+        )));
+        let route = warp::path("devices")
+            .and(with_auth(config, manager))
+            .map(warp::reply);
+        request.matches(&route).await
+    }
+
+    #[tokio::test]
+    async fn authorization_header() {
+        let config = config(true);
+        let request = || warp::test::request().path("/devices");
+
+        assert!(authorized(&config, request().header("Authorization", "Bearer secret")).await);
+        assert!(authorized(&config, request().header("authorization", "bearer secret")).await);
+        assert!(!authorized(&config, request().header("Authorization", "Bearer wrong")).await);
+        assert!(!authorized(&config, request().header("Authorization", "Basic secret")).await);
+        assert!(!authorized(&config, request()).await);
+
+        // The deprecated query parameter works until it is disabled
+        let request = || warp::test::request().path("/devices?token=secret");
+        assert!(authorized(&config, request()).await);
+        assert!(!authorized(&self::config(false), request()).await);
+    }
+
+    #[tokio::test]
+    async fn locks_out_wrong_tokens() {
+        let config = crate::config::parse(
+            r#"
+            web_port = 9138
+            token = "secret"
+
+            [wake_on_lan]
+            target_addr = "AA:AA:AA:AA:AA:AA"
+
+            [rate_limit]
+            max_auth_failures = 2
+            trusted_proxies = ["127.0.0.1"]
+            "#,
+        )
+        .unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new(
+            config.clone(),
+            crate::registry::Registry::default(),
+            crate::audit::AuditLog::default(),
+        )));
+        let rejection_manager = manager.clone();
+        let route = with_rate_limit(manager.clone())
+            .and(warp::path("devices"))
+            .and(with_auth(&config, manager.clone()))
+            .map(warp::reply)
+            .recover(move |rejection| handle_rejection(rejection, rejection_manager.clone()));
+
+        // Through a proxy, the forwarded address is locked out
+        let request = |token: &str, client: &str| {
+            warp::test::request()
+                .path("/devices")
+                .remote_addr("127.0.0.1:4000".parse().unwrap())
+                .header("x-forwarded-for", client)
+                .header("authorization", format!("Bearer {}", token))
+        };
+
+        for _ in 0..2 {
+            let response = request("wrong", "1.2.3.4").reply(&route).await;
+            assert_eq!(response.status(), 404);
+        }
+        let response = request("secret", "1.2.3.4").reply(&route).await;
+        assert_eq!(response.status(), 429);
+        assert!(response.headers().contains_key("retry-after"));
+
+        let response = request("secret", "5.6.7.8").reply(&route).await;
+        assert_eq!(response.status(), 200);
+
+        let stats = manager.read().unwrap().rate_limit_stats();
+        assert_eq!((stats.auth_failures, stats.locked_out), (2, 1));
+    }
+
+    #[test]
+    fn rejects_huge_ttl() {
+        let manager = Arc::new(RwLock::new(Manager::new(
+            config(true),
+            crate::registry::Registry::default(),
+            crate::audit::AuditLog::default(),
+        )));
+        let caller = AuditActor {
+            credential: Credential::Token,
+            device: None,
+            ip: None,
+        };
+
+        let response = handle_write_clipboard_route(
+            ClipboardQuery {
+                ttl: Some(u64::MAX),
+            },
+            "secret".into(),
+            caller,
+            manager,
+        )
+        .into_response();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn metrics_token() {
+        let config = crate::config::parse(
+            r#"
+            web_port = 9138
+            token = "secret"
+
+            [wake_on_lan]
+            target_addr = "AA:AA:AA:AA:AA:AA"
+
+            [metrics]
+            token = "scraper"
+            "#,
+        )
+        .unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new(
+            config.clone(),
+            crate::registry::Registry::default(),
+            crate::audit::AuditLog::default(),
+        )));
+        let route = |config: &Config| {
+            warp::path!("metrics")
+                .and(with_metrics_auth(config, manager.clone()))
+                .and(with_manager(manager.clone()))
+                .map(handle_metrics_route)
+        };
+        let request = |token: &str| {
+            warp::test::request()
+                .path("/metrics")
+                .header("authorization", format!("Bearer {}", token))
+        };
+
+        let response = request("scraper").reply(&route(&config)).await;
+        assert_eq!(response.status(), 200);
+        let body = String::from_utf8_lossy(response.body()).to_string();
+        assert!(body.contains("mysti_connected_clients 0"));
+
+        // The device token doesn't work here
+        let rejected = request("secret").filter(&route(&config)).await;
+        assert!(rejected.is_err_and(|r| r.find::<InvalidToken>().is_some()));
+
+        let disabled = Config {
+            metrics: None,
+            ..config.clone()
+        };
+        let rejected = request("scraper").filter(&route(&disabled)).await;
+        assert!(rejected.is_err_and(|r| r.is_not_found()));
+    }
+
+    #[tokio::test]
+    async fn audit_token() {
+        let config = crate::config::parse(
+            r#"
+            web_port = 9138
+            token = "secret"
+            audit_token = "auditor"
+
+            [wake_on_lan]
+            target_addr = "AA:AA:AA:AA:AA:AA"
+
+            [metrics]
+            token = "scraper"
+            "#,
+        )
+        .unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new(
+            config.clone(),
+            crate::registry::Registry::default(),
+            crate::audit::AuditLog::default(),
+        )));
+        let route = |config: &Config| {
+            warp::path!("audit")
+                .and(with_audit_auth(config, manager.clone()))
+                .and(warp::query::<AuditQuery>())
+                .and(with_manager(manager.clone()))
+                .map(handle_audit_route)
+        };
+        let request = |token: &str| {
+            warp::test::request()
+                .path("/audit")
+                .header("authorization", format!("Bearer {}", token))
+        };
+
+        let response = request("auditor").reply(&route(&config)).await;
+        assert_eq!(response.status(), 200);
+
+        // Neither the device nor the scraper token works here
+        for token in ["secret", "scraper"] {
+            let rejected = request(token).filter(&route(&config)).await;
+            assert!(rejected.is_err_and(|r| r.find::<InvalidToken>().is_some()));
+        }
+
+        let disabled = Config {
+            audit_token: None,
+            ..config.clone()
+        };
+        let rejected = request("auditor").filter(&route(&disabled)).await;
+        assert!(rejected.is_err_and(|r| r.is_not_found()));
+    }
 }
@@ -1,10 +1,17 @@
-use crate::connection::Manager;
+use crate::audit;
+use crate::connection::{ConnectionChannel, Manager};
+use crate::metrics::metrics;
 use anyhow::Result;
-use common::ActionMessage;
+use common::action::{ActionInfo, ActionParam, ActionResult, ActionStatus};
+use common::sync::SyncRule;
+use common::types::{AuditActor, AuditEntry, AuditEvent, Credential};
+use common::{ActionMessage, ClipboardContent, MAX_CLIPBOARD_TTL};
 use futures_util::{SinkExt, StreamExt};
 use log::{error, info};
+use std::net::IpAddr;
 use std::sync::{Arc, RwLock};
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
 use warp::{
     reply::Reply,
     ws::{Message, WebSocket},
@@ -14,14 +21,133 @@ use warp::{
 pub(crate) struct DeviceInfoFilter {
     device_name: String,
     supported_actions: String,
+    // Comma-separated, used for tag-based groups
+    #[serde(default)]
+    tags: String,
+    // JSON of the sync rule the daemon configured for itself
+    #[serde(default)]
+    sync: Option<String>,
 }
 
+// What is written to the audit log for a message, clipboard contents are left out
+fn audit_event(message: &ActionMessage) -> Option<AuditEvent> {
+    let clipboard = |content: &ClipboardContent, ttl| {
+        let (kind, bytes) = match content {
+            ClipboardContent::Text(text) => ("text", text.len()),
+            ClipboardContent::Image(image) => ("image", image.len()),
+        };
+        AuditEvent::Clipboard {
+            kind: kind.to_string(),
+            bytes,
+            ttl,
+        }
+    };
+
+    match message {
+        ActionMessage::Clipboard(content) => Some(clipboard(content, None)),
+        ActionMessage::ExpiringClipboard { content, ttl } => Some(clipboard(content, Some(*ttl))),
+        ActionMessage::ClearClipboard => Some(AuditEvent::ClearClipboard),
+        ActionMessage::Action(action) => Some(AuditEvent::Action {
+            action: action.action.clone(),
+            args: action.args.clone(),
+            id: action.id,
+        }),
+        _ => None,
+    }
+}
+
+fn audit_result(actor: AuditActor, result: &ActionResult) -> AuditEntry {
+    let success = match result.status {
+        ActionStatus::Started => true,
+        ActionStatus::Finished => result.exit_code.unwrap_or(0) == 0,
+        ActionStatus::Failed | ActionStatus::Killed | ActionStatus::Refused => false,
+    };
+
+    let mut entry = AuditEntry::new(
+        actor,
+        AuditEvent::ActionResult {
+            action: result.action.clone(),
+            id: result.id,
+            status: result.status,
+            exit_code: result.exit_code,
+        },
+    );
+    entry.success = success;
+    entry.outcome = result.message.clone();
+    entry
+}
+
+/// Handles a message from a device, or sent via HTTP if there is no sender
 pub(crate) async fn handle_client_message(
     message: ActionMessage,
     manager: Arc<RwLock<Manager>>,
     sender_id: Option<usize>,
+    actor: AuditActor,
 ) -> Result<()> {
-    manager.write().unwrap().broadcast(&message, sender_id);
+    // Results are meant for the requester, not for other clients
+    if let ActionMessage::ActionResult(result) = message {
+        if let Some(sender_id) = sender_id {
+            let manager = manager.read().unwrap();
+            manager.audit(audit_result(actor, &result));
+            manager.record_action_result(sender_id, result);
+        }
+        return Ok(());
+    }
+
+    match message {
+        ActionMessage::PauseState(paused) => {
+            if let Some(sender_id) = sender_id {
+                manager.read().unwrap().set_pause_state(sender_id, paused);
+            }
+            return Ok(());
+        }
+        ActionMessage::Metadata(metadata) => {
+            if let Some(sender_id) = sender_id {
+                manager.read().unwrap().set_metadata(sender_id, *metadata);
+            }
+            return Ok(());
+        }
+        ActionMessage::Capabilities(capabilities) => {
+            if let Some(sender_id) = sender_id {
+                manager
+                    .read()
+                    .unwrap()
+                    .update_capabilities(sender_id, capabilities);
+            }
+            return Ok(());
+        }
+        // Only the server tells clients to pause
+        ActionMessage::PauseSync(_) | ActionMessage::ResumeSync => return Ok(()),
+        ActionMessage::ExpiringClipboard { ttl, .. } if ttl > MAX_CLIPBOARD_TTL => {
+            return Err(anyhow::anyhow!(
+                "Clipboard TTL of {}s is longer than {}s",
+                ttl,
+                MAX_CLIPBOARD_TTL
+            ));
+        }
+        _ => (),
+    }
+
+    let event = audit_event(&message);
+
+    if let Some(sender_id) = sender_id {
+        let manager = manager.read().unwrap();
+        if !manager.allow_message(sender_id, &message) {
+            if let Some(event) = event {
+                manager.audit(AuditEntry::new(actor, event).outcome(false, "rate limited"));
+            }
+            return Ok(());
+        }
+    }
+
+    let sent = manager.write().unwrap().broadcast(&message, sender_id);
+
+    if let Some(event) = event {
+        // The server keeps clipboard contents for devices that connect later
+        let success = sent > 0 || message.clipboard_content().is_some();
+        let entry = AuditEntry::new(actor, event).outcome(success, audit::sent_to(sent));
+        manager.read().unwrap().audit(entry);
+    }
 
     Ok(())
 }
@@ -30,28 +156,67 @@ pub(crate) async fn handle_connection(
     ws: WebSocket,
     manager: Arc<RwLock<Manager>>,
     device_name: String,
-    supported_actions: Vec<(String, usize)>,
+    supported_actions: Vec<ActionInfo>,
+    tags: Vec<String>,
+    sync: SyncRule,
+    ip: Option<IpAddr>,
 ) {
     let (mut user_ws_tx, mut user_ws_rx) = ws.split();
-    let (websocket_writer, mut websocket_outbound_stream) = mpsc::unbounded_channel();
+    let settings = manager.read().unwrap().config.connections.clone();
+    let (messages, mut websocket_outbound_stream) = mpsc::channel(settings.send_queue);
+    let channel = ConnectionChannel {
+        messages,
+        close: Arc::new(Notify::new()),
+    };
+    let mut shutdown = manager.read().unwrap().subscribe_shutdown();
+    // Pings and close frames skip the queue of action messages
+    let (control_writer, mut control_stream) = mpsc::unbounded_channel::<Message>();
 
-    let id =
-        manager
-            .write()
-            .unwrap()
-            .add_connection(&websocket_writer, &device_name, supported_actions);
+    let actor = AuditActor {
+        credential: Credential::Device,
+        device: Some(device_name.clone()),
+        ip,
+    };
+
+    let id = manager.write().unwrap().add_connection(
+        &channel,
+        &device_name,
+        supported_actions,
+        tags,
+        sync,
+        ip,
+    );
 
     // Every time we get a message from the outbound stream, send it to the user.
     tokio::spawn(async move {
-        while let Some(action_msg) = websocket_outbound_stream.recv().await {
-            let Ok(message) = Message::try_from(action_msg) else {
-                error!("Error converting Action Message to WebSocket message");
-                continue;
+        loop {
+            let message = tokio::select! {
+                biased;
+                control = control_stream.recv() => match control {
+                    Some(message) => message,
+                    None => break,
+                },
+                Some(action_msg) = websocket_outbound_stream.recv() => {
+                    match Message::try_from(action_msg) {
+                        Ok(message) => {
+                            metrics().bytes_relayed.inc_by(message.as_bytes().len() as u64);
+                            message
+                        }
+                        Err(_) => {
+                            metrics().websocket_errors.inc();
+                            error!("Error converting Action Message to WebSocket message");
+                            continue;
+                        }
+                    }
+                }
             };
 
+            let closing = message.is_close();
             match user_ws_tx.send(message).await {
+                Ok(_) if closing => break,
                 Ok(_) => (),
                 Err(e) => {
+                    metrics().websocket_errors.inc();
                     error!("Error sending message to WebSocket: {}", e);
                     break;
                 }
@@ -59,17 +224,13 @@ pub(crate) async fn handle_connection(
         }
     });
 
-    // Initial message writing
-    let ws_writer_clone = websocket_writer.clone();
-    let manager_clone = manager.clone();
-    tokio::spawn(async move {
-        // Send the last clipboard content to the user
-        let manager = manager_clone.write().unwrap();
-        let last_clipboard_content = manager.last_clipboard_content.read().unwrap();
-
-        let message = ActionMessage::Clipboard(last_clipboard_content.clone());
-        let _ = ws_writer_clone.send(message);
-    });
+    // Send the last clipboard content to the user
+    {
+        let manager = manager.read().unwrap();
+        if let Some(message) = manager.clipboard_for(id) {
+            manager.send_to_specific(id, &message);
+        }
+    }
 
     log::info!(
         "Connected WebSocket connection {} ({}), now have {} connections",
@@ -78,15 +239,62 @@ pub(crate) async fn handle_connection(
         manager.read().unwrap().client_count()
     );
 
-    // Every time we get a message from the user, handle it with the handler.
-    while let Some(result) = user_ws_rx.next().await {
+    // Shown for offline devices
+    let mut disconnect_reason = "connection lost".to_string();
+
+    let timeout = Duration::from_secs(settings.timeout);
+    let ping_interval = Duration::from_secs(settings.ping_interval);
+    let mut heartbeat =
+        tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_received = Instant::now();
+
+    loop {
+        let result = tokio::select! {
+            result = user_ws_rx.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                if last_received.elapsed() > timeout {
+                    info!("Connection {} did not answer pings, closing it", id);
+                    disconnect_reason = "heartbeat timeout".to_string();
+                    break;
+                }
+                let _ = control_writer.send(Message::ping(Vec::new()));
+                continue;
+            }
+            _ = channel.close.notified() => {
+                disconnect_reason = "too slow to receive messages".to_string();
+                let _ = control_writer.send(Message::close_with(1008u16, "send queue full"));
+                break;
+            }
+            Ok(()) = shutdown.changed() => {
+                // Daemons reconnect quickly after this
+                disconnect_reason = "server restarting".to_string();
+                let _ = control_writer.send(Message::close_with(1012u16, "server restarting"));
+                break;
+            }
+        };
+        // Any frame, including pongs, shows the connection is alive
+        last_received = Instant::now();
+
         match result {
             Ok(message) => {
                 let Ok(message) = ActionMessage::try_from(&message) else {
-                    if message.is_ping() || message.is_close() {
+                    if let Some((_, reason)) = message.close_frame() {
+                        disconnect_reason = match reason {
+                            "" => "closed by the device".to_string(),
+                            reason => format!("closed by the device: {}", reason),
+                        };
+                    } else if message.is_close() {
+                        disconnect_reason = "closed by the device".to_string();
+                    }
+                    if message.is_ping() || message.is_pong() || message.is_close() {
                         continue;
                     }
 
+                    metrics().websocket_errors.inc();
                     error!(
                         "Error converting WebSocket message {:?} to Action Message",
                         message
@@ -94,18 +302,25 @@ pub(crate) async fn handle_connection(
                     continue;
                 };
 
-                if let Err(e) = handle_client_message(message, manager.clone(), Some(id)).await {
+                if let Err(e) =
+                    handle_client_message(message, manager.clone(), Some(id), actor.clone()).await
+                {
                     error!("Error handling message from WebSocket: {}", e);
                 }
             }
             Err(e) => {
+                metrics().websocket_errors.inc();
                 error!("Error receiving message from WebSocket: {}", e);
+                disconnect_reason = e.to_string();
                 break;
             }
         }
     }
 
-    manager.write().unwrap().remove_connection(id);
+    manager
+        .write()
+        .unwrap()
+        .remove_connection(id, disconnect_reason);
 
     info!(
         "WebSocket connection closed for {}, now have {} clients",
@@ -117,6 +332,7 @@ pub(crate) async fn handle_connection(
 pub(crate) fn handle_ws_route(
     device_info: DeviceInfoFilter,
     ws: warp::ws::Ws,
+    ip: Option<IpAddr>,
     manager: Arc<RwLock<Manager>>,
 ) -> impl Reply {
     ws.on_upgrade(move |socket| {
@@ -124,16 +340,155 @@ pub(crate) fn handle_ws_route(
             socket,
             manager,
             device_info.device_name,
+            parse_supported_actions(&device_info.supported_actions),
             device_info
-                .supported_actions
-                .split(",")
-                .filter_map(|pair| {
-                    let (key, value_str) = pair.split_once(":")?;
-                    let key = key.trim().to_string();
-                    let value = value_str.trim().parse().ok()?;
-                    Some((key, value))
-                })
+                .tags
+                .split(',')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
                 .collect(),
+            parse_sync_rule(device_info.sync.as_deref()),
+            ip,
         )
     })
 }
+
+fn parse_sync_rule(sync: Option<&str>) -> SyncRule {
+    let Some(sync) = sync else {
+        return SyncRule::default();
+    };
+
+    serde_json::from_str(sync).unwrap_or_else(|e| {
+        error!("Ignoring invalid sync rule {}: {}", sync, e);
+        SyncRule::default()
+    })
+}
+
+/// Parses the actions a client advertises. Current clients send a JSON list of `ActionInfo`,
+/// older ones a comma-separated list of `name:required_args` pairs.
+fn parse_supported_actions(supported_actions: &str) -> Vec<ActionInfo> {
+    if let Ok(actions) = serde_json::from_str(supported_actions) {
+        return actions;
+    }
+
+    supported_actions
+        .split(',')
+        .filter_map(|pair| {
+            let (key, value_str) = pair.split_once(':')?;
+            let required_args: usize = value_str.trim().parse().ok()?;
+            Some(ActionInfo {
+                name: key.trim().to_string(),
+                description: None,
+                params: (1..=required_args).map(ActionParam::positional).collect(),
+                confirm: false,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Registry;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn closes_connections_on_shutdown() {
+        let config = crate::config::parse(
+            r#"
+            web_port = 9138
+            token = "token"
+
+            [wake_on_lan]
+            target_addr = "AA:AA:AA:AA:AA:AA"
+            "#,
+        )
+        .unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new(
+            config,
+            Registry::default(),
+            crate::audit::AuditLog::default(),
+        )));
+
+        let route_manager = manager.clone();
+        let route = warp::ws().map(move |ws: warp::ws::Ws| {
+            let manager = route_manager.clone();
+            ws.on_upgrade(move |socket| {
+                handle_connection(
+                    socket,
+                    manager,
+                    "laptop".to_string(),
+                    Vec::new(),
+                    Vec::new(),
+                    SyncRule::default(),
+                    None,
+                )
+            })
+        });
+        let mut client = warp::test::ws().handshake(route).await.unwrap();
+
+        // The initial clipboard content
+        assert!(client.recv().await.unwrap().is_text());
+        assert_eq!(manager.read().unwrap().client_count(), 1);
+
+        manager.read().unwrap().shutdown();
+        client.recv_closed().await.unwrap();
+        for _ in 0..100 {
+            if manager.read().unwrap().client_count() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let offline = manager.read().unwrap().list_clients(true);
+        assert_eq!(
+            offline[0].disconnect_reason.as_deref(),
+            Some("server restarting")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_huge_ttl() {
+        let config = crate::config::parse(
+            r#"
+            web_port = 9138
+            token = "token"
+
+            [wake_on_lan]
+            target_addr = "AA:AA:AA:AA:AA:AA"
+            "#,
+        )
+        .unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new(
+            config,
+            Registry::default(),
+            crate::audit::AuditLog::default(),
+        )));
+        let actor = AuditActor {
+            credential: Credential::Token,
+            device: None,
+            ip: None,
+        };
+        let expiring = |ttl| ActionMessage::ExpiringClipboard {
+            content: ClipboardContent::Text("secret".to_string()),
+            ttl,
+        };
+
+        let result =
+            handle_client_message(expiring(u64::MAX), manager.clone(), None, actor.clone()).await;
+        assert!(result.is_err());
+
+        // Even if it gets through, the lock must not be poisoned
+        manager
+            .write()
+            .unwrap()
+            .broadcast(&expiring(u64::MAX), None);
+        handle_client_message(expiring(60), manager.clone(), None, actor)
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.read().unwrap().clipboard(),
+            ClipboardContent::Text("secret".to_string())
+        );
+    }
+}